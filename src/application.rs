@@ -0,0 +1,192 @@
+use crate::build_tool::{build_tool_in_dir, create_application};
+use crate::cache::Cache;
+use crate::config::{Config, ExecutionConfig};
+use crate::language_driver::{driver_for, LanguageDriver};
+use crate::llm_api::LLMApi;
+use crate::llm_prompt::Prompt;
+use crate::llm_response::{Application, LLMResponse};
+use crate::runs::Run;
+use crate::{Lang, VERBOSE};
+
+/// Generates a multi-file seed project into `output_dir`: the LLM plans the
+/// file tree and the manifest, then every file is written one by one and the
+/// files blamed by the build and test output are rewritten until the whole
+/// project compiles and its tests pass.
+pub fn run_application_state_machine(
+    lang: &Lang,
+    question: &str,
+    prompt: &Prompt,
+    cache: &mut Cache,
+    llm: &LLMApi,
+    config: &Config,
+    output_dir: &str,
+) {
+    let driver = driver_for(lang, config);
+    let mut run = Run::start(config, "application", lang, question, llm, output_dir);
+    run.next_attempt();
+    let result = match run.request(
+        llm,
+        "application",
+        &[question.to_string()],
+        cache,
+        prompt,
+        &[],
+    ) {
+        Ok(result) => result,
+        Err(err) => {
+            println!("{}", err);
+            run.finish(false);
+            return;
+        }
+    };
+    let mut application = LLMResponse::parse_application_plan(&result);
+    if *VERBOSE.lock().unwrap() {
+        println!("{:#?}", application);
+    }
+    if application.tree.is_empty() {
+        println!("LLM did not plan any file of the application");
+        run.finish(false);
+        return;
+    }
+
+    let tree = application.tree_listing();
+    let paths = application
+        .tree
+        .iter()
+        .map(|(path, _)| path.clone())
+        .collect::<Vec<_>>();
+    for path in paths {
+        if application.file(&path).is_some() {
+            continue;
+        }
+        println!("Generate file: {}", path);
+        let result = match run.request(
+            llm,
+            "application_file",
+            &[
+                question.to_string(),
+                tree.clone(),
+                path.clone(),
+                application.files_listing(&path),
+            ],
+            cache,
+            prompt,
+            &[],
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                println!("{}", err);
+                run.finish(false);
+                return;
+            }
+        };
+        match LLMResponse::parse_file(&result, &path) {
+            Some(content) => application.set_file(&path, content),
+            None => println!("Warning: LLM did not write the file {}", path),
+        }
+    }
+
+    create_application(output_dir, &application);
+    let (mut build_res, mut test_res) = build_and_test(
+        driver.as_ref(),
+        output_dir,
+        &application,
+        &mut run,
+        &config.execution,
+        cache,
+    );
+    let mut number_of_attempts = 0;
+    while !(build_res.0 && test_res.0) {
+        if number_of_attempts > config.max_attempts {
+            println!("To many attempts");
+            run.finish(false);
+            return;
+        }
+        number_of_attempts += 1;
+        run.next_attempt();
+        for path in application.files_to_rewrite(&build_res.1, &test_res.1) {
+            println!("Rewrite file: {}", path);
+            let content = application.file(&path).cloned().unwrap_or_default();
+            let result = match run.request(
+                llm,
+                "rewrite_file",
+                &[
+                    tree.clone(),
+                    application.files_listing(&path),
+                    path.clone(),
+                    content,
+                    application.build_command.clone(),
+                    build_res.1.clone(),
+                    application.test_command.clone(),
+                    test_res.1.clone(),
+                    question.to_string(),
+                ],
+                cache,
+                prompt,
+                &[],
+            ) {
+                Ok(result) => result,
+                Err(err) => {
+                    println!("{}", err);
+                    run.finish(false);
+                    return;
+                }
+            };
+            if let Some(content) = LLMResponse::parse_file(&result, &path) {
+                application.set_file(&path, content);
+            }
+        }
+        if *VERBOSE.lock().unwrap() {
+            println!("{:#?}", application);
+        }
+        create_application(output_dir, &application);
+        (build_res, test_res) = build_and_test(
+            driver.as_ref(),
+            output_dir,
+            &application,
+            &mut run,
+            &config.execution,
+            cache,
+        );
+    }
+    println!("Application is saved in {}", output_dir);
+    run.finish(true);
+}
+
+fn build_and_test(
+    driver: &dyn LanguageDriver,
+    output_dir: &str,
+    application: &Application,
+    run: &mut Run,
+    limits: &ExecutionConfig,
+    cache: &mut Cache,
+) -> ((bool, String), (bool, String)) {
+    run.files(&application.files);
+    let src = application
+        .files
+        .iter()
+        .map(|(path, content)| format!("{}\n{}", path, content))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let build_res = run.step("build", &application.build_command, || {
+        build_tool_in_dir(
+            driver,
+            output_dir,
+            &application.build_command,
+            &src,
+            limits,
+            cache,
+        )
+    });
+    let test_res = run.step("test", &application.test_command, || {
+        build_tool_in_dir(
+            driver,
+            output_dir,
+            &application.test_command,
+            &src,
+            limits,
+            cache,
+        )
+    });
+    (build_res, test_res)
+}
@@ -1,657 +1,364 @@
-use crate::cache::Cache;
-use crate::llm_response::Project;
-use crate::{Lang, VERBOSE};
-
-pub fn build_tool(lang: &Lang, command_str: &str, cache: &mut Cache) -> (bool, String) {
-    match lang {
-        Lang::Rust => {
-            println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/lib.rs").unwrap();
-            let dependencies = std::fs::read_to_string("sandbox/Cargo.toml").unwrap();
-            let src = format!("{}\n{}", dependencies, code);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    let output = std::process::Command::new(command_parts[0])
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
-
-            let exit_code = parsed.0;
-            let output = parsed.1;
-
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
-        }
-        Lang::Java => {
-            println!("Launch: {}", command_str);
-            let code =
-                std::fs::read_to_string("sandbox/src/main/java/com/example/solution/Solution.java")
-                    .unwrap();
-            let test = std::fs::read_to_string(
-                "sandbox/src/test/java/com/example/solution/SolutionTest.java",
-            )
-            .unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/pom.xml").unwrap();
-            let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    // check OS if windows then add ".cmd" to command name in command_parts[0]
-                    let command = if cfg!(target_os = "windows") {
-                        format!("{}.cmd", command_parts[0])
-                    } else {
-                        command_parts[0].to_string()
-                    };
-                    let output = std::process::Command::new(command)
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
-
-            let exit_code = parsed.0;
-            let output = parsed.1;
-
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
-        }
-
-        Lang::Scala => {
-            println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/main/scala/Solution.scala").unwrap();
-            let test =
-                std::fs::read_to_string("sandbox/src/test/scala/SolutionTest.scala").unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/build.sbt").unwrap();
-            let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    // check OS if windows then add ".cmd" to command name in command_parts[0]
-                    let command = if cfg!(target_os = "windows") {
-                        format!("{}.cmd", command_parts[0])
-                    } else {
-                        command_parts[0].to_string()
-                    };
-                    let output = std::process::Command::new(command)
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
-
-            let exit_code = parsed.0;
-            let output = parsed.1;
-
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
-        }
-        Lang::Swift => {
-            println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/Sources/Solution/Solution.swift").unwrap();
-            let test =
-                std::fs::read_to_string("sandbox/Tests/SolutionTests/SolutionTests.swift").unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/Package.swift").unwrap();
-            let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    // check OS if windows then add ".cmd" to command name in command_parts[0]
-                    let command = command_parts[0].to_string();
-                    let output = std::process::Command::new(command)
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
-
-            let exit_code = parsed.0;
-            let output = parsed.1;
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{Cache, Namespace};
+use crate::command_line;
+use crate::config::ExecutionConfig;
+use crate::diagnostics::{self, Counts};
+use crate::executor;
+use crate::language_driver::LanguageDriver;
+use crate::llm_response::{Application, Project};
+use crate::VERBOSE;
+
+pub fn build_tool(
+    driver: &dyn LanguageDriver,
+    sandbox_dir: &str,
+    command_str: &str,
+    limits: &ExecutionConfig,
+    cache: &mut Cache,
+) -> (bool, String, Counts) {
+    let src = driver
+        .cache_key_files()
+        .iter()
+        .map(|path| std::fs::read_to_string(format!("{}/{}", sandbox_dir, path)).unwrap())
+        .collect::<Vec<_>>()
+        .join("\n");
+    run_commands(driver, sandbox_dir, command_str, &src, limits, cache)
+}
 
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
+pub fn create_project(driver: &dyn LanguageDriver, sandbox_dir: &str, project: &Project) {
+    let layout = driver.sandbox_layout(project);
+    println!("Create sandbox project with");
+    println!(
+        "{}",
+        layout
+            .iter()
+            .map(|(_, content)| content.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    if std::path::Path::new(sandbox_dir).exists() {
+        std::fs::remove_dir_all(sandbox_dir).unwrap();
+    }
+    std::fs::create_dir(sandbox_dir).unwrap();
+    for (path, content) in &layout {
+        let file_path = std::path::Path::new(sandbox_dir).join(path);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
         }
-        Lang::Kotlin => {
-            println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/main/kotlin/Solution.kt").unwrap();
-            let test = std::fs::read_to_string("sandbox/src/test/kotlin/SolutionTest.kt").unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/build.gradle").unwrap();
-            let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    // check OS if windows then add ".cmd" to command name in command_parts[0]
-                    let command = if cfg!(target_os = "windows") {
-                        format!("{}.bat", command_parts[0])
-                    } else {
-                        command_parts[0].to_string()
-                    };
-                    // println!("{}, {:?}", command, args);
-                    let output = std::process::Command::new(command)
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
-
-            let exit_code = parsed.0;
-            let output = parsed.1;
+        std::fs::write(&file_path, content).unwrap();
+    }
+}
 
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
-        }
+/// Output of a build or test command as it is cached.
+#[derive(Serialize, Deserialize)]
+struct CommandOutput {
+    exit_code: i32,
+    stdout: String,
+    stderr: String,
+    /// Why the command was stopped: timeout, exceeded limit or launch error.
+    #[serde(default)]
+    limit: Option<String>,
+    /// Contents of the JUnit XML reports written by the command.
+    reports: Vec<String>,
+}
 
-        Lang::Python => {
-            println!("Launch: {}", command_str);
-            if command_str == "" {
-                return (true, "".to_string());
-            }
-            let code = std::fs::read_to_string("sandbox/solution.py").unwrap();
-            let test = std::fs::read_to_string("sandbox/test.py").unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/requirements.txt").unwrap();
-            let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    let output = std::process::Command::new(command_parts[0])
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
+/// Runs a build or test command of a project located in `dir`. `src` is the
+/// content of the project files and makes the cache key together with the command.
+/// A failed command is reported with the errors and failed tests found in its
+/// output, a command stopped by the `limits` with the reason.
+pub fn build_tool_in_dir(
+    driver: &dyn LanguageDriver,
+    dir: &str,
+    command_str: &str,
+    src: &str,
+    limits: &ExecutionConfig,
+    cache: &mut Cache,
+) -> (bool, String) {
+    let (success, message, _) = run_commands(driver, dir, command_str, src, limits, cache);
+    (success, message)
+}
 
-            let exit_code = parsed.0;
-            let output = parsed.1;
+/// `build_tool_in_dir` which also counts the errors and failed tests of a
+/// failed command.
+fn run_commands(
+    driver: &dyn LanguageDriver,
+    dir: &str,
+    command_str: &str,
+    src: &str,
+    limits: &ExecutionConfig,
+    cache: &mut Cache,
+) -> (bool, String, Counts) {
+    let commands = match command_line::parse(command_str) {
+        Ok(commands) => commands,
+        Err(err) => return rejected(err),
+    };
+    // nothing is run when one of the commands is not allowed
+    let allowed = driver.allowed_commands();
+    if let Some(command) = commands
+        .iter()
+        .find(|command| !command_line::is_allowed(command, &allowed))
+    {
+        return rejected(command_line::rejection(command, &allowed));
+    }
 
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
+    let mut previous = String::new();
+    for command in &commands {
+        let command = driver.diagnostic_command(command);
+        let command_str = shell_words::join(&command);
+        println!("Launch: {}", command_str);
+        // a command depends on the ones run before it
+        previous = format!("{}{}\n", previous, command_str);
+        let key = format!("{}{}", previous, src);
+        let output = run_command(driver, dir, &command, &key, limits, cache);
+        println!("Exit result: {}", output.exit_code == 0);
+        if let Some(limit) = &output.limit {
+            println!("{}", limit);
         }
-
-        Lang::JavaScript => {
-            println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/solution.js").unwrap();
-            let test = std::fs::read_to_string("sandbox/src/solution.test.js").unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/package.json").unwrap();
-            let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    // check OS if windows then add ".cmd" to command name in command_parts[0]
-                    let command = if cfg!(target_os = "windows") {
-                        format!("{}.cmd", command_parts[0])
-                    } else {
-                        command_parts[0].to_string()
-                    };
-                    let output = std::process::Command::new(command)
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
-
-            let exit_code = parsed.0;
-            let output = parsed.1;
-
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
+        if *VERBOSE.lock().unwrap() {
+            println!("Output: {}{}", output.stdout, output.stderr);
         }
-        Lang::TypeScript => {
-            println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/solution.ts").unwrap();
-            let test = std::fs::read_to_string("sandbox/src/solution.test.ts").unwrap();
-            let config = std::fs::read_to_string("sandbox/tsconfig.json").unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/package.json").unwrap();
-            let src = format!("{}\n{}\n{}", dependencies, config, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    // check OS if windows then add ".cmd" to command name in command_parts[0]
-                    let command = if cfg!(target_os = "windows") {
-                        format!("{}.cmd", command_parts[0])
-                    } else {
-                        command_parts[0].to_string()
-                    };
-                    let output = std::process::Command::new(command)
-                        .args(args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
+        if output.exit_code != 0 {
+            let (message, counts) = error_message(&output, dir);
+            return match commands.len() {
+                1 => (false, message, counts),
+                _ => (
+                    false,
+                    format!("\"{}\" failed:\n{}", command_str, message),
+                    counts,
+                ),
             };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
-
-            let exit_code = parsed.0;
-            let output = parsed.1;
-
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
         }
-        Lang::Php => {
-            println!("Launch: {}", command_str);
-            let code = std::fs::read_to_string("sandbox/src/Solution.php").unwrap();
-            let test = std::fs::read_to_string("sandbox/tests/SolutionTest.php").unwrap();
-            let code_and_test = format!("{}\n{}", code, test);
-            let dependencies = std::fs::read_to_string("sandbox/composer.json").unwrap();
-            let src = format!("{}\n{}", dependencies, code_and_test);
-            let key = format!("{}{}", command_str, src);
-            let result_str_opt = cache.get(&key);
-            let result_str = match result_str_opt {
-                None => {
-                    let command_parts = command_str.split(" ").collect::<Vec<&str>>();
-                    let args = command_parts[1..].to_vec();
-                    // check OS if windows then add ".cmd" to command name in command_parts[0]
-                    let command = if cfg!(target_os = "windows") {
-                        format!("{}.cmd", command_parts[0])
-                    } else {
-                        command_parts[0].to_string()
-                    };
-                    println!("ARGS - {:?}", args);
-
-                    let output = std::process::Command::new(&command)
-                        .args(&args)
-                        .current_dir("sandbox")
-                        .output()
-                        .unwrap();
-
-                    let exit_code = output.status.code().unwrap();
-                    // let std_out = String::from_utf8_lossy(output.stdout).unwrap();
-                    let std_err = String::from_utf8_lossy(&output.stderr).to_string();
-                    let tuple: (i32, String) = (exit_code, std_err);
-                    let json_str = serde_json::to_string(&tuple).unwrap();
-                    cache.set(key, json_str.clone());
-                    json_str
-                }
-                Some(result) => result.to_string(),
-            };
-            let parsed: (i32, String) = serde_json::from_str(&result_str).unwrap();
+    }
+    (true, "".to_string(), Counts::default())
+}
 
-            let exit_code = parsed.0;
-            let output = parsed.1;
+fn rejected(message: String) -> (bool, String, Counts) {
+    println!("Rejected: {}", message);
+    (false, message, Counts::default())
+}
 
-            println!("Exit result: {}", exit_code == 0);
-            if *VERBOSE.lock().unwrap() {
-                println!("Output: {}", output);
-            }
-            let exit_code_bool = exit_code == 0;
-            (exit_code_bool, only_error_message(&output, exit_code))
-        }
-        _ => panic!("Unsupported language: {:?}", lang),
+/// Output of the command from the cache, or of the command run in `dir`.
+fn run_command(
+    driver: &dyn LanguageDriver,
+    dir: &str,
+    command: &[String],
+    key: &str,
+    limits: &ExecutionConfig,
+    cache: &mut Cache,
+) -> CommandOutput {
+    // results cached by older versions have no stdout and are run again
+    let cached = cache
+        .get(Namespace::Build, key)
+        .and_then(|json| serde_json::from_str::<CommandOutput>(&json).ok());
+    if let Some(output) = cached {
+        return output;
+    }
+    // reports of a previous run would be taken for reports of this one
+    for report_dir in driver.test_reports() {
+        let _ = std::fs::remove_dir_all(std::path::Path::new(dir).join(report_dir));
+    }
+    let args = command[1..]
+        .iter()
+        .map(|arg| arg.as_str())
+        .collect::<Vec<_>>();
+    let execution = executor::run(&driver.executable(&command[0]), &args, dir, limits);
+    let output = CommandOutput {
+        exit_code: execution.exit_code,
+        stdout: execution.stdout,
+        stderr: execution.stderr,
+        limit: execution.limit,
+        reports: read_reports(dir, &driver.test_reports()),
+    };
+    // a timeout may be caused by a busy machine, the command is run again next time
+    if !execution.timed_out {
+        cache.set(
+            Namespace::Build,
+            "",
+            key,
+            &serde_json::to_string(&output).unwrap(),
+        );
     }
+    output
 }
 
-pub fn create_project_rust(lang: &Lang, project: &Project) {
-    match lang {
-        Lang::Rust => {
-            println!("Create sandbox project with");
-            println!("{}\n{}", project.dependencies, project.solution_code);
-            let sandbox_path = "sandbox";
-            let src_path = format!("{}/src", sandbox_path);
-            let main_path = format!("{}/src/lib.rs", sandbox_path);
-            let cargo_path = format!("{}/Cargo.toml", sandbox_path);
-            if !std::path::Path::new(sandbox_path).exists() {
-                std::fs::create_dir(sandbox_path).unwrap();
-            } else {
-                std::fs::remove_dir_all(sandbox_path).unwrap();
-                std::fs::create_dir(sandbox_path).unwrap();
+/// XML files of the report folders relative to `dir`.
+fn read_reports(dir: &str, report_dirs: &[String]) -> Vec<String> {
+    let mut reports = Vec::new();
+    for report_dir in report_dirs {
+        let Ok(entries) = std::fs::read_dir(std::path::Path::new(dir).join(report_dir)) else {
+            continue;
+        };
+        let mut paths = entries
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map(|ext| ext == "xml") == Some(true))
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            match std::fs::read_to_string(&path) {
+                Ok(report) => reports.push(report),
+                Err(err) => println!("Warning: Cant read \"{}\": {}", path.display(), err),
             }
-            if !std::path::Path::new(&src_path).exists() {
-                std::fs::create_dir(&src_path).unwrap();
-            }
-            std::fs::write(&main_path, &project.solution_code).unwrap();
-            std::fs::write(&cargo_path, &project.dependencies).unwrap();
         }
-        _ => panic!("Unsupported language: {:?}", lang),
     }
+    reports
 }
-pub fn create_project_java(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}",
-        project.dependencies, project.solution_code, project.test_code
-    );
-    let sandbox_path = "sandbox";
 
-    let main_path = format!(
-        "{}/src/main/java/com/example/solution/Solution.java",
-        sandbox_path
-    );
-    let test_path = format!(
-        "{}/src/test/java/com/example/solution/SolutionTest.java",
-        sandbox_path
-    );
-    let pom_path = format!("{}/pom.xml", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
+/// Writes the files of the application into `output_dir`. Files which are
+/// already there and not a part of the application are kept.
+pub fn create_application(output_dir: &str, application: &Application) {
+    println!("Create application project in {}", output_dir);
+    for (path, content) in &application.files {
+        let file_path = std::path::Path::new(output_dir).join(path);
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent).unwrap();
+        }
+        std::fs::write(&file_path, content).unwrap();
     }
-    std::fs::create_dir_all(format!(
-        "{}/src/main/java/com/example/solution",
-        sandbox_path
-    ))
-    .unwrap();
-    std::fs::create_dir_all(format!(
-        "{}/src/test/java/com/example/solution",
-        sandbox_path
-    ))
-    .unwrap();
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
-pub fn create_project_scala(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}",
-        project.dependencies, project.solution_code, project.test_code
-    );
-    let sandbox_path = "sandbox";
 
-    let main_path = format!("{}/src/main/scala/Solution.scala", sandbox_path);
-    let test_path = format!("{}/src/test/scala/SolutionTest.scala", sandbox_path);
-    let pom_path = format!("{}/build.sbt", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
+/// Copies the project in `from` into the empty scratch folder `to`, skipping
+/// `.git`, files ignored by `.gitignore` and the scratch folder itself.
+/// Returns the copied paths relative to the project and the hash of their contents.
+pub fn copy_project(from: &str, to: &str) -> Result<(Vec<String>, String), String> {
+    let root = std::path::Path::new(from);
+    if !root.is_dir() {
+        return Err(format!("\"{}\" is not a folder", from));
     }
-    std::fs::create_dir_all(format!("{}/src/main/scala", sandbox_path)).unwrap();
-    std::fs::create_dir_all(format!("{}/src/test/scala", sandbox_path)).unwrap();
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
-}
-pub fn create_project_swift(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}",
-        project.dependencies, project.solution_code, project.test_code
-    );
-    let sandbox_path = "sandbox";
-
-    let main_path = format!("{}/Sources/Solution/Solution.swift", sandbox_path);
-    let test_path = format!("{}/Tests/SolutionTests/SolutionTests.swift", sandbox_path);
-    let pom_path = format!("{}/Package.swift", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
+    if std::path::Path::new(to).exists() {
+        // never wipe the project itself
+        let project = std::fs::canonicalize(root).map_err(|err| err.to_string())?;
+        let scratch = std::fs::canonicalize(to).map_err(|err| err.to_string())?;
+        if project.starts_with(&scratch) {
+            return Err(format!(
+                "The scratch folder \"{}\" contains the project \"{}\"",
+                to, from
+            ));
+        }
+        std::fs::remove_dir_all(to).map_err(|err| format!("Can't clean \"{}\": {}", to, err))?;
     }
-    std::fs::create_dir_all(format!("{}/Sources/Solution/", sandbox_path)).unwrap();
-    std::fs::create_dir_all(format!("{}/Tests/SolutionTests", sandbox_path)).unwrap();
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
-}
-pub fn create_project_kotlin(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}",
-        project.dependencies, project.solution_code, project.test_code
-    );
-    let sandbox_path = "sandbox";
+    std::fs::create_dir_all(to).map_err(|err| format!("Can't create \"{}\": {}", to, err))?;
 
-    let main_path = format!("{}/src/main/kotlin/Solution.kt", sandbox_path);
-    let test_path = format!("{}/src/test/kotlin/SolutionTest.kt", sandbox_path);
-    let pom_path = format!("{}/build.gradle", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
+    let files = project_files(from, to)?;
+    for (relative, content) in &files {
+        let target = std::path::Path::new(to).join(relative);
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| err.to_string())?;
+        }
+        std::fs::write(&target, content)
+            .map_err(|err| format!("Can't write \"{}\": {}", target.display(), err))?;
     }
-    std::fs::create_dir_all(format!("{}/src/main/kotlin", sandbox_path)).unwrap();
-    std::fs::create_dir_all(format!("{}/src/test/kotlin", sandbox_path)).unwrap();
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
+    let hash = hash_files(&files);
+    Ok((files.into_iter().map(|(path, _)| path).collect(), hash))
 }
-pub fn create_project_python(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}",
-        project.dependencies, project.solution_code, project.test_code
-    );
-    let sandbox_path = "sandbox";
 
-    let main_path = format!("{}/solution.py", sandbox_path);
-    let test_path = format!("{}/test.py", sandbox_path);
-    let pom_path = format!("{}/requirements.txt", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
-    }
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
+/// Hash of the project in `from` like the one returned by `copy_project`, to
+/// check that the project did not change since it was copied into `scratch`.
+pub fn project_hash(from: &str, scratch: &str) -> Result<String, String> {
+    Ok(hash_files(&project_files(from, scratch)?))
 }
 
-pub fn create_project_javascript(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}",
-        project.dependencies, project.solution_code, project.test_code
-    );
-    let sandbox_path = "sandbox";
-
-    let main_path = format!("{}/src/solution.js", sandbox_path);
-    let test_path = format!("{}/src/solution.test.js", sandbox_path);
-    let pom_path = format!("{}/package.json", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
+/// Files of the project copied by `copy_project` and their contents, sorted by
+/// their path relative to the project.
+fn project_files(from: &str, scratch: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+    let root = std::path::Path::new(from);
+    let scratch = std::fs::canonicalize(scratch).ok();
+    let walker = ignore::WalkBuilder::new(root)
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(move |entry| {
+            entry.file_name() != ".git"
+                && (scratch.is_none() || std::fs::canonicalize(entry.path()).ok() != scratch)
+        })
+        .build();
+    let mut files = Vec::new();
+    for entry in walker {
+        let entry = entry.map_err(|err| err.to_string())?;
+        if entry.file_type().map(|t| t.is_file()) != Some(true) {
+            continue;
+        }
+        let relative = entry.path().strip_prefix(root).unwrap();
+        let content = std::fs::read(entry.path())
+            .map_err(|err| format!("Can't read \"{}\": {}", entry.path().display(), err))?;
+        files.push((relative.to_string_lossy().replace('\\', "/"), content));
     }
-    std::fs::create_dir_all(format!("{}/src", sandbox_path)).unwrap();
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
+    // the walker order depends on the file system
+    files.sort();
+    Ok(files)
 }
 
-pub fn create_project_typescript(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}\n{}",
-        project.dependencies,
-        project.additional_config[0],
-        project.solution_code,
-        project.test_code
-    );
-    let sandbox_path = "sandbox";
+fn hash_files(files: &[(String, Vec<u8>)]) -> String {
+    use sha2::{Digest, Sha256};
 
-    let main_path = format!("{}/src/solution.ts", sandbox_path);
-    let test_path = format!("{}/src/solution.test.ts", sandbox_path);
-    let pom_path = format!("{}/package.json", sandbox_path);
-    let config_path = format!("{}/tsconfig.json", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
+    let mut hasher = Sha256::new();
+    for (relative, content) in files {
+        hasher.update(relative.as_bytes());
+        hasher.update([0]);
+        hasher.update((content.len() as u64).to_le_bytes());
+        hasher.update(content);
     }
-    std::fs::create_dir_all(format!("{}/src", sandbox_path)).unwrap();
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
-    std::fs::write(&config_path, &project.additional_config[0]).unwrap();
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
 }
 
-pub fn create_project_php(project: &Project) {
-    println!("Create sandbox project with");
-    println!(
-        "{}\n{}\n{}",
-        project.dependencies, project.solution_code, project.test_code
-    );
-    let sandbox_path = "sandbox";
-    let main_path = format!("{}/src/Solution.php", sandbox_path);
-    let test_path = format!("{}/tests/SolutionTest.php", sandbox_path);
-    let pom_path = format!("{}/composer.json", sandbox_path);
-    if !std::path::Path::new(sandbox_path).exists() {
-        std::fs::create_dir(sandbox_path).unwrap();
-    } else {
-        std::fs::remove_dir_all(sandbox_path).unwrap();
-        std::fs::create_dir(sandbox_path).unwrap();
+/// What the LLM is told about a failed command.
+fn error_message(output: &CommandOutput, dir: &str) -> (String, Counts) {
+    let (message, counts) =
+        diagnostics::error_message(&output.stdout, &output.stderr, &output.reports, dir);
+    match &output.limit {
+        Some(limit) => (
+            format!("{}\n{}", limit, message).trim_end().to_string(),
+            counts,
+        ),
+        None => (message, counts),
     }
-    std::fs::create_dir_all(format!("{}/src", sandbox_path)).unwrap();
-    std::fs::create_dir_all(format!("{}/tests", sandbox_path)).unwrap();
-    std::fs::write(&main_path, &project.solution_code).unwrap();
-    std::fs::write(&test_path, &project.test_code).unwrap();
-    std::fs::write(&pom_path, &project.dependencies).unwrap();
 }
 
-fn only_error_message(output: &str, exit_code: i32) -> String {
-    if exit_code == 0 {
-        return "".to_string();
-    } else {
-        output.to_string()
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_copy_project() {
+        let root = std::env::temp_dir().join("rustsn_test_copy_project");
+        let _ = std::fs::remove_dir_all(&root);
+        let project = root.join("project");
+        for dir in ["src", "target", ".git"] {
+            std::fs::create_dir_all(project.join(dir)).unwrap();
+        }
+        std::fs::write(project.join(".gitignore"), "target/\n").unwrap();
+        std::fs::write(project.join("Cargo.toml"), "[package]").unwrap();
+        std::fs::write(project.join("src/lib.rs"), "pub mod utils;").unwrap();
+        std::fs::write(project.join("target/lib.rlib"), "binary").unwrap();
+        std::fs::write(project.join(".git/HEAD"), "ref").unwrap();
+
+        let project_str = project.to_str().unwrap();
+        let scratch = root.join("scratch");
+        let (files, hash) = super::copy_project(project_str, scratch.to_str().unwrap()).unwrap();
+        assert_eq!(files, vec![".gitignore", "Cargo.toml", "src/lib.rs"]);
+        assert!(scratch.join("src/lib.rs").exists());
+        assert!(!scratch.join("target").exists());
+        assert_eq!(hash.len(), 64);
+        let hash_of = |scratch: &std::path::Path| {
+            super::project_hash(project_str, scratch.to_str().unwrap()).unwrap()
+        };
+        assert_eq!(hash_of(&scratch), hash);
+
+        // a scratch folder inside the project is neither copied nor hashed
+        let inner = project.join("sandbox");
+        let (files, inner_hash) =
+            super::copy_project(project_str, inner.to_str().unwrap()).unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(inner_hash, hash);
+        std::fs::write(inner.join("src/lib.rs"), "pub mod other;").unwrap();
+        assert_eq!(hash_of(&inner), hash);
+        std::fs::write(project.join("src/lib.rs"), "pub mod other;").unwrap();
+        assert_ne!(hash_of(&inner), hash);
+
+        // the project must never be wiped as the scratch folder
+        assert!(super::copy_project(project_str, root.to_str().unwrap()).is_err());
+        assert!(project.join("src/lib.rs").exists());
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
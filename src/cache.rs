@@ -1,55 +1,610 @@
 use bincode::{config, Decode, Encode};
-use std::collections::HashMap;
+use redb::backends::InMemoryBackend;
+use redb::{Database, ReadableTable, TableDefinition, TableError, TableHandle};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
 use std::fs;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-#[derive(Encode, Decode, PartialEq, Debug)]
+use crate::config::CacheConfig;
+
+type Table = TableDefinition<'static, &'static [u8], &'static [u8]>;
+
+/// Kind of the cached results, every namespace is a table of the database.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Namespace {
+    /// Completions of the LLM.
+    Llm,
+    /// Embeddings, stored as little-endian `f32` values.
+    Embedding,
+    /// Outputs of the build and test commands.
+    Build,
+}
+
+impl Namespace {
+    pub const ALL: [Namespace; 3] = [Namespace::Llm, Namespace::Embedding, Namespace::Build];
+
+    fn table(self) -> Table {
+        match self {
+            Namespace::Llm => TableDefinition::new("llm"),
+            Namespace::Embedding => TableDefinition::new("embedding"),
+            Namespace::Build => TableDefinition::new("build"),
+        }
+    }
+}
+
+impl Display for Namespace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.table().name())
+    }
+}
+
+impl FromStr for Namespace {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Namespace::ALL
+            .into_iter()
+            .find(|namespace| namespace.to_string() == s)
+            .ok_or_else(|| format!("Unknown cache namespace: {}", s))
+    }
+}
+
+/// How the LLM completions and embeddings are cached, set by `--no-cache`
+/// and `--refresh`. Build results are always cached.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Cached results are used and new ones are stored.
+    ReadWrite,
+    /// Everything is requested again and replaces the cached results.
+    Refresh,
+    /// Nothing is read from or written to the cache.
+    Off,
+}
+
+/// Value of an entry with the model which produced it, stored by the
+/// SHA-256 of the key.
+#[derive(Encode, Decode)]
+struct Record {
+    model: String,
+    /// Seconds since the Unix epoch.
+    created: u64,
+    value: Vec<u8>,
+}
+
+/// Entry as listed by `rustsn cache list`.
+#[derive(Debug)]
+pub struct EntryInfo {
+    pub namespace: Namespace,
+    /// Hex of the key hash.
+    pub key: String,
+    pub model: String,
+    pub created: u64,
+    pub size: usize,
+}
+
+/// Entries selected by the cache commands, every field which is set must match.
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub namespace: Option<Namespace>,
+    pub model: Option<String>,
+    /// Age in seconds.
+    pub older_than: Option<u64>,
+}
+
+impl Filter {
+    fn matches(&self, namespace: Namespace, record: &Record, now: u64) -> bool {
+        self.namespace.is_none_or(|selected| selected == namespace)
+            && self
+                .model
+                .as_ref()
+                .is_none_or(|model| *model == record.model)
+            && self
+                .older_than
+                .is_none_or(|age| now.saturating_sub(record.created) >= age)
+    }
+}
+
+/// Cache of the LLM completions, embeddings and build results in a redb
+/// database file. Every `set` is a committed transaction, so a crash never
+/// leaves a half-written file. Entries older than the TTL of their namespace
+/// are ignored. Clones share the database and can be used from other threads.
+#[derive(Clone)]
 pub struct Cache {
-    cache: HashMap<String, String>,
+    db: Arc<Database>,
+    config: CacheConfig,
+    mode: Mode,
 }
 
 impl Cache {
-    pub fn new() -> Cache {
-        let mut cache = Cache {
-            cache: HashMap::new(),
+    pub fn new(path: &str, config: &CacheConfig) -> Cache {
+        let db = open(path).unwrap_or_else(|err| {
+            println!(
+                "Warning: Cant open the cache \"{}\": {}. Results are cached in memory only",
+                path, err
+            );
+            Database::builder()
+                .create_with_backend(InMemoryBackend::new())
+                .unwrap()
+        });
+        Cache {
+            db: Arc::new(db),
+            config: config.clone(),
+            mode: Mode::ReadWrite,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        self.mode = mode;
+    }
+
+    pub fn get(&self, namespace: Namespace, key: &str) -> Option<String> {
+        self.read(namespace, key)
+            .map(|value| String::from_utf8_lossy(&value).to_string())
+    }
+
+    pub fn set(&mut self, namespace: Namespace, model: &str, key: &str, value: &str) {
+        self.write(namespace, model, key, value.as_bytes().to_vec());
+    }
+
+    pub fn get_embedding(&self, key: &str) -> Option<Vec<f32>> {
+        self.read(Namespace::Embedding, key)
+            .map(|value| decode_embedding(&value))
+    }
+
+    pub fn set_embedding(&mut self, model: &str, key: &str, embedding: &[f32]) {
+        self.write(
+            Namespace::Embedding,
+            model,
+            key,
+            encode_embedding(embedding),
+        );
+    }
+
+    /// Entries matching `filter`, oldest first.
+    pub fn entries(&self, filter: &Filter) -> Result<Vec<EntryInfo>, String> {
+        let now = now();
+        let mut entries = Vec::new();
+        self.scan(|namespace, key, record| {
+            if filter.matches(namespace, record, now) {
+                entries.push(EntryInfo {
+                    namespace,
+                    key: hex(key),
+                    model: record.model.clone(),
+                    created: record.created,
+                    size: record.value.len(),
+                });
+            }
+        })
+        .map_err(|err| err.to_string())?;
+        entries.sort_by_key(|entry| entry.created);
+        Ok(entries)
+    }
+
+    /// Number of entries, size and models by namespace for `rustsn cache stats`.
+    pub fn stats(&self) -> Result<String, String> {
+        let mut lines = vec![format!(
+            "{:<10} {:>8} {:>12}  {:<16} {:<16} {}",
+            "NAMESPACE", "ENTRIES", "SIZE", "OLDEST", "NEWEST", "MODELS"
+        )];
+        let entries = self.entries(&Filter::default())?;
+        for namespace in Namespace::ALL {
+            let entries = entries
+                .iter()
+                .filter(|entry| entry.namespace == namespace)
+                .collect::<Vec<_>>();
+            let mut models = BTreeMap::new();
+            for entry in &entries {
+                *models.entry(display_model(&entry.model)).or_insert(0) += 1;
+            }
+            let time = |entry: Option<&&EntryInfo>| {
+                entry.map_or("-".to_string(), |entry| format_time(entry.created))
+            };
+            lines.push(format!(
+                "{:<10} {:>8} {:>12}  {:<16} {:<16} {}",
+                namespace.to_string(),
+                entries.len(),
+                entries.iter().map(|entry| entry.size).sum::<usize>(),
+                time(entries.first()),
+                time(entries.last()),
+                models
+                    .iter()
+                    .map(|(model, count)| format!("{} ({})", model, count))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        let ttl = Namespace::ALL
+            .iter()
+            .map(|namespace| match self.ttl(*namespace) {
+                0 => format!("{} never", namespace),
+                ttl => format!("{} after {}h", namespace, ttl / 3600),
+            })
+            .collect::<Vec<_>>();
+        lines.push(format!("Expiry: {}", ttl.join(", ")));
+        Ok(lines.join("\n"))
+    }
+
+    /// Table of the entries matching `filter` for `rustsn cache list`.
+    pub fn list_table(&self, filter: &Filter) -> Result<String, String> {
+        let mut lines = vec![format!(
+            "{:<12} {:<10} {:<24} {:<16} {:>10}",
+            "KEY", "NAMESPACE", "MODEL", "CREATED", "SIZE"
+        )];
+        for entry in self.entries(filter)? {
+            lines.push(format!(
+                "{:<12} {:<10} {:<24} {:<16} {:>10}",
+                &entry.key[..12],
+                entry.namespace.to_string(),
+                display_model(&entry.model),
+                format_time(entry.created),
+                entry.size
+            ));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// Removes the entries matching `filter`, with `expired` only the ones
+    /// older than the TTL of their namespace. Returns the number of removed entries.
+    pub fn remove(&mut self, filter: &Filter, expired: bool) -> Result<usize, String> {
+        let now = now();
+        let mut removed = Vec::new();
+        self.scan(|namespace, key, record| {
+            let ttl = self.ttl(namespace);
+            let is_expired = ttl > 0 && now.saturating_sub(record.created) >= ttl;
+            if filter.matches(namespace, record, now) && (!expired || is_expired) {
+                removed.push((namespace, key.to_vec()));
+            }
+        })
+        .map_err(|err| err.to_string())?;
+        delete_records(&self.db, &removed).map_err(|err| err.to_string())?;
+        // the file only shrinks when no other handle of the database is open
+        if let Some(db) = Arc::get_mut(&mut self.db) {
+            db.compact().map_err(|err| err.to_string())?;
+        }
+        Ok(removed.len())
+    }
+
+    /// Writes the entries matching `filter` into a new cache file at `path`.
+    pub fn export(&self, path: &str, filter: &Filter) -> Result<usize, String> {
+        if std::path::Path::new(path).exists() {
+            return Err(format!("\"{}\" already exists", path));
+        }
+        let now = now();
+        let mut records = Vec::new();
+        self.scan(|namespace, key, record| {
+            if filter.matches(namespace, record, now) {
+                records.push((namespace, key.to_vec(), encode_record(record)));
+            }
+        })
+        .map_err(|err| err.to_string())?;
+        let db = Database::create(path).map_err(|err| err.to_string())?;
+        insert_records(&db, &records).map_err(|err| err.to_string())?;
+        Ok(records.len())
+    }
+
+    /// Adds the entries of the cache file at `path`. Cached entries are only
+    /// replaced by newer ones. Returns the number of added entries.
+    pub fn import(&mut self, path: &str) -> Result<usize, String> {
+        if !std::path::Path::new(path).exists() {
+            return Err(format!("\"{}\" does not exist", path));
+        }
+        let other = Cache {
+            db: Arc::new(Database::open(path).map_err(|err| err.to_string())?),
+            config: self.config.clone(),
+            mode: Mode::ReadWrite,
         };
-        cache.restore();
-        cache
+        let mut records = Vec::new();
+        other
+            .scan(|namespace, key, record| {
+                let known = self
+                    .read_record(namespace, key)
+                    .ok()
+                    .flatten()
+                    .is_some_and(|known| known.created >= record.created);
+                if !known {
+                    records.push((namespace, key.to_vec(), encode_record(record)));
+                }
+            })
+            .map_err(|err| err.to_string())?;
+        insert_records(&self.db, &records).map_err(|err| err.to_string())?;
+        Ok(records.len())
     }
 
-    pub fn get(&mut self, key: &str) -> Option<&String> {
-        self.cache.get(key)
+    fn ttl(&self, namespace: Namespace) -> u64 {
+        let hours = match namespace {
+            Namespace::Llm => self.config.llm_ttl_hours,
+            Namespace::Embedding => self.config.embedding_ttl_hours,
+            Namespace::Build => self.config.build_ttl_hours,
+        };
+        hours * 3600
     }
 
-    pub fn set(&mut self, key: String, value: String) {
-        self.cache.insert(key, value);
-        self.save();
+    fn read(&self, namespace: Namespace, key: &str) -> Option<Vec<u8>> {
+        if namespace != Namespace::Build && self.mode != Mode::ReadWrite {
+            return None;
+        }
+        let record = self
+            .read_record(namespace, &hash(key))
+            .unwrap_or_else(|err| {
+                println!("Warning: Cant read the cache: {}", err);
+                None
+            })?;
+        let ttl = self.ttl(namespace);
+        if ttl > 0 && now().saturating_sub(record.created) >= ttl {
+            return None;
+        }
+        Some(record.value)
     }
 
-    fn save(&mut self) {
-        let config = config::standard();
-        let encoded: Vec<u8> = bincode::encode_to_vec(&*self, config).unwrap();
-        fs::write("cache.bin", encoded).unwrap();
+    #[allow(clippy::result_large_err)]
+    fn read_record(&self, namespace: Namespace, key: &[u8]) -> Result<Option<Record>, redb::Error> {
+        let transaction = self.db.begin_read()?;
+        let table = match transaction.open_table(namespace.table()) {
+            Ok(table) => table,
+            Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let value = table.get(key)?;
+        Ok(value.and_then(|value| decode_record(value.value())))
     }
 
-    fn restore(&mut self) {
-        if !std::path::Path::new("cache.bin").exists() {
+    fn write(&self, namespace: Namespace, model: &str, key: &str, value: Vec<u8>) {
+        if namespace != Namespace::Build && self.mode == Mode::Off {
             return;
         }
-        let config = config::standard();
-        let encoded = fs::read("cache.bin").unwrap();
-        let (decoded, _): (Cache, usize) = bincode::decode_from_slice(&encoded, config).unwrap();
-        *self = decoded;
+        let record = Record {
+            model: model.to_string(),
+            created: now(),
+            value,
+        };
+        let records = [(namespace, hash(key).to_vec(), encode_record(&record))];
+        if let Err(err) = insert_records(&self.db, &records) {
+            println!("Warning: Cant write the cache: {}", err);
+        }
+    }
+
+    /// Calls `visit` with the namespace, the key hash and the record of every entry.
+    #[allow(clippy::result_large_err)]
+    fn scan(&self, mut visit: impl FnMut(Namespace, &[u8], &Record)) -> Result<(), redb::Error> {
+        let transaction = self.db.begin_read()?;
+        for namespace in Namespace::ALL {
+            let table = match transaction.open_table(namespace.table()) {
+                Ok(table) => table,
+                Err(TableError::TableDoesNotExist(_)) => continue,
+                Err(err) => return Err(err.into()),
+            };
+            for entry in table.iter()? {
+                let (key, value) = entry?;
+                if let Some(record) = decode_record(value.value()) {
+                    visit(namespace, key.value(), &record);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Opens or creates the database at `path`. A bincode `cache.bin` of older
+/// versions is converted, the old file is kept as `<path>.bak`. Converted
+/// entries have no model and the current time.
+#[allow(clippy::result_large_err)]
+fn open(path: &str) -> Result<Database, redb::Error> {
+    let err = match Database::create(path) {
+        Ok(db) => return Ok(db),
+        Err(err) => err,
+    };
+    let Some(entries) = read_legacy(path) else {
+        return Err(err.into());
+    };
+    let backup = format!("{}.bak", path);
+    fs::rename(path, &backup)?;
+    println!(
+        "Convert the cache \"{}\" of an older version, the old file is kept as \"{}\"",
+        path, backup
+    );
+    let db = Database::create(path)?;
+    let records = entries
+        .iter()
+        .map(|(key, value)| {
+            // embeddings were stored as JSON arrays
+            let (namespace, value) = match serde_json::from_str::<Vec<f32>>(value) {
+                Ok(embedding) if !embedding.is_empty() => {
+                    (Namespace::Embedding, encode_embedding(&embedding))
+                }
+                _ => (legacy_namespace(value), value.as_bytes().to_vec()),
+            };
+            (namespace, hash(key).to_vec(), legacy_record(value))
+        })
+        .collect::<Vec<_>>();
+    insert_records(&db, &records)?;
+    Ok(db)
+}
+
+/// Build results are JSON objects with an exit code, everything else is an LLM completion.
+fn legacy_namespace(value: &str) -> Namespace {
+    match serde_json::from_str::<serde_json::Value>(value) {
+        Ok(output) if output.get("exit_code").is_some() => Namespace::Build,
+        _ => Namespace::Llm,
+    }
+}
+
+fn legacy_record(value: Vec<u8>) -> Vec<u8> {
+    encode_record(&Record {
+        model: String::new(),
+        created: now(),
+        value,
+    })
+}
+
+fn read_legacy(path: &str) -> Option<HashMap<String, String>> {
+    let encoded = fs::read(path).ok()?;
+    let (decoded, _): (HashMap<String, String>, usize) =
+        bincode::decode_from_slice(&encoded, config::standard()).ok()?;
+    Some(decoded)
+}
+
+#[allow(clippy::result_large_err)]
+fn insert_records(
+    db: &Database,
+    records: &[(Namespace, Vec<u8>, Vec<u8>)],
+) -> Result<(), redb::Error> {
+    let transaction = db.begin_write()?;
+    for (namespace, key, record) in records {
+        let mut table = transaction.open_table(namespace.table())?;
+        table.insert(key.as_slice(), record.as_slice())?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+fn delete_records(db: &Database, keys: &[(Namespace, Vec<u8>)]) -> Result<(), redb::Error> {
+    let transaction = db.begin_write()?;
+    for (namespace, key) in keys {
+        let mut table = transaction.open_table(namespace.table())?;
+        table.remove(key.as_slice())?;
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+fn encode_record(record: &Record) -> Vec<u8> {
+    bincode::encode_to_vec(record, config::standard()).unwrap()
+}
+
+fn decode_record(bytes: &[u8]) -> Option<Record> {
+    bincode::decode_from_slice(bytes, config::standard())
+        .ok()
+        .map(|(record, _)| record)
+}
+
+fn hash(key: &str) -> [u8; 32] {
+    Sha256::digest(key.as_bytes()).into()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn format_time(secs: u64) -> String {
+    chrono::DateTime::from_timestamp(secs as i64, 0)
+        .map(|time| {
+            time.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M")
+                .to_string()
+        })
+        .unwrap_or_default()
+}
+
+fn display_model(model: &str) -> &str {
+    match model {
+        "" => "-",
+        model => model,
     }
 }
 
+fn encode_embedding(embedding: &[f32]) -> Vec<u8> {
+    embedding
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+fn decode_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|value| f32::from_le_bytes(value.try_into().unwrap()))
+        .collect()
+}
+
 mod tests {
     #[test]
     fn test_cache() {
-        let mut cache = super::Cache::new();
-        cache.set("key".to_string(), "value".to_string());
-        assert_eq!(cache.get("key").unwrap(), "value");
-        assert_eq!(cache.get("key").unwrap(), "value");
-        assert_eq!(cache.get("key").unwrap(), "value");
-        assert_eq!(cache.get("key"), None);
+        use super::{Filter, Namespace};
+
+        let dir = std::env::temp_dir().join("rustsn_test_cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.bin").to_string_lossy().to_string();
+        let config = crate::config::Config::default().cache;
+        let mut cache = super::Cache::new(&path, &config);
+        cache.set(Namespace::Llm, "gemma2", "key", "value");
+        cache.set(Namespace::Llm, "qwen2.5-coder", "other", "value");
+        cache.set(Namespace::Build, "", "key", "{\"exit_code\":0}");
+        cache.set_embedding("bge-large", "key", &[0.5, -1.0]);
+        assert_eq!(cache.get(Namespace::Llm, "key").unwrap(), "value");
+        assert_eq!(cache.get_embedding("key").unwrap(), vec![0.5, -1.0]);
+        assert_eq!(cache.get(Namespace::Llm, "missing"), None);
+        assert!(cache
+            .stats()
+            .unwrap()
+            .contains("gemma2 (1), qwen2.5-coder (1)"));
+
+        let gemma = Filter {
+            model: Some("gemma2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(cache.entries(&gemma).unwrap().len(), 1);
+        let export = dir.join("export.bin").to_string_lossy().to_string();
+        let embeddings = Filter {
+            namespace: Some(Namespace::Embedding),
+            ..Default::default()
+        };
+        assert_eq!(cache.export(&export, &embeddings).unwrap(), 1);
+        assert_eq!(cache.remove(&Filter::default(), true).unwrap(), 0);
+        assert_eq!(cache.remove(&gemma, false).unwrap(), 1);
+        assert_eq!(cache.get(Namespace::Llm, "key"), None);
+        assert_eq!(cache.remove(&Filter::default(), false).unwrap(), 3);
+        assert_eq!(cache.get_embedding("key"), None);
+        assert_eq!(cache.import(&export).unwrap(), 1);
+        assert_eq!(cache.import(&export).unwrap(), 0);
+        cache.set_mode(super::Mode::Refresh);
+        assert_eq!(cache.get_embedding("key"), None);
+        cache.set_mode(super::Mode::Off);
+        cache.set(Namespace::Llm, "gemma2", "key", "value");
+        cache.set_mode(super::Mode::ReadWrite);
+        assert_eq!(cache.get(Namespace::Llm, "key"), None);
+        drop(cache);
+
+        let cache = super::Cache::new(&path, &config);
+        assert_eq!(cache.get_embedding("key").unwrap(), vec![0.5, -1.0]);
+        assert_eq!(cache.entries(&Filter::default()).unwrap().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_convert_legacy_cache() {
+        use super::Namespace;
+
+        let dir = std::env::temp_dir().join("rustsn_test_legacy_cache");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cache.bin").to_string_lossy().to_string();
+        let legacy = std::collections::HashMap::from([
+            ("prompt".to_string(), "response".to_string()),
+            ("\"text\"".to_string(), "[0.5,-1.0]".to_string()),
+            ("cargo build".to_string(), "{\"exit_code\":1}".to_string()),
+        ]);
+        let encoded = bincode::encode_to_vec(&legacy, bincode::config::standard()).unwrap();
+        std::fs::write(&path, encoded).unwrap();
+
+        let config = crate::config::Config::default().cache;
+        let cache = super::Cache::new(&path, &config);
+        assert_eq!(cache.get(Namespace::Llm, "prompt").unwrap(), "response");
+        assert_eq!(cache.get_embedding("\"text\"").unwrap(), vec![0.5, -1.0]);
+        assert!(cache.get(Namespace::Build, "cargo build").is_some());
+        assert!(dir.join("cache.bin.bak").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
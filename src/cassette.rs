@@ -0,0 +1,169 @@
+use serde::{Deserialize, Serialize};
+
+/// Template name of the requests sent with a ready prompt.
+pub const PROMPT_TEMPLATE: &str = "prompt";
+/// Template name of the recorded chat requests.
+pub const CHAT_TEMPLATE: &str = "chat";
+/// Template name of the recorded embeddings.
+pub const EMBEDDING_TEMPLATE: &str = "embedding";
+
+/// Recorded request and its response, a completion or an embedding.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Interaction {
+    /// Name of the prompt template, "prompt", "chat" or "embedding".
+    pub template: String,
+    pub prompt: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub response: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+/// LLM traffic recorded into a JSON file with `--record` and served from it
+/// with `--replay`, so runs can be repeated without an LLM server.
+pub struct Cassette {
+    path: String,
+    replay: bool,
+    interactions: Vec<Interaction>,
+    /// Replayed interactions.
+    used: Vec<bool>,
+}
+
+impl Cassette {
+    /// Starts a new recording, the file is written after every interaction.
+    pub fn record(path: &str) -> Cassette {
+        Cassette {
+            path: path.to_string(),
+            replay: false,
+            interactions: Vec::new(),
+            used: Vec::new(),
+        }
+    }
+
+    pub fn replay(path: &str) -> Result<Cassette, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Cant read the cassette \"{}\": {}", path, err))?;
+        let file: CassetteFile = serde_json::from_str(&content)
+            .map_err(|err| format!("Cant parse the cassette \"{}\": {}", path, err))?;
+        Ok(Cassette {
+            path: path.to_string(),
+            replay: true,
+            used: vec![false; file.interactions.len()],
+            interactions: file.interactions,
+        })
+    }
+
+    pub fn is_replay(&self) -> bool {
+        self.replay
+    }
+
+    /// Recorded response to `prompt`. Rewrite prompts contain build outputs
+    /// which differ between runs, so without a recording of the exact prompt
+    /// the next response recorded for the template is used.
+    pub fn completion(&mut self, template: &str, prompt: &str) -> Result<String, String> {
+        self.take(template, Some(prompt))
+            .or_else(|| self.take(template, None))
+            .map(|interaction| interaction.response)
+            .ok_or_else(|| {
+                format!(
+                    "No response for the \"{}\" prompt in the cassette \"{}\":\n{}",
+                    template, self.path, prompt
+                )
+            })
+    }
+
+    pub fn embedding(&mut self, text: &str) -> Result<Vec<f32>, String> {
+        self.take(EMBEDDING_TEMPLATE, Some(text))
+            .map(|interaction| interaction.embedding)
+            .ok_or_else(|| {
+                format!(
+                    "No embedding in the cassette \"{}\" for:\n{}",
+                    self.path, text
+                )
+            })
+    }
+
+    pub fn add(&mut self, interaction: Interaction) {
+        self.interactions.push(interaction);
+        let file = CassetteFile {
+            interactions: self.interactions.clone(),
+        };
+        let content = serde_json::to_string_pretty(&file).unwrap();
+        // written next to the cassette and renamed, so an interrupted run keeps the last complete file
+        let temp = format!("{}.tmp", self.path);
+        if let Err(err) =
+            std::fs::write(&temp, content).and_then(|()| std::fs::rename(&temp, &self.path))
+        {
+            println!(
+                "Warning: Cant write the cassette \"{}\": {}",
+                self.path, err
+            );
+        }
+    }
+
+    /// First interaction of `template` which was not replayed yet, with
+    /// `prompt` when given. An interaction recorded once is replayed again
+    /// when it is the last one of its prompt.
+    fn take(&mut self, template: &str, prompt: Option<&str>) -> Option<Interaction> {
+        let matches = |interaction: &Interaction| {
+            interaction.template == template
+                && prompt.is_none_or(|prompt| interaction.prompt == prompt)
+        };
+        let index = (0..self.interactions.len())
+            .find(|&i| !self.used[i] && matches(&self.interactions[i]))
+            .or_else(|| {
+                prompt?;
+                (0..self.interactions.len())
+                    .rev()
+                    .find(|&i| matches(&self.interactions[i]))
+            })?;
+        self.used[index] = true;
+        Some(self.interactions[index].clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_record_and_replay() {
+        use super::{Cassette, Interaction};
+
+        let path = std::env::temp_dir().join("rustsn_test_cassette.json");
+        let path = path.to_string_lossy().to_string();
+        let interaction = |template: &str, prompt: &str, response: &str| Interaction {
+            template: template.to_string(),
+            prompt: prompt.to_string(),
+            response: response.to_string(),
+            embedding: Vec::new(),
+        };
+        let mut cassette = Cassette::record(&path);
+        cassette.add(interaction("generate", "sum", "fn sum"));
+        cassette.add(interaction("rewrite", "sum error 1", "fn sum 2"));
+        cassette.add(interaction("rewrite", "sum error 2", "fn sum 3"));
+        cassette.add(Interaction {
+            embedding: vec![0.5, 1.0],
+            ..interaction("embedding", "fn sum", "")
+        });
+
+        let mut cassette = Cassette::replay(&path).unwrap();
+        assert!(cassette.is_replay());
+        assert_eq!(cassette.completion("generate", "sum").unwrap(), "fn sum");
+        assert_eq!(cassette.completion("generate", "sum").unwrap(), "fn sum");
+        assert_eq!(
+            cassette.completion("rewrite", "sum error 2").unwrap(),
+            "fn sum 3"
+        );
+        assert_eq!(
+            cassette.completion("rewrite", "other error").unwrap(),
+            "fn sum 2"
+        );
+        assert_eq!(cassette.embedding("fn sum").unwrap(), vec![0.5, 1.0]);
+        std::fs::remove_file(&path).unwrap();
+        assert!(Cassette::replay(&path).is_err());
+    }
+}
@@ -0,0 +1,420 @@
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use crate::cache::Cache;
+use crate::config::AskConfig;
+use crate::embedding_index::EmbeddingIndex;
+use crate::language_driver::AskDescriptor;
+use crate::llm_api::{ChatMessage, LLMApi};
+use crate::llm_prompt::Prompt;
+use crate::retrieval::{self, RetrievedChunk};
+use crate::VERBOSE;
+
+const CITATION_PROMPT: &str =
+    "Cite the code your answer is based on as `path:line` using the file paths and line numbers above.";
+
+/// Retrieved chunks and the previous questions and answers are sent for this
+/// many questions, so follow-ups can refer to them and long sessions still fit
+/// the context of the model.
+const CONTEXT_TURNS: usize = 3;
+
+const HELP: &str = "Commands:
+  /pin <path>    always pass the whole file to the LLM
+  /unpin <path>  stop passing the file
+  /sources       show the code the last answer is based on
+  /reset         forget the conversation and the retrieved code
+  /save <file>   save the transcript as Markdown
+  /help          show this help
+  /exit          end the session (or Ctrl-D)
+End a line with \\ to continue the question on the next line.";
+
+#[derive(Debug, PartialEq)]
+enum ChatCommand {
+    Question(String),
+    Pin(String),
+    Unpin(String),
+    Sources,
+    Reset,
+    Save(String),
+    Help,
+    Exit,
+    Unknown(String),
+}
+
+struct Turn {
+    question: String,
+    answer: String,
+    /// `path:start-end` of the chunks and pinned files the answer was given with.
+    sources: Vec<String>,
+}
+
+/// Multi-turn conversation about the sources of one project. The last
+/// questions and answers and the code retrieved for them are sent with every
+/// question, so follow-ups like "and where is that called
+/// from?" work.
+struct ChatSession<'a> {
+    root: &'a str,
+    index: EmbeddingIndex,
+    ask_descriptor: &'a AskDescriptor,
+    ask_config: &'a AskConfig,
+    turns: Vec<Turn>,
+    /// Retrieved chunks, newest first.
+    context: Vec<RetrievedChunk>,
+    /// Paths relative to `root` of the files passed whole with every question.
+    pinned: Vec<String>,
+}
+
+/// Runs the `ask` REPL until `/exit` or the end of the input.
+pub fn run_chat(
+    root: &str,
+    index: EmbeddingIndex,
+    llm: &LLMApi,
+    cache: &mut Cache,
+    prompt: &Prompt,
+    ask_descriptor: &AskDescriptor,
+    ask_config: &AskConfig,
+) {
+    let mut session = ChatSession {
+        root,
+        index,
+        ask_descriptor,
+        ask_config,
+        turns: Vec::new(),
+        context: Vec::new(),
+        pinned: Vec::new(),
+    };
+    println!("Ask questions about your project sources, /help shows the commands.");
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    while let Some(line) = read_input(&mut input) {
+        match parse_command(&line) {
+            ChatCommand::Question(question) => {
+                match session.answer(&question, llm, cache, prompt) {
+                    Ok(answer) => {
+                        println!("++++++++ Answer ++++++++++++");
+                        println!("{}", answer);
+                    }
+                    // the question is not added to the conversation, it can be asked again
+                    Err(err) => println!("{}", err),
+                }
+            }
+            ChatCommand::Pin(path) => session.pin(&path),
+            ChatCommand::Unpin(path) => session.unpin(&path),
+            ChatCommand::Sources => match session.turns.last() {
+                Some(turn) if !turn.sources.is_empty() => {
+                    for source in &turn.sources {
+                        println!("File: {}", source);
+                    }
+                }
+                _ => println!("No sources yet"),
+            },
+            ChatCommand::Reset => {
+                session.turns.clear();
+                session.context.clear();
+                println!("Conversation reset, pinned files are kept");
+            }
+            ChatCommand::Save(file) => match std::fs::write(&file, session.transcript()) {
+                Ok(()) => println!("Transcript saved to {}", file),
+                Err(err) => println!("Cant save the transcript to \"{}\": {}", file, err),
+            },
+            ChatCommand::Help => println!("{}", HELP),
+            ChatCommand::Exit => break,
+            ChatCommand::Unknown(command) => {
+                println!("Unknown command \"{}\", /help shows the commands", command)
+            }
+        }
+    }
+}
+
+impl ChatSession<'_> {
+    fn answer(
+        &mut self,
+        question: &str,
+        llm: &LLMApi,
+        cache: &mut Cache,
+        prompt: &Prompt,
+    ) -> Result<String, String> {
+        // the previous question gives the retrieval a subject for follow-ups
+        let query = match self.turns.last() {
+            Some(turn) => format!("{}\r\n{}", turn.question, question),
+            None => question.to_string(),
+        };
+        let retrieved = self.retrieve(&query, llm, cache, prompt)?;
+        let context = self.merged_context(retrieved);
+        let messages = self.messages(&context, question);
+        if *VERBOSE.lock().unwrap() {
+            println!("Request: {:#?}", messages);
+        }
+        let answer = llm.chat(&messages, cache)?;
+        // the retrieved code is kept only once the question is answered
+        self.context = context;
+
+        let mut sources = self.pinned.clone();
+        sources.extend(self.context.iter().map(source));
+        self.turns.push(Turn {
+            question: question.to_string(),
+            answer: answer.clone(),
+            sources,
+        });
+        Ok(answer)
+    }
+
+    fn retrieve(
+        &self,
+        query: &str,
+        llm: &LLMApi,
+        cache: &mut Cache,
+        prompt: &Prompt,
+    ) -> Result<Vec<RetrievedChunk>, String> {
+        let query_emb = llm.emb(query, cache)?;
+        let top_k = self.ask_config.top_k;
+        let mut results = if self.ask_config.rerank {
+            let candidates =
+                retrieval::hybrid_search(&self.index, self.root, query, &query_emb, top_k * 3);
+            retrieval::rerank(candidates, query, top_k, llm, cache, prompt)
+        } else {
+            retrieval::hybrid_search(&self.index, self.root, query, &query_emb, top_k)
+        };
+        results.truncate(top_k);
+        println!("Find closest chunks:");
+        for result in &results {
+            println!("File: {}", source(result));
+        }
+        Ok(results)
+    }
+
+    /// Puts the new chunks first and drops the oldest ones and duplicates.
+    /// The `retrieved` chunks followed by the ones of the previous questions.
+    fn merged_context(&self, retrieved: Vec<RetrievedChunk>) -> Vec<RetrievedChunk> {
+        let mut context = retrieved;
+        for chunk in &self.context {
+            let duplicate = context
+                .iter()
+                .any(|known| known.path == chunk.path && known.start_line == chunk.start_line);
+            if !duplicate {
+                context.push(chunk.clone());
+            }
+        }
+        context.truncate(self.ask_config.top_k * CONTEXT_TURNS);
+        context
+    }
+
+    /// Conversation sent for `question`: the code in the system message and
+    /// the last `CONTEXT_TURNS` questions and answers.
+    fn messages(&self, context: &[RetrievedChunk], question: &str) -> Vec<ChatMessage> {
+        let mut messages = vec![ChatMessage::new("system", &self.system_message(context))];
+        let recent = self.turns.len().saturating_sub(CONTEXT_TURNS);
+        for turn in &self.turns[recent..] {
+            messages.push(ChatMessage::new("user", &turn.question));
+            messages.push(ChatMessage::new("assistant", &turn.answer));
+        }
+        messages.push(ChatMessage::new("user", question));
+        messages
+    }
+
+    fn system_message(&self, context: &[RetrievedChunk]) -> String {
+        let mut fragments = Vec::new();
+        for path in &self.pinned {
+            match std::fs::read_to_string(Path::new(self.root).join(path)) {
+                Ok(content) => {
+                    fragments.push(format!("# {}\r\n{}", path, numbered_lines(&content, 1)))
+                }
+                Err(err) => println!("Warning: Cant read pinned file \"{}\": {}", path, err),
+            }
+        }
+        for chunk in context {
+            fragments.push(format!(
+                "# {}\r\n{}",
+                source(chunk),
+                numbered_lines(&chunk.text, chunk.start_line)
+            ));
+        }
+        format!(
+            "{}\r\n{}\r\n{}",
+            fragments.join("\r\n"),
+            self.ask_descriptor.answer_prompt,
+            CITATION_PROMPT
+        )
+    }
+
+    fn pin(&mut self, path: &str) {
+        let full_path = Path::new(self.root).join(path);
+        if !full_path.is_file() {
+            println!("File not found: {}", full_path.display());
+            return;
+        }
+        let path = path.replace('\\', "/");
+        if !self.pinned.contains(&path) {
+            self.pinned.push(path.clone());
+        }
+        println!("Pinned: {}", path);
+    }
+
+    fn unpin(&mut self, path: &str) {
+        let path = path.replace('\\', "/");
+        let count = self.pinned.len();
+        self.pinned.retain(|pinned| *pinned != path);
+        if self.pinned.len() == count {
+            println!("Not pinned: {}", path);
+        } else {
+            println!("Unpinned: {}", path);
+        }
+    }
+
+    fn transcript(&self) -> String {
+        let mut transcript = format!("# rustsn ask: {}\n", self.root);
+        for turn in &self.turns {
+            transcript.push_str(&format!(
+                "\n## Question\n\n{}\n\n## Answer\n\n{}\n",
+                turn.question.trim(),
+                turn.answer.trim()
+            ));
+            if !turn.sources.is_empty() {
+                transcript.push_str("\nSources:\n\n");
+                for source in &turn.sources {
+                    transcript.push_str(&format!("- `{}`\n", source));
+                }
+            }
+        }
+        transcript
+    }
+}
+
+fn source(chunk: &RetrievedChunk) -> String {
+    format!("{}:{}-{}", chunk.path, chunk.start_line, chunk.end_line)
+}
+
+/// Lines of `chunk` prefixed with their numbers in the file, so the LLM can cite them.
+fn numbered_lines(chunk: &str, first_line: usize) -> String {
+    chunk
+        .lines()
+        .enumerate()
+        .map(|(i, line)| format!("{:>5} | {}", first_line + i, line))
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Next non-empty input, lines ending with `\` are joined with the following
+/// one. `None` at the end of the input.
+fn read_input(input: &mut impl BufRead) -> Option<String> {
+    let mut lines = Vec::new();
+    loop {
+        if lines.is_empty() {
+            print!("> ");
+        } else {
+            print!(". ");
+        }
+        std::io::stdout().flush().unwrap();
+        let mut line = String::new();
+        if input.read_line(&mut line).unwrap_or(0) == 0 {
+            return None;
+        }
+        let line = line.trim_end_matches(['\r', '\n']);
+        match line.strip_suffix('\\') {
+            Some(start) => lines.push(start.to_string()),
+            None => {
+                lines.push(line.to_string());
+                let text = lines.join("\n").trim().to_string();
+                if !text.is_empty() {
+                    return Some(text);
+                }
+                lines.clear();
+            }
+        }
+    }
+}
+
+fn parse_command(line: &str) -> ChatCommand {
+    if !line.starts_with('/') {
+        return ChatCommand::Question(line.to_string());
+    }
+    let (command, argument) = match line.split_once(char::is_whitespace) {
+        Some((command, argument)) => (command, argument.trim().to_string()),
+        None => (line, String::new()),
+    };
+    match (command, argument.is_empty()) {
+        ("/pin", false) => ChatCommand::Pin(argument),
+        ("/unpin", false) => ChatCommand::Unpin(argument),
+        ("/save", false) => ChatCommand::Save(argument),
+        ("/sources", _) => ChatCommand::Sources,
+        ("/reset", _) => ChatCommand::Reset,
+        ("/help", _) => ChatCommand::Help,
+        ("/exit", _) | ("/quit", _) => ChatCommand::Exit,
+        _ => ChatCommand::Unknown(line.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_command_and_read_input() {
+        use super::ChatCommand;
+        assert_eq!(
+            super::parse_command("where is parse_file called?"),
+            ChatCommand::Question("where is parse_file called?".to_string())
+        );
+        assert_eq!(
+            super::parse_command("/pin src/main.rs"),
+            ChatCommand::Pin("src/main.rs".to_string())
+        );
+        assert_eq!(
+            super::parse_command("/save"),
+            ChatCommand::Unknown("/save".to_string())
+        );
+        assert_eq!(super::parse_command("/exit"), ChatCommand::Exit);
+
+        let mut input = "\nfirst \\\nsecond\r\n/reset\n".as_bytes();
+        assert_eq!(
+            super::read_input(&mut input),
+            Some("first \nsecond".to_string())
+        );
+        assert_eq!(super::read_input(&mut input), Some("/reset".to_string()));
+        assert_eq!(super::read_input(&mut input), None);
+    }
+
+    #[test]
+    fn test_messages_keep_recent_turns() {
+        use super::{RetrievedChunk, Turn, CONTEXT_TURNS};
+
+        let config = crate::config::Config::default();
+        let ask_descriptor = crate::language_driver::AskDescriptor {
+            extensions: vec!["rs".to_string()],
+            exclude_dirs: Vec::new(),
+            explain_prompt: String::new(),
+            answer_prompt: "Answer the question.".to_string(),
+        };
+        let chunk = |path: &str| RetrievedChunk {
+            path: path.to_string(),
+            start_line: 1,
+            end_line: 1,
+            text: "fn a() {}".to_string(),
+            score: 1.0,
+        };
+        let mut session = super::ChatSession {
+            root: ".",
+            index: Default::default(),
+            ask_descriptor: &ask_descriptor,
+            ask_config: &config.ask,
+            turns: Vec::new(),
+            context: vec![chunk("src/old.rs")],
+            pinned: Vec::new(),
+        };
+        for number in 1..=CONTEXT_TURNS + 2 {
+            session.turns.push(Turn {
+                question: format!("question {}", number),
+                answer: format!("answer {}", number),
+                sources: Vec::new(),
+            });
+        }
+
+        let context = session.merged_context(vec![chunk("src/new.rs")]);
+        let messages = session.messages(&context, "next question");
+        assert_eq!(messages.len(), 2 + 2 * CONTEXT_TURNS);
+        assert_eq!(messages[1].content, "question 3");
+        assert_eq!(messages.last().unwrap().content, "next question");
+        let system = &messages[0].content;
+        assert!(system.find("src/new.rs").unwrap() < system.find("src/old.rs").unwrap());
+        // merging does not change the session until the question is answered
+        assert_eq!(session.context.len(), 1);
+    }
+}
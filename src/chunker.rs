@@ -0,0 +1,226 @@
+use regex::Regex;
+
+/// Part of a source file: byte offsets and 1-based inclusive line range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub start: usize,
+    pub end: usize,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// Splits source files on function, impl, class and method boundaries given
+/// by the `chunk_boundaries` patterns of the language descriptor. Small
+/// neighbouring definitions are merged up to `max_lines`, longer ones are
+/// split into windows, and every chunk repeats `overlap_lines` lines of the
+/// previous one.
+pub struct Chunker {
+    boundaries: Vec<Regex>,
+    /// Boundaries of the files with the given extensions, for polyglot projects.
+    languages: Vec<(Vec<String>, Vec<Regex>)>,
+    max_lines: usize,
+    overlap_lines: usize,
+}
+
+impl Chunker {
+    pub fn new(patterns: &[String], max_lines: usize, overlap_lines: usize) -> Chunker {
+        Chunker {
+            boundaries: compile(patterns),
+            languages: Vec::new(),
+            max_lines: max_lines.max(1),
+            overlap_lines: overlap_lines.min(max_lines.saturating_sub(1)),
+        }
+    }
+
+    /// Splits files with one of the `extensions` on `patterns` instead of the default boundaries.
+    pub fn with_language(mut self, extensions: &[String], patterns: &[String]) -> Chunker {
+        self.languages
+            .push((extensions.to_vec(), compile(patterns)));
+        self
+    }
+
+    /// Settings which change the chunks; an index built with other settings is stale.
+    pub fn signature(&self) -> String {
+        let mut signature = format!(
+            "{}:{}:{}",
+            self.max_lines,
+            self.overlap_lines,
+            join_patterns(&self.boundaries)
+        );
+        for (extensions, boundaries) in &self.languages {
+            signature.push_str(&format!(
+                " {}={}",
+                extensions.join(","),
+                join_patterns(boundaries)
+            ));
+        }
+        signature
+    }
+
+    /// Chunks of the file at `path`, split on the boundaries of its language
+    /// or on the default ones.
+    pub fn chunk_file(&self, path: &str, content: &str) -> Vec<Chunk> {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let boundaries = self
+            .languages
+            .iter()
+            .find(|(extensions, _)| extensions.contains(&extension))
+            .map(|(_, boundaries)| boundaries)
+            .unwrap_or(&self.boundaries);
+        self.split(boundaries, content)
+    }
+
+    fn split(&self, boundaries: &[Regex], content: &str) -> Vec<Chunk> {
+        // byte offset of the start of every line and of the end of the content
+        let mut line_offsets = vec![0];
+        line_offsets.extend(
+            content
+                .match_indices('\n')
+                .map(|(i, _)| i + 1)
+                .filter(|i| *i < content.len()),
+        );
+        let line_count = line_offsets.len();
+        line_offsets.push(content.len());
+        if content.is_empty() {
+            return Vec::new();
+        }
+
+        let lines = (0..line_count)
+            .map(|i| &content[line_offsets[i]..line_offsets[i + 1]])
+            .collect::<Vec<_>>();
+        let mut starts = vec![0];
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 && boundaries.iter().any(|regex| regex.is_match(line)) {
+                // doc comments and attributes belong to the definition below them
+                let mut start = i;
+                while start > 0 && is_preamble(lines[start - 1]) {
+                    start -= 1;
+                }
+                if start > *starts.last().unwrap() {
+                    starts.push(start);
+                }
+            }
+        }
+        starts.push(line_count);
+
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        let mut current: Option<(usize, usize)> = None;
+        for segment in starts.windows(2) {
+            let (start, end) = (segment[0], segment[1]);
+            if end - start > self.max_lines {
+                ranges.extend(current.take());
+                let mut window_start = start;
+                while window_start < end {
+                    let window_end = (window_start + self.max_lines).min(end);
+                    ranges.push((window_start, window_end));
+                    window_start = window_end;
+                }
+                continue;
+            }
+            current = match current {
+                Some((current_start, _)) if end - current_start <= self.max_lines => {
+                    Some((current_start, end))
+                }
+                Some(range) => {
+                    ranges.push(range);
+                    Some((start, end))
+                }
+                None => Some((start, end)),
+            };
+        }
+        ranges.extend(current);
+
+        ranges
+            .into_iter()
+            .enumerate()
+            .map(|(i, (start, end))| {
+                let start = if i == 0 {
+                    start
+                } else {
+                    start.saturating_sub(self.overlap_lines)
+                };
+                Chunk {
+                    start: line_offsets[start],
+                    end: line_offsets[end],
+                    start_line: start + 1,
+                    end_line: end,
+                }
+            })
+            .collect()
+    }
+}
+
+fn compile(patterns: &[String]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match Regex::new(pattern) {
+            Ok(regex) => Some(regex),
+            Err(err) => {
+                println!("Warning: Invalid chunk boundary \"{}\": {}", pattern, err);
+                None
+            }
+        })
+        .collect()
+}
+
+fn join_patterns(boundaries: &[Regex]) -> String {
+    boundaries
+        .iter()
+        .map(|regex| regex.as_str())
+        .collect::<Vec<_>>()
+        .join("|")
+}
+
+fn is_preamble(line: &str) -> bool {
+    let line = line.trim_start();
+    ["///", "//", "/*", "*", "#[", "@"]
+        .iter()
+        .any(|prefix| line.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_chunk_on_boundaries() {
+        let content = "use std::fmt;\n\n/// Adds\nfn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n\nfn sub(a: i32, b: i32) -> i32 {\n    a - b\n}\n";
+        let chunker = super::Chunker::new(&[r"^\s*(pub\s+)?fn\s".to_string()], 5, 0);
+        let chunks = chunker.chunk_file("a.rs", content);
+        let lines = chunks
+            .iter()
+            .map(|chunk| (chunk.start_line, chunk.end_line))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![(1, 2), (3, 7), (8, 10)]);
+        assert!(content[chunks[1].start..chunks[1].end].starts_with("/// Adds\nfn add"));
+
+        let polyglot = super::Chunker::new(&[], 3, 0)
+            .with_language(&["py".to_string()], &[r"^def\s".to_string()]);
+        let python = "x = 1\ny = 2\ndef a():\n    pass\n";
+        let start_lines = |path: &str| {
+            polyglot
+                .chunk_file(path, python)
+                .iter()
+                .map(|chunk| chunk.start_line)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(start_lines("src/a.py"), vec![1, 3]);
+        assert_eq!(start_lines("src/a.rs"), vec![1, 4]);
+    }
+
+    #[test]
+    fn test_chunk_merge_split_and_overlap() {
+        let content = (1..=10)
+            .map(|i| format!("line {}\n", i))
+            .collect::<String>();
+        let chunker = super::Chunker::new(&[], 4, 1);
+        let lines = chunker
+            .chunk_file("a.txt", &content)
+            .iter()
+            .map(|chunk| (chunk.start_line, chunk.end_line))
+            .collect::<Vec<_>>();
+        assert_eq!(lines, vec![(1, 4), (4, 8), (8, 10)]);
+        assert!(chunker.chunk_file("a.txt", "").is_empty());
+    }
+}
@@ -0,0 +1,134 @@
+/// Tokens which separate sequential commands on one line.
+const SEPARATORS: [&str; 2] = ["&&", ";"];
+
+/// Shell operators which need a shell, commands are launched without one.
+const SHELL_OPERATORS: [&str; 7] = ["|", "||", "&", ">", ">>", "<", "2>&1"];
+
+/// Options which only change the output or the network use of a tool and may
+/// stand before the subcommand of an allowed command. Others like
+/// `--config=build.rustc-wrapper=...` can run arbitrary programs.
+const SKIPPED_OPTIONS: [&str; 11] = [
+    "-q",
+    "--quiet",
+    "-v",
+    "--verbose",
+    "-B",
+    "--batch-mode",
+    "-e",
+    "--errors",
+    "--offline",
+    "--no-daemon",
+    "--info",
+];
+
+/// Splits a build or test command block into commands and their shell words.
+/// Every line is a command, lines ending with `\` continue on the next one,
+/// `&&` and `;` separate commands on one line, `#` starts a comment and a
+/// leading `$ ` prompt is dropped.
+pub fn parse(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let text = text.replace("\r\n", "\n").replace("\\\n", " ");
+    let mut commands = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        let line = line.strip_prefix("$ ").unwrap_or(line);
+        let words = shell_words::split(line)
+            .map_err(|err| format!("Cant parse the command \"{}\": {}", line, err))?;
+        let mut command = Vec::new();
+        for word in words {
+            if SEPARATORS.contains(&word.as_str()) {
+                commands.push(std::mem::take(&mut command));
+            } else if let Some(word) = word.strip_suffix(';') {
+                command.push(word.to_string());
+                commands.push(std::mem::take(&mut command));
+            } else if SHELL_OPERATORS.contains(&word.as_str()) {
+                return Err(format!(
+                    "The command \"{}\" uses the shell operator \"{}\", pipes and redirections are not supported",
+                    line, word
+                ));
+            } else {
+                command.push(word);
+            }
+        }
+        commands.push(command);
+    }
+    commands.retain(|command| !command.is_empty());
+    Ok(commands)
+}
+
+/// True when `command` starts with one of the `allowed` commands, e.g.
+/// "cargo test" allows "cargo test --release". `SKIPPED_OPTIONS` before the
+/// expected word are skipped, so "mvn -q test" is allowed by "mvn test".
+/// Everything is allowed when `allowed` is empty.
+pub fn is_allowed(command: &[String], allowed: &[String]) -> bool {
+    allowed.is_empty() || allowed.iter().any(|entry| starts_with(command, entry))
+}
+
+fn starts_with(command: &[String], entry: &str) -> bool {
+    let mut words = command.iter();
+    for (i, expected) in entry.split_whitespace().enumerate() {
+        loop {
+            match words.next() {
+                Some(word) if word == expected => break,
+                // harmless options before the expected word are skipped, the program is not
+                Some(word) if i > 0 && SKIPPED_OPTIONS.contains(&word.as_str()) => continue,
+                _ => return false,
+            }
+        }
+    }
+    true
+}
+
+/// Message for the LLM about a command which is not allowed.
+pub fn rejection(command: &[String], allowed: &[String]) -> String {
+    format!(
+        "The command \"{}\" is not allowed. Only these commands can be used, with any options and arguments: {}",
+        shell_words::join(command),
+        allowed.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_and_allow_commands() {
+        let text = "$ npm install\ncd /tmp && rm -rf 'my dir'; echo done\n# comment\n\nmvn -q test \\\n  -Dtest=\"Solution Test\"\n";
+        let commands = super::parse(text).unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                vec!["npm", "install"],
+                vec!["cd", "/tmp"],
+                vec!["rm", "-rf", "my dir"],
+                vec!["echo", "done"],
+                vec!["mvn", "-q", "test", "-Dtest=Solution Test"],
+            ]
+        );
+        assert!(super::parse("cargo test | tee out.txt").is_err());
+        assert!(super::parse("echo \"unclosed").is_err());
+
+        let allowed = vec![
+            "npm install".to_string(),
+            "mvn test".to_string(),
+            "python -m unittest".to_string(),
+        ];
+        let allowed_command = |command: &[&str]| {
+            super::is_allowed(
+                &command.iter().map(|w| w.to_string()).collect::<Vec<_>>(),
+                &allowed,
+            )
+        };
+        assert!(allowed_command(&["npm", "install", "--save", "lodash"]));
+        assert!(allowed_command(&["mvn", "-q", "test"]));
+        assert!(allowed_command(&["python", "-m", "unittest", "test.py"]));
+        assert!(!allowed_command(&["python", "-c", "import os"]));
+        assert!(!allowed_command(&["npm", "exec", "install"]));
+        assert!(!allowed_command(&["rm", "-rf", "/"]));
+        assert!(!allowed_command(&["-x", "npm", "install"]));
+        assert!(!allowed_command(&["mvn", "-Dexec.executable=sh", "test"]));
+        assert!(!super::is_allowed(
+            &["cargo", "--config=build.rustc-wrapper=\"sh\"", "test"].map(String::from),
+            &["cargo test".to_string()]
+        ));
+        assert!(super::is_allowed(&["rm".to_string()], &[]));
+    }
+}
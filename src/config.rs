@@ -0,0 +1,322 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Name of the project configuration file, looked up in the current folder.
+pub const PROJECT_CONFIG_FILE: &str = "rustsn.toml";
+
+const DEFAULT_CONFIG: &str = r#"
+lang = "rust"
+max_attempts = 5
+sandbox_dir = "sandbox"
+cache_path = "cache.bin"
+runs_dir = ".rustsn/runs"
+prompt_dir = "prompt"
+temperature = 0.7
+max_tokens = 1000
+
+[ask]
+max_chunk_lines = 60
+chunk_overlap_lines = 5
+top_k = 5
+rerank = false
+languages = []
+include = []
+exclude = []
+max_file_kb = 1024
+
+[execution]
+timeout_secs = 600
+cpu_secs = 600
+memory_mb = 8192
+processes = 16384
+file_size_mb = 1024
+jail = "none"
+network = false
+writable = []
+
+[cache]
+llm_ttl_hours = 0
+embedding_ttl_hours = 0
+build_ttl_hours = 168
+
+[ollama]
+url = "http://127.0.0.1:11434"
+model = "qwen2.5-coder:7b"
+emb = "bge-large"
+timeout_secs = 600
+
+[openai]
+url = "https://api.openai.com/v1"
+model = "gpt-4o-2024-08-06"
+emb = "text-embedding-ada-002"
+token_file = "token.txt"
+timeout_secs = 300
+"#;
+
+/// Effective settings merged from the defaults, the user-global config, the
+/// project `rustsn.toml`, environment variables and CLI flags (in this order).
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub lang: String,
+    /// "ollama" or "openai". When not set "openai" is used if the token file exists.
+    pub backend: Option<String>,
+    pub max_attempts: i32,
+    pub sandbox_dir: String,
+    pub cache_path: String,
+    /// Folder of the transcripts of the `generate` runs.
+    pub runs_dir: String,
+    pub prompt_dir: String,
+    pub temperature: f64,
+    pub max_tokens: i32,
+    pub ask: AskConfig,
+    pub execution: ExecutionConfig,
+    pub cache: CacheConfig,
+    pub ollama: OllamaConfig,
+    pub openai: OpenAIConfig,
+    /// Commands overriding the ones of the language descriptor, by language name.
+    #[serde(default)]
+    pub languages: BTreeMap<String, LanguageCommands>,
+}
+
+/// Chunking and retrieval settings of the `ask` command.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AskConfig {
+    pub max_chunk_lines: usize,
+    pub chunk_overlap_lines: usize,
+    /// Number of chunks passed to the LLM with the question.
+    pub top_k: usize,
+    /// Let the LLM pick the relevant chunks among the best retrieved ones.
+    pub rerank: bool,
+    /// Other languages indexed together with `lang` in polyglot projects.
+    pub languages: Vec<String>,
+    /// Globs of the indexed files relative to the project, all source files when empty.
+    pub include: Vec<String>,
+    /// Globs of the files and folders relative to the project which are not indexed.
+    pub exclude: Vec<String>,
+    /// Larger files are not indexed.
+    pub max_file_kb: u64,
+}
+
+/// Limits of the build and test commands run on generated code, 0 is no limit.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExecutionConfig {
+    /// Wall-clock time of a command.
+    pub timeout_secs: u64,
+    /// CPU time of every process of a command.
+    pub cpu_secs: u64,
+    /// Address space of every process of a command.
+    pub memory_mb: u64,
+    /// Processes and threads of the user.
+    pub processes: u64,
+    /// Size of the files written by a command.
+    pub file_size_mb: u64,
+    /// "none", "namespace" (Linux user namespaces) or "bwrap" (bubblewrap). A
+    /// jailed command can only write into its project folder and `writable`.
+    pub jail: String,
+    /// Network access of jailed commands.
+    pub network: bool,
+    /// Folders jailed commands can write into besides the project, e.g. "~/.cargo".
+    pub writable: Vec<String>,
+}
+
+/// Expiry of the cached results by namespace, 0 keeps them forever.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheConfig {
+    pub llm_ttl_hours: u64,
+    pub embedding_ttl_hours: u64,
+    /// Build results depend on the installed toolchains and dependencies.
+    pub build_ttl_hours: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OllamaConfig {
+    pub url: String,
+    pub model: String,
+    pub emb: String,
+    /// Time limit of a request, reading the streamed response included.
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OpenAIConfig {
+    pub url: String,
+    pub api_key: Option<String>,
+    /// File with the API key, used when `api_key` is not set.
+    pub token_file: String,
+    pub model: String,
+    pub emb: String,
+    /// Time limit of a request, reading the streamed response included.
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LanguageCommands {
+    pub install: Option<String>,
+    pub build: Option<String>,
+    pub test: Option<String>,
+    /// Commands the LLM may run besides the ones of the language descriptor.
+    #[serde(default)]
+    pub allowed: Vec<String>,
+}
+
+impl Default for Config {
+    /// Built-in defaults without any config file applied.
+    fn default() -> Config {
+        toml::from_str(DEFAULT_CONFIG).unwrap()
+    }
+}
+
+impl Config {
+    /// Loads the config files and applies `overrides` built from environment
+    /// variables and CLI flags on top of them.
+    pub fn load(overrides: toml::Table) -> Result<Config, String> {
+        let mut config = toml::from_str::<toml::Table>(DEFAULT_CONFIG).unwrap();
+        for file in Config::files() {
+            if std::path::Path::new(&file).exists() {
+                merge(&mut config, read_table(&file)?);
+            }
+        }
+        merge(&mut config, overrides);
+        let mut config = toml::Value::Table(config)
+            .try_into::<Config>()
+            .map_err(|err| format!("Invalid configuration: {}", err))?;
+        if config.backend.is_none() {
+            let backend = if std::path::Path::new(&config.openai.token_file).exists() {
+                "openai"
+            } else {
+                "ollama"
+            };
+            config.backend = Some(backend.to_string());
+        }
+        Ok(config)
+    }
+
+    /// Config files in the order they are applied: user-global, then project.
+    pub fn files() -> Vec<String> {
+        let mut files = Vec::new();
+        if let Some(dir) = user_config_dir() {
+            files.push(format!("{}/rustsn/config.toml", dir));
+        }
+        files.push(PROJECT_CONFIG_FILE.to_string());
+        files
+    }
+
+    pub fn backend(&self) -> &str {
+        self.backend.as_deref().unwrap_or("ollama")
+    }
+
+    /// API key of the OpenAI-compatible backend: `openai.api_key` or the content of the token file.
+    pub fn openai_api_key(&self) -> Option<String> {
+        self.openai.api_key.clone().or_else(|| {
+            std::fs::read_to_string(&self.openai.token_file)
+                .ok()
+                .map(|token| token.trim().to_string())
+        })
+    }
+
+    pub fn language_commands(&self, lang: &str) -> Option<&LanguageCommands> {
+        self.languages.get(lang)
+    }
+
+    /// Effective configuration in the TOML format with the API key hidden.
+    pub fn show(&self) -> String {
+        let mut table = toml::Table::try_from(self).unwrap();
+        if let Some(toml::Value::Table(openai)) = table.get_mut("openai") {
+            if openai.contains_key("api_key") {
+                openai.insert("api_key".to_string(), toml::Value::from("********"));
+            }
+        }
+        toml::to_string(&table).unwrap()
+    }
+}
+
+/// Sets `value` at the dotted `key` of the overrides table, e.g. "ollama.model".
+pub fn set_override(table: &mut toml::Table, key: &str, value: toml::Value) {
+    match key.split_once('.') {
+        Some((section, rest)) => {
+            let entry = table
+                .entry(section.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+            if let toml::Value::Table(section_table) = entry {
+                set_override(section_table, rest, value);
+            }
+        }
+        None => {
+            table.insert(key.to_string(), value);
+        }
+    }
+}
+
+fn user_config_dir() -> Option<String> {
+    if let Ok(dir) = std::env::var("XDG_CONFIG_HOME") {
+        return Some(dir);
+    }
+    if cfg!(target_os = "windows") {
+        return std::env::var("APPDATA").ok();
+    }
+    std::env::var("HOME")
+        .ok()
+        .map(|home| format!("{}/.config", home))
+}
+
+fn read_table(file: &str) -> Result<toml::Table, String> {
+    let content =
+        std::fs::read_to_string(file).map_err(|err| format!("Can't read \"{}\": {}", file, err))?;
+    toml::from_str(&content).map_err(|err| format!("Can't parse \"{}\": {}", file, err))
+}
+
+/// Merges `layer` into `base`, values of `layer` win. Tables are merged key by key.
+fn merge(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(layer_table)) => {
+                merge(base_table, layer_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_merge_layers() {
+        let mut config = toml::from_str::<toml::Table>(super::DEFAULT_CONFIG).unwrap();
+        let project = toml::from_str::<toml::Table>(
+            r#"
+            max_attempts = 10
+            [ollama]
+            model = "gemma2:2b"
+            [languages.rust]
+            test = "cargo nextest run"
+            "#,
+        )
+        .unwrap();
+        super::merge(&mut config, project);
+        let mut overrides = toml::Table::new();
+        super::set_override(
+            &mut overrides,
+            "ollama.model",
+            toml::Value::from("qwen2.5-coder:1.5b"),
+        );
+        super::merge(&mut config, overrides);
+
+        let config: super::Config = toml::Value::Table(config).try_into().unwrap();
+        assert_eq!(config.max_attempts, 10);
+        assert_eq!(config.ollama.model, "qwen2.5-coder:1.5b");
+        assert_eq!(config.ollama.emb, "bge-large");
+        assert_eq!(
+            config.language_commands("rust").unwrap().test.as_deref(),
+            Some("cargo nextest run")
+        );
+    }
+}
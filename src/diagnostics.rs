@@ -0,0 +1,654 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// Errors and failed tests listed to the LLM at most.
+const MAX_ENTRIES: usize = 20;
+/// Lines of an assertion message kept.
+const MAX_MESSAGE_LINES: usize = 4;
+/// Lines of the raw output kept when no diagnostic could be extracted.
+const MAX_RAW_LINES: usize = 200;
+
+/// Compiler error or failed test located in a source file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// Test which did not pass and its assertion message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestFailure {
+    pub name: String,
+    pub location: Diagnostic,
+}
+
+/// Number of compiler errors and failed tests found in an output, including
+/// the ones left out of the summary.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Counts {
+    pub errors: usize,
+    pub failed_tests: usize,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    pub errors: Vec<Diagnostic>,
+    pub failures: Vec<TestFailure>,
+}
+
+impl Diagnostics {
+    /// Errors and test failures found in the output of a build or test tool:
+    /// `cargo --message-format=json`, rustc, javac and Maven, kotlinc, scalac,
+    /// swiftc, tsc, libtest, unittest, pytest, Jest, PHPUnit, ScalaTest and
+    /// JUnit XML `reports`. Absolute paths inside `dir` are made relative.
+    pub fn extract(stdout: &str, stderr: &str, reports: &[String], dir: &str) -> Diagnostics {
+        let output = format!("{}\n{}", stdout, stderr).replace("\r\n", "\n");
+        let mut diagnostics = Diagnostics::default();
+        diagnostics.cargo_json(&output);
+        diagnostics.compiler_errors(&output);
+        diagnostics.libtest_failures(&output);
+        diagnostics.unittest_failures(&output);
+        diagnostics.pytest_failures(&output);
+        diagnostics.jest_failures(&output);
+        diagnostics.phpunit_failures(&output);
+        diagnostics.scalatest_failures(&output);
+        for report in reports {
+            diagnostics.junit_failures(report);
+        }
+
+        // tools print the folder as given, made absolute or with the symlinks resolved
+        // (e.g. "/tmp" and "/private/tmp" on macOS)
+        let mut prefixes = [
+            Some(std::path::PathBuf::from(dir)),
+            std::path::absolute(dir).ok(),
+            std::fs::canonicalize(dir).ok(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(|dir| dir.to_string_lossy().replace('\\', "/"))
+        .map(|dir| format!("{}/", dir.trim_end_matches('/')))
+        .filter(|prefix| prefix != "./" && prefix != "/")
+        .collect::<Vec<_>>();
+        prefixes.sort_by_key(|prefix| std::cmp::Reverse(prefix.len()));
+        prefixes.dedup();
+        let relative = |location: &mut Diagnostic| {
+            if let Some(file) = &location.file {
+                let file = file.replace('\\', "/");
+                let file = file.strip_prefix("file://").unwrap_or(&file);
+                let file = prefixes
+                    .iter()
+                    .find_map(|prefix| file.strip_prefix(prefix.as_str()))
+                    .unwrap_or(file);
+                location.file = Some(file.trim_start_matches("./").to_string());
+            }
+        };
+        diagnostics.errors.iter_mut().for_each(relative);
+        diagnostics
+            .failures
+            .iter_mut()
+            .for_each(|failure| relative(&mut failure.location));
+        diagnostics.dedup();
+        diagnostics
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty() && self.failures.is_empty()
+    }
+
+    pub fn counts(&self) -> Counts {
+        Counts {
+            errors: self.errors.len(),
+            failed_tests: self.failures.len(),
+        }
+    }
+
+    /// Compact listing for the rewrite prompt.
+    pub fn summary(&self) -> String {
+        let mut lines = Vec::new();
+        if !self.errors.is_empty() {
+            lines.push("Errors:".to_string());
+            for error in self.errors.iter().take(MAX_ENTRIES) {
+                lines.push(format!("- {}{}", location(error), error.message));
+            }
+        }
+        if !self.failures.is_empty() {
+            lines.push("Failed tests:".to_string());
+            for failure in self.failures.iter().take(MAX_ENTRIES) {
+                let message = failure.location.message.replace('\n', "\n  ");
+                lines.push(format!(
+                    "- {} {}\n  {}",
+                    failure.name,
+                    location(&failure.location).trim_end_matches(": "),
+                    message
+                ));
+            }
+        }
+        let omitted = self.errors.len().saturating_sub(MAX_ENTRIES)
+            + self.failures.len().saturating_sub(MAX_ENTRIES);
+        if omitted > 0 {
+            lines.push(format!("... and {} more", omitted));
+        }
+        lines.join("\n")
+    }
+
+    fn dedup(&mut self) {
+        let mut errors: Vec<Diagnostic> = Vec::new();
+        for error in self.errors.drain(..) {
+            if !errors.contains(&error) {
+                errors.push(error);
+            }
+        }
+        self.errors = errors;
+        let mut failures: Vec<TestFailure> = Vec::new();
+        for failure in self.failures.drain(..) {
+            if !failures.iter().any(|known| known.name == failure.name) {
+                failures.push(failure);
+            }
+        }
+        self.failures = failures;
+    }
+
+    fn error(&mut self, file: Option<&str>, line: Option<&str>, message: &str) {
+        self.errors.push(Diagnostic {
+            file: file.map(|file| file.to_string()),
+            line: line.and_then(|line| line.parse().ok()),
+            message: message.trim().to_string(),
+        });
+    }
+
+    fn failure(&mut self, name: &str, file: Option<&str>, line: Option<&str>, message: &str) {
+        self.failures.push(TestFailure {
+            name: name.trim().to_string(),
+            location: Diagnostic {
+                file: file.map(|file| file.to_string()),
+                line: line.and_then(|line| line.parse().ok()),
+                message: compact_message(message),
+            },
+        });
+    }
+
+    /// `cargo build --message-format=json` compiler messages of the error level.
+    fn cargo_json(&mut self, output: &str) {
+        for line in output.lines().filter(|line| line.starts_with('{')) {
+            let Ok(value) = serde_json::from_str::<serde_json::Value>(line) else {
+                continue;
+            };
+            if value["reason"] != "compiler-message" || value["message"]["level"] != "error" {
+                continue;
+            }
+            let message = &value["message"];
+            let text = match message["code"]["code"].as_str() {
+                Some(code) => format!(
+                    "error[{}]: {}",
+                    code,
+                    message["message"].as_str().unwrap_or("")
+                ),
+                None => format!("error: {}", message["message"].as_str().unwrap_or("")),
+            };
+            let primary = message["spans"]
+                .as_array()
+                .and_then(|spans| spans.iter().find(|span| span["is_primary"] == true));
+            let text = match primary.and_then(|span| span["label"].as_str()) {
+                Some(label) => format!("{} ({})", text, label),
+                None => text,
+            };
+            let file = primary.and_then(|span| span["file_name"].as_str());
+            let line = primary.and_then(|span| span["line_start"].as_u64());
+            self.error(file, line.map(|line| line.to_string()).as_deref(), &text);
+        }
+    }
+
+    /// One line compiler errors: rustc, javac, Maven, kotlinc, scalac, swiftc, tsc.
+    fn compiler_errors(&mut self, output: &str) {
+        static RUSTC: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?m)^(error(?:\[E\d+\])?: .*)\n\s*--> (.*?):(\d+):\d+").unwrap()
+        });
+        static ONE_LINE: Lazy<Vec<Regex>> = Lazy::new(|| {
+            [
+                // javac, swiftc, XCTest and clang: "File.java:10: error: message"
+                r"(?m)^(.*?\.\w+):(\d+)(?::\d+)?: (?:fatal )?error: (.*)$",
+                // Maven: "[ERROR] /path/File.java:[10,5] message"
+                r"(?m)^\[ERROR\] (.*?\.\w+):\[(\d+),\d+\] (.*)$",
+                // kotlinc: "e: file:///path/File.kt:10:5 message" or "e: File.kt: (10, 5): message"
+                r"(?m)^e: (.*?\.kts?):(\d+):\d+ (.*)$",
+                r"(?m)^e: (.*?\.kts?): \((\d+), \d+\): (.*)$",
+                // scalac through sbt: "[error] /path/File.scala:10:5: message"
+                r"(?m)^\[error\] (.*?\.scala):(\d+):\d+: (.*)$",
+                // tsc: "src/file.ts(10,5): error TS2322: message"
+                r"(?m)^(.*?\.tsx?)\((\d+),\d+\): error (.*)$",
+            ]
+            .iter()
+            .map(|pattern| Regex::new(pattern).unwrap())
+            .collect()
+        });
+        let found = RUSTC
+            .captures_iter(output)
+            .map(|cap| (cap[2].to_string(), cap[3].to_string(), cap[1].to_string()))
+            .collect::<Vec<_>>();
+        for (file, line, message) in found {
+            self.error(Some(&file), Some(&line), &message);
+        }
+        for regex in ONE_LINE.iter() {
+            for cap in regex.captures_iter(output) {
+                self.error(Some(&cap[1]), Some(&cap[2]), &cap[3]);
+            }
+        }
+    }
+
+    /// `cargo test`: "---- name stdout ----" followed by the panic message.
+    fn libtest_failures(&mut self, output: &str) {
+        static BLOCK: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?ms)^---- (\S+) stdout ----\n(.*?)(?:\n\n|\z)").unwrap());
+        static PANIC: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?s)panicked at (?:'(.*)', )?(.*?):(\d+):\d+:?\n?(.*)").unwrap()
+        });
+        for block in BLOCK.captures_iter(output) {
+            match PANIC.captures(&block[2]) {
+                Some(panic) => {
+                    let message = panic
+                        .get(1)
+                        .map(|message| message.as_str())
+                        .unwrap_or(&panic[4]);
+                    let message = message
+                        .lines()
+                        .filter(|line| !line.starts_with("note: "))
+                        .collect::<Vec<_>>()
+                        .join("\n");
+                    self.failure(&block[1], Some(&panic[2]), Some(&panic[3]), &message)
+                }
+                None => self.failure(&block[1], None, None, &block[2]),
+            }
+        }
+    }
+
+    /// Python unittest: "FAIL: test_sum (test.TestSolution.test_sum)" and its traceback.
+    fn unittest_failures(&mut self, output: &str) {
+        static BLOCK: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?ms)^(?:FAIL|ERROR): (\S+) \((.*?)\)\n-+\n(.*?)(?:\n-{10,}|\n={10,}|\z)")
+                .unwrap()
+        });
+        static FILE: Lazy<Regex> = Lazy::new(|| Regex::new(r#"File "(.*?)", line (\d+)"#).unwrap());
+        for block in BLOCK.captures_iter(output) {
+            let traceback = &block[3];
+            let location = FILE.captures_iter(traceback).last();
+            let message = traceback
+                .lines()
+                .rev()
+                .find(|line| !line.trim().is_empty())
+                .unwrap_or_default();
+            self.failure(
+                &block[2],
+                location.as_ref().map(|cap| cap.get(1).unwrap().as_str()),
+                location.as_ref().map(|cap| cap.get(2).unwrap().as_str()),
+                message,
+            );
+        }
+    }
+
+    /// pytest short test summary: "FAILED tests/test_a.py::test_sum - assert 3 == 4".
+    fn pytest_failures(&mut self, output: &str) {
+        static SUMMARY: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?m)^(?:FAILED|ERROR) (\S+?)(?:::(\S+))?(?: - (.*))?$").unwrap()
+        });
+        static LOCATION: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?m)^(\S+\.py):(\d+): \w+").unwrap());
+        let locations = LOCATION.captures_iter(output).collect::<Vec<_>>();
+        for cap in SUMMARY.captures_iter(output) {
+            let file = &cap[1];
+            let name = match cap.get(2) {
+                Some(test) => format!("{}::{}", file, test.as_str()),
+                None => file.to_string(),
+            };
+            let line = locations
+                .iter()
+                .find(|location| &location[1] == file)
+                .map(|location| location.get(2).unwrap().as_str());
+            let message = cap.get(3).map(|message| message.as_str()).unwrap_or("");
+            self.failure(&name, Some(file), line, message);
+        }
+    }
+
+    /// Jest: "● Suite › test" followed by the expectation and the stack.
+    fn jest_failures(&mut self, output: &str) {
+        static BLOCK: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?ms)^\s*● (.+?)\n\n(.*?)(?:\n\s*● |\z)").unwrap());
+        static AT: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"at .*?\(?([^\s()]+?):(\d+):\d+\)?").unwrap());
+        let mut start = 0;
+        while let Some(block) = BLOCK.captures_at(output, start) {
+            let body = &block[2];
+            let message = body
+                .lines()
+                .take_while(|line| !line.trim_start().starts_with("at ") && !line.contains(" | "))
+                .collect::<Vec<_>>()
+                .join("\n");
+            let location = AT
+                .captures_iter(body)
+                .find(|cap| !cap[1].contains("node_modules"));
+            if block[1].contains(" › ") || !message.trim().is_empty() {
+                self.failure(
+                    &block[1],
+                    location.as_ref().map(|cap| cap.get(1).unwrap().as_str()),
+                    location.as_ref().map(|cap| cap.get(2).unwrap().as_str()),
+                    &message,
+                );
+            }
+            start = block.get(2).unwrap().end();
+        }
+    }
+
+    /// PHPUnit: "1) Tests\SolutionTest::testSum", the message and the location.
+    fn phpunit_failures(&mut self, output: &str) {
+        static BLOCK: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?ms)^\d+\) (\S+::\S+)\n(.*?)\n\n(\S+\.php):(\d+)$").unwrap()
+        });
+        for cap in BLOCK.captures_iter(output) {
+            self.failure(&cap[1], Some(&cap[3]), Some(&cap[4]), &cap[2]);
+        }
+    }
+
+    /// ScalaTest through sbt: "- sums *** FAILED ***" and "  3 did not equal 4 (SolutionTest.scala:10)".
+    fn scalatest_failures(&mut self, output: &str) {
+        static FAILED: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?m)^(?:\[info\] )?- (.*?) \*\*\* FAILED \*\*\*\n(?:\[info\])?\s+(.*?) \((\S+?):(\d+)\)$")
+                .unwrap()
+        });
+        for cap in FAILED.captures_iter(output) {
+            self.failure(&cap[1], Some(&cap[3]), Some(&cap[4]), &cap[2]);
+        }
+    }
+
+    /// `<testcase>` elements with a `<failure>` or `<error>` of a JUnit XML report.
+    fn junit_failures(&mut self, report: &str) {
+        static TESTCASE: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"(?s)<testcase\b([^>]*?)(?:/>|>(.*?)</testcase>)").unwrap());
+        static FAILURE: Lazy<Regex> = Lazy::new(|| {
+            Regex::new(r"(?s)<(?:failure|error)\b([^>]*?)(?:/>|>(.*?)</(?:failure|error)>)")
+                .unwrap()
+        });
+        static LOCATION: Lazy<Regex> =
+            Lazy::new(|| Regex::new(r"\(([\w$.-]+\.\w+):(\d+)\)").unwrap());
+        for testcase in TESTCASE.captures_iter(report) {
+            let Some(body) = testcase.get(2) else {
+                continue;
+            };
+            let Some(failure) = FAILURE.captures(body.as_str()) else {
+                continue;
+            };
+            let attributes = &testcase[1];
+            let name = match (
+                attribute(attributes, "classname"),
+                attribute(attributes, "name"),
+            ) {
+                (Some(class), Some(name)) => format!("{}.{}", class, name),
+                (None, Some(name)) => name,
+                _ => continue,
+            };
+            let text = failure
+                .get(2)
+                .map(|text| unescape_xml(text.as_str()))
+                .unwrap_or_default();
+            let message = attribute(&failure[1], "message")
+                .unwrap_or_else(|| text.lines().next().unwrap_or_default().to_string());
+            // the first stack frame in a test source file
+            let location = LOCATION.captures_iter(&text).find(|cap| {
+                let file = cap.get(1).unwrap().as_str();
+                !file.starts_with("Assert") && !file.starts_with("Native")
+            });
+            self.failure(
+                &name,
+                location.as_ref().map(|cap| cap.get(1).unwrap().as_str()),
+                location.as_ref().map(|cap| cap.get(2).unwrap().as_str()),
+                &message,
+            );
+        }
+    }
+}
+
+/// Diagnostics of the output or the last lines of the raw output when none was
+/// found, and their counts.
+pub fn error_message(
+    stdout: &str,
+    stderr: &str,
+    reports: &[String],
+    dir: &str,
+) -> (String, Counts) {
+    let diagnostics = Diagnostics::extract(stdout, stderr, reports, dir);
+    if !diagnostics.is_empty() {
+        return (diagnostics.summary(), diagnostics.counts());
+    }
+    let raw = format!("{}\n{}", stderr.trim_end(), stdout.trim_end());
+    let lines = raw.trim().lines().collect::<Vec<_>>();
+    (
+        lines[lines.len().saturating_sub(MAX_RAW_LINES)..].join("\n"),
+        Counts::default(),
+    )
+}
+
+fn location(diagnostic: &Diagnostic) -> String {
+    match (&diagnostic.file, diagnostic.line) {
+        (Some(file), Some(line)) => format!("{}:{}: ", file, line),
+        (Some(file), None) => format!("{}: ", file),
+        _ => String::new(),
+    }
+}
+
+/// First lines of `message` without the empty lines and the common indentation.
+fn compact_message(message: &str) -> String {
+    let lines = message
+        .lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .take(MAX_MESSAGE_LINES)
+        .collect::<Vec<_>>();
+    let indent = lines
+        .iter()
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    lines
+        .iter()
+        .map(|line| &line[indent..])
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let regex = Regex::new(&format!(r#"\b{}="([^"]*)""#, name)).unwrap();
+    regex
+        .captures(attributes)
+        .map(|cap| unescape_xml(cap.get(1).unwrap().as_str()))
+}
+
+fn unescape_xml(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&#10;", "\n")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_cargo_diagnostics() {
+        let stdout = r#"{"reason":"compiler-artifact","target":{"name":"serde"}}
+{"reason":"compiler-message","message":{"level":"warning","message":"unused variable: `x`","code":null,"spans":[]}}
+{"reason":"compiler-message","message":{"level":"error","message":"mismatched types","code":{"code":"E0308"},"spans":[{"file_name":"src/lib.rs","line_start":3,"is_primary":true,"label":"expected `i32`, found `&str`"}]}}
+{"reason":"compiler-message","message":{"level":"error","message":"mismatched types","code":{"code":"E0308"},"spans":[{"file_name":"src/lib.rs","line_start":3,"is_primary":true,"label":"expected `i32`, found `&str`"}]}}
+
+running 2 tests
+test tests::test_ok ... ok
+test tests::test_sum ... FAILED
+
+failures:
+
+---- tests::test_sum stdout ----
+thread 'tests::test_sum' panicked at src/lib.rs:12:9:
+assertion `left == right` failed
+  left: 3
+ right: 4
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+failures:
+    tests::test_sum
+"#;
+        let diagnostics = super::Diagnostics::extract(stdout, "error: test failed", &[], ".");
+        assert_eq!(
+            diagnostics.summary(),
+            "Errors:
+- src/lib.rs:3: error[E0308]: mismatched types (expected `i32`, found `&str`)
+Failed tests:
+- tests::test_sum src/lib.rs:12
+  assertion `left == right` failed
+    left: 3
+   right: 4"
+        );
+    }
+
+    #[test]
+    fn test_compiler_errors() {
+        let dir = std::env::temp_dir().join("rustsn_test_diagnostics");
+        std::fs::create_dir_all(&dir).unwrap();
+        // the sandbox is given through a symlink, some tools print the resolved path
+        let link = std::env::temp_dir().join("rustsn_test_diagnostics_link");
+        let _ = std::fs::remove_file(&link);
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        #[cfg(not(unix))]
+        let link = dir.clone();
+        let given = link.to_string_lossy().replace('\\', "/");
+        let canonical = std::fs::canonicalize(&link)
+            .unwrap()
+            .to_string_lossy()
+            .replace('\\', "/");
+        let output = format!(
+            "[ERROR] {}/src/main/java/Solution.java:[5,9] cannot find symbol
+e: file://{}/src/main/kotlin/Solution.kt:3:5 Unresolved reference: x
+src/solution.ts(2,7): error TS2322: Type 'string' is not assignable to type 'number'.
+Sources/Solution/Solution.swift:4:12: error: cannot find 'y' in scope
+error[E0425]: cannot find value `z` in this scope
+ --> src/lib.rs:7:5
+[ERROR] /elsewhere/Other.java:[1,1] cannot find symbol
+",
+            given, canonical
+        );
+        let errors = super::Diagnostics::extract(&output, "", &[], &given)
+            .errors
+            .into_iter()
+            .map(|error| {
+                format!(
+                    "{}:{} {}",
+                    error.file.unwrap(),
+                    error.line.unwrap(),
+                    error.message
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![
+                "src/lib.rs:7 error[E0425]: cannot find value `z` in this scope",
+                "Sources/Solution/Solution.swift:4 cannot find 'y' in scope",
+                "src/main/java/Solution.java:5 cannot find symbol",
+                "/elsewhere/Other.java:1 cannot find symbol",
+                "src/main/kotlin/Solution.kt:3 Unresolved reference: x",
+                "src/solution.ts:2 TS2322: Type 'string' is not assignable to type 'number'.",
+            ]
+        );
+        let _ = std::fs::remove_file(&link);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_test_failures() {
+        let unittest = "F.
+======================================================================
+FAIL: test_sum (test.TestSolution.test_sum)
+----------------------------------------------------------------------
+Traceback (most recent call last):
+  File \"/tmp/sandbox/test.py\", line 8, in test_sum
+    self.assertEqual(solution(1, 2), 4)
+AssertionError: 3 != 4
+
+----------------------------------------------------------------------
+Ran 2 tests in 0.001s
+";
+        let pytest = "tests/test_a.py:5: AssertionError
+=========================== short test summary info ============================
+FAILED tests/test_a.py::test_sum - assert 3 == 4
+";
+        let jest = "  ● solution › sums two numbers
+
+    expect(received).toBe(expected) // Object.is equality
+
+    Expected: 4
+    Received: 3
+
+      3 | test('sums two numbers', () => {
+    > 4 |   expect(solution(1, 2)).toBe(4);
+
+      at Object.<anonymous> (src/solution.test.js:4:26)
+
+Tests:       1 failed, 1 total
+";
+        let phpunit = "There was 1 failure:
+
+1) Tests\\SolutionTest::testSum
+Failed asserting that 3 matches expected 4.
+
+/tmp/sandbox/tests/SolutionTest.php:12
+
+FAILURES!
+";
+        let junit = r#"<testsuite name="SolutionTest" tests="2" failures="1">
+  <testcase name="ok" classname="SolutionTest" time="0.01"/>
+  <testcase name="testSum" classname="SolutionTest" time="0.01">
+    <failure message="expected: &lt;4&gt; but was: &lt;3&gt;" type="org.opentest4j.AssertionFailedError">org.opentest4j.AssertionFailedError: expected: &lt;4&gt; but was: &lt;3&gt;
+	at org.junit.jupiter.api.AssertionUtils.fail(AssertionUtils.java:55)
+	at SolutionTest.testSum(SolutionTest.java:10)
+</failure>
+  </testcase>
+</testsuite>"#;
+        let output = [unittest, pytest, jest, phpunit].join("\n");
+        let failures =
+            super::Diagnostics::extract(&output, "", &[junit.to_string()], "/tmp/sandbox")
+                .failures
+                .into_iter()
+                .map(|failure| {
+                    format!(
+                        "{} {}:{} {}",
+                        failure.name,
+                        failure.location.file.unwrap_or_default(),
+                        failure.location.line.unwrap_or_default(),
+                        failure.location.message.replace('\n', " / ")
+                    )
+                })
+                .collect::<Vec<_>>();
+        assert_eq!(
+            failures,
+            vec![
+                "test.TestSolution.test_sum test.py:8 AssertionError: 3 != 4",
+                "tests/test_a.py::test_sum tests/test_a.py:5 assert 3 == 4",
+                "solution › sums two numbers src/solution.test.js:4 expect(received).toBe(expected) // Object.is equality / Expected: 4 / Received: 3",
+                "Tests\\SolutionTest::testSum tests/SolutionTest.php:12 Failed asserting that 3 matches expected 4.",
+                "SolutionTest.testSum SolutionTest.java:10 expected: <4> but was: <3>",
+            ]
+        );
+        let counts = super::Counts {
+            errors: 0,
+            failed_tests: 5,
+        };
+        assert_eq!(
+            super::error_message(&output, "", &[junit.to_string()], "/tmp/sandbox").1,
+            counts
+        );
+        assert_eq!(
+            super::error_message("- not a diagnostic", "", &[], "/tmp/sandbox").1,
+            super::Counts::default()
+        );
+    }
+}
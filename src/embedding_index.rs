@@ -0,0 +1,266 @@
+use bincode::{config, Decode, Encode};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::chunker::Chunker;
+use crate::utils::sha256_hex;
+
+/// Folder inside the indexed project where rustsn keeps its data.
+pub const INDEX_DIR: &str = ".rustsn";
+const INDEX_FILE: &str = "index.bin";
+
+/// Embeddings of the source files of one project, stored in
+/// `<project>/.rustsn/index.bin` and updated incrementally: only new and
+/// changed files are embedded again, deleted files are pruned.
+#[derive(Encode, Decode, Debug, Default)]
+pub struct EmbeddingIndex {
+    /// Embedding model and chunking settings the vectors were computed with.
+    pub settings: String,
+    pub files: Vec<IndexedFile>,
+}
+
+#[derive(Encode, Decode, Debug)]
+pub struct IndexedFile {
+    /// Path relative to the project folder.
+    pub path: String,
+    /// Modification time in milliseconds since the Unix epoch.
+    pub mtime: u64,
+    pub hash: String,
+    pub chunks: Vec<IndexedChunk>,
+}
+
+#[derive(Encode, Decode, Debug)]
+pub struct IndexedChunk {
+    /// Byte offsets of the chunk in the file content.
+    pub start: usize,
+    pub end: usize,
+    /// 1-based inclusive line range of the chunk.
+    pub start_line: usize,
+    pub end_line: usize,
+    pub vector: Vec<f32>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexStats {
+    pub added: usize,
+    pub updated: usize,
+    pub removed: usize,
+    pub unchanged: usize,
+    /// Files left out because a chunk could not be embedded.
+    pub failed: usize,
+}
+
+impl EmbeddingIndex {
+    pub fn file_path(root: &str) -> PathBuf {
+        Path::new(root).join(INDEX_DIR).join(INDEX_FILE)
+    }
+
+    /// Index of the project in `root`. An index computed with other `settings`
+    /// (embedding model, chunking) or which can't be read is dropped and built
+    /// again from scratch.
+    pub fn load(root: &str, settings: &str) -> EmbeddingIndex {
+        let empty = EmbeddingIndex {
+            settings: settings.to_string(),
+            files: Vec::new(),
+        };
+        let Ok(encoded) = std::fs::read(EmbeddingIndex::file_path(root)) else {
+            return empty;
+        };
+        match bincode::decode_from_slice::<EmbeddingIndex, _>(&encoded, config::standard()) {
+            Ok((index, _)) if index.settings == settings => index,
+            Ok(_) => {
+                println!("Embedding model or chunking changed, the index is rebuilt");
+                empty
+            }
+            Err(err) => {
+                println!("Warning: Cant read the index, it is rebuilt: {}", err);
+                empty
+            }
+        }
+    }
+
+    pub fn save(&self, root: &str) {
+        let file_path = EmbeddingIndex::file_path(root);
+        std::fs::create_dir_all(file_path.parent().unwrap()).unwrap();
+        let encoded = bincode::encode_to_vec(self, config::standard()).unwrap();
+        // write a copy first so an interrupted save does not destroy the index
+        let tmp_path = file_path.with_extension("tmp");
+        std::fs::write(&tmp_path, encoded).unwrap();
+        std::fs::rename(&tmp_path, &file_path).unwrap();
+    }
+
+    /// Brings the index in sync with `files` (paths as returned by the file
+    /// explorer for `root`). `embed` computes the vector of a chunk text. A
+    /// file with a chunk which can't be embedded is left out of the index, so
+    /// the next update embeds it again.
+    pub fn update(
+        &mut self,
+        root: &str,
+        files: &[String],
+        chunker: &Chunker,
+        embed: &mut dyn FnMut(&str) -> Result<Vec<f32>, String>,
+    ) -> IndexStats {
+        let mut stats = IndexStats::default();
+        let relative_paths = files
+            .iter()
+            .map(|file| relative_path(root, file))
+            .collect::<Vec<_>>();
+
+        let files_before = self.files.len();
+        self.files
+            .retain(|indexed| relative_paths.contains(&indexed.path));
+        stats.removed = files_before - self.files.len();
+
+        for (file, path) in files.iter().zip(relative_paths) {
+            let mtime = modification_time(file);
+            let position = self.files.iter().position(|indexed| indexed.path == path);
+            if let Some(i) = position {
+                if self.files[i].mtime == mtime {
+                    stats.unchanged += 1;
+                    continue;
+                }
+            }
+            let Ok(content) = std::fs::read_to_string(file) else {
+                println!("Warning: Cant read \"{}\", skipped", file);
+                continue;
+            };
+            let hash = sha256_hex(content.as_bytes());
+            if let Some(i) = position {
+                if self.files[i].hash == hash {
+                    self.files[i].mtime = mtime;
+                    stats.unchanged += 1;
+                    continue;
+                }
+            }
+
+            println!("Index: {}", path);
+            let chunks = chunker
+                .chunk_file(&path, &content)
+                .into_iter()
+                .map(|chunk| {
+                    Ok(IndexedChunk {
+                        vector: embed(&format!(
+                            "# {}:{}-{}\r\n{}",
+                            path,
+                            chunk.start_line,
+                            chunk.end_line,
+                            &content[chunk.start..chunk.end]
+                        ))?,
+                        start: chunk.start,
+                        end: chunk.end,
+                        start_line: chunk.start_line,
+                        end_line: chunk.end_line,
+                    })
+                })
+                .collect::<Result<Vec<_>, String>>();
+            let chunks = match chunks {
+                Ok(chunks) => chunks,
+                Err(err) => {
+                    println!("Warning: Cant index \"{}\", skipped: {}", path, err);
+                    // the old chunks don't match the changed content
+                    if let Some(i) = position {
+                        self.files.remove(i);
+                    }
+                    stats.failed += 1;
+                    continue;
+                }
+            };
+            let indexed = IndexedFile {
+                path,
+                mtime,
+                hash,
+                chunks,
+            };
+            match position {
+                Some(i) => {
+                    self.files[i] = indexed;
+                    stats.updated += 1;
+                }
+                None => {
+                    self.files.push(indexed);
+                    stats.added += 1;
+                }
+            }
+        }
+        stats
+    }
+}
+
+fn relative_path(root: &str, file: &str) -> String {
+    Path::new(file)
+        .strip_prefix(root)
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .unwrap_or_else(|_| file.to_string())
+}
+
+fn modification_time(file: &str) -> u64 {
+    std::fs::metadata(file)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_update_index() {
+        let root = std::env::temp_dir().join("rustsn_test_update_index");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let root_str = root.to_str().unwrap();
+        let file = |name: &str| root.join(name).to_str().unwrap().to_string();
+        std::fs::write(file("a.rs"), "fn a() {}").unwrap();
+        std::fs::write(file("b.rs"), "fn b() {}").unwrap();
+
+        let mut embedded = Vec::new();
+        let mut embed = |text: &str| {
+            embedded.push(text.to_string());
+            Ok(vec![text.len() as f32])
+        };
+        let chunker = crate::chunker::Chunker::new(&[], 60, 5);
+        let mut index = super::EmbeddingIndex::load(root_str, "test");
+        let stats = index.update(
+            root_str,
+            &[file("a.rs"), file("b.rs")],
+            &chunker,
+            &mut embed,
+        );
+        assert_eq!(stats.added, 2);
+        index.save(root_str);
+
+        let mut index = super::EmbeddingIndex::load(root_str, "test");
+        std::fs::write(file("b.rs"), "fn b() { changed() }").unwrap();
+        // make sure the modification time differs on file systems with coarse timestamps
+        index.files[1].mtime = 0;
+        let stats = index.update(root_str, &[file("b.rs")], &chunker, &mut embed);
+        assert_eq!(
+            stats,
+            super::IndexStats {
+                added: 0,
+                updated: 1,
+                removed: 1,
+                unchanged: 0,
+                failed: 0,
+            }
+        );
+        assert_eq!(index.files.len(), 1);
+        assert_eq!(embedded.len(), 3);
+
+        // a file whose embedding failed is dropped and embedded again next time
+        std::fs::write(file("b.rs"), "fn b() { changed_again() }").unwrap();
+        index.files[0].mtime = 0;
+        let mut failing = |_: &str| Err("LLM request failed".to_string());
+        let stats = index.update(root_str, &[file("b.rs")], &chunker, &mut failing);
+        assert_eq!((stats.failed, index.files.len()), (1, 0));
+        let mut embed = |text: &str| Ok(vec![text.len() as f32]);
+        let stats = index.update(root_str, &[file("b.rs")], &chunker, &mut embed);
+        assert_eq!((stats.added, stats.failed), (1, 0));
+
+        assert!(super::EmbeddingIndex::load(root_str, "other")
+            .files
+            .is_empty());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,535 @@
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+use crate::config::ExecutionConfig;
+
+/// How often a running command is checked for its end and the timeout.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Time given to the output pipes to close once the command ended, processes
+/// left in the background keep them open.
+const PIPE_GRACE: Duration = Duration::from_secs(5);
+/// Exit code of a command which could not be launched, as in shells.
+const LAUNCH_FAILED: i32 = 127;
+
+/// Result of a command run by `run`.
+pub struct Execution {
+    pub exit_code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// Why the command was stopped: timeout, exceeded limit or launch error.
+    pub limit: Option<String>,
+    /// The command was killed at the wall-clock timeout.
+    pub timed_out: bool,
+}
+
+/// Runs `program` with `args` in `dir` within the limits, jailed when
+/// `limits.jail` is set. Failures to launch, timeouts and exceeded limits are
+/// reported as a failed command with the reason in `limit`.
+pub fn run(program: &str, args: &[&str], dir: &str, limits: &ExecutionConfig) -> Execution {
+    let mut command = match command(program, args, dir, limits) {
+        Ok(command) => command,
+        Err(err) => return launch_failure(err),
+    };
+    command
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(err) => return launch_failure(format!("Cant launch \"{}\": {}", program, err)),
+    };
+    let stdout = read_in_background(child.stdout.take().unwrap());
+    let stderr = read_in_background(child.stderr.take().unwrap());
+
+    let start = Instant::now();
+    let timeout = Duration::from_secs(limits.timeout_secs);
+    let mut timed_out = false;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) => {}
+            Err(err) => {
+                println!("Warning: Cant wait for \"{}\": {}", program, err);
+                kill(&mut child);
+                break child.wait().ok();
+            }
+        }
+        if limits.timeout_secs > 0 && start.elapsed() >= timeout {
+            timed_out = true;
+            kill(&mut child);
+            break child.wait().ok();
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    };
+    let stdout = match stdout.recv_timeout(PIPE_GRACE) {
+        Ok(stdout) => stdout,
+        Err(_) => {
+            kill(&mut child);
+            stdout.recv_timeout(PIPE_GRACE).unwrap_or_default()
+        }
+    };
+    let stderr = match stderr.recv_timeout(PIPE_GRACE) {
+        Ok(stderr) => stderr,
+        Err(_) => {
+            kill(&mut child);
+            stderr.recv_timeout(PIPE_GRACE).unwrap_or_default()
+        }
+    };
+
+    let (exit_code, signal) = match status {
+        Some(status) => exit_code(&status),
+        None => (-1, None),
+    };
+    let limit = if timed_out {
+        Some(format!(
+            "The command was killed after the time limit of {} seconds",
+            limits.timeout_secs
+        ))
+    } else {
+        signal
+            .or_else(|| shell_signal(exit_code))
+            .map(|signal| signal_reason(signal, limits))
+    };
+    Execution {
+        exit_code,
+        stdout,
+        stderr,
+        limit,
+        timed_out,
+    }
+}
+
+fn launch_failure(err: String) -> Execution {
+    Execution {
+        exit_code: LAUNCH_FAILED,
+        stdout: String::new(),
+        stderr: String::new(),
+        limit: Some(err),
+        timed_out: false,
+    }
+}
+
+/// The program with its arguments, wrapped in the jail.
+fn command(
+    program: &str,
+    args: &[&str],
+    dir: &str,
+    limits: &ExecutionConfig,
+) -> Result<Command, String> {
+    let mut command = match limits.jail.as_str() {
+        "none" => {
+            let mut command = Command::new(program);
+            command.args(args);
+            command
+        }
+        "bwrap" => bwrap(program, args, &writable_dirs(dir, limits)?, limits.network),
+        #[cfg(target_os = "linux")]
+        "namespace" => {
+            let mut command = Command::new(program);
+            command.args(args);
+            namespace::jail(&mut command, &writable_dirs(dir, limits)?, limits.network)?;
+            command
+        }
+        #[cfg(not(target_os = "linux"))]
+        "namespace" => return Err("The namespace jail is only available on Linux".to_string()),
+        jail => {
+            return Err(format!(
+                "Unknown jail \"{}\", use \"none\", \"namespace\" or \"bwrap\"",
+                jail
+            ))
+        }
+    };
+    #[cfg(unix)]
+    set_limits(&mut command, limits);
+    Ok(command)
+}
+
+/// Absolute paths of the project folder and the other folders a jailed
+/// command can write into.
+fn writable_dirs(dir: &str, limits: &ExecutionConfig) -> Result<Vec<String>, String> {
+    let mut dirs = vec![std::fs::canonicalize(dir)
+        .map_err(|err| format!("Cant find \"{}\": {}", dir, err))?
+        .to_string_lossy()
+        .to_string()];
+    for path in &limits.writable {
+        let path = match (path.strip_prefix("~/"), std::env::var("HOME")) {
+            (Some(rest), Ok(home)) => format!("{}/{}", home, rest),
+            _ => path.clone(),
+        };
+        match std::fs::canonicalize(&path) {
+            Ok(path) => dirs.push(path.to_string_lossy().to_string()),
+            Err(err) => println!("Warning: Cant find writable folder \"{}\": {}", path, err),
+        }
+    }
+    Ok(dirs)
+}
+
+/// `bwrap` launching the program with a read-only view of the file system,
+/// private `/tmp`, `/dev` and `/proc` and no network unless `network` is set.
+fn bwrap(program: &str, args: &[&str], writable: &[String], network: bool) -> Command {
+    let mut command = Command::new("bwrap");
+    command.args(["--ro-bind", "/", "/", "--dev", "/dev", "--proc", "/proc"]);
+    command.args(["--tmpfs", "/tmp"]);
+    for dir in writable {
+        command.args(["--bind", dir, dir]);
+    }
+    command.args(["--unshare-pid", "--unshare-ipc", "--unshare-uts"]);
+    command.args(["--die-with-parent", "--new-session"]);
+    if !network {
+        command.arg("--unshare-net");
+    }
+    command.args(["--chdir", &writable[0], "--", program]);
+    command.args(args);
+    command
+}
+
+/// Sets the resource limits and puts the command into its own process group,
+/// so the whole process tree can be killed.
+#[cfg(unix)]
+fn set_limits(command: &mut Command, limits: &ExecutionConfig) {
+    use std::os::unix::process::CommandExt;
+
+    const MB: u64 = 1024 * 1024;
+    let rlimits = [
+        (libc::RLIMIT_CPU, limits.cpu_secs),
+        (libc::RLIMIT_AS, limits.memory_mb * MB),
+        (libc::RLIMIT_NPROC, limits.processes),
+        (libc::RLIMIT_FSIZE, limits.file_size_mb * MB),
+    ];
+    // only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            libc::setpgid(0, 0);
+            for (resource, value) in rlimits {
+                if value == 0 {
+                    continue;
+                }
+                let mut limit = libc::rlimit {
+                    rlim_cur: 0,
+                    rlim_max: 0,
+                };
+                if libc::getrlimit(resource, &mut limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                let value = value as libc::rlim_t;
+                limit.rlim_cur = value.min(limit.rlim_max);
+                // the soft CPU limit sends SIGXCPU, the hard one SIGKILL a second later
+                if resource == libc::RLIMIT_CPU {
+                    limit.rlim_max = limit.rlim_max.min(value.saturating_add(1));
+                }
+                if libc::setrlimit(resource, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
+
+/// Kills the process group of the command, or the command alone where
+/// there are no process groups.
+fn kill(child: &mut std::process::Child) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+}
+
+fn read_in_background(mut pipe: impl Read + Send + 'static) -> mpsc::Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut bytes = Vec::new();
+        let _ = pipe.read_to_end(&mut bytes);
+        let _ = sender.send(String::from_utf8_lossy(&bytes).to_string());
+    });
+    receiver
+}
+
+/// Exit code and the signal which killed the command. Commands killed by a
+/// signal get the exit code 128 + signal, as in shells.
+fn exit_code(status: &std::process::ExitStatus) -> (i32, Option<i32>) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return (128 + signal, Some(signal));
+        }
+    }
+    (status.code().unwrap_or(-1), None)
+}
+
+/// Signal of the limits which killed a process started by a shell, shells
+/// exit with 128 + signal then.
+fn shell_signal(exit_code: i32) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        let signal = exit_code - 128;
+        if signal == libc::SIGXCPU || signal == libc::SIGXFSZ {
+            return Some(signal);
+        }
+    }
+    let _ = exit_code;
+    None
+}
+
+fn signal_reason(signal: i32, limits: &ExecutionConfig) -> String {
+    #[cfg(unix)]
+    {
+        if signal == libc::SIGXCPU || (signal == libc::SIGKILL && limits.cpu_secs > 0) {
+            return format!(
+                "The command was killed by signal {}, it exceeded the CPU time limit of {} seconds or the memory",
+                signal, limits.cpu_secs
+            );
+        }
+        if signal == libc::SIGXFSZ {
+            return format!(
+                "The command exceeded the file size limit of {} MB",
+                limits.file_size_mb
+            );
+        }
+        if limits.memory_mb > 0 && [libc::SIGABRT, libc::SIGSEGV, libc::SIGBUS].contains(&signal) {
+            return format!(
+                "The command was killed by signal {}, the memory limit is {} MB",
+                signal, limits.memory_mb
+            );
+        }
+    }
+    format!("The command was killed by signal {}", signal)
+}
+
+/// Jail made of Linux namespaces: a user namespace with the same user, a
+/// mount namespace where everything but the writable folders is remounted
+/// read-only, and an empty network namespace.
+#[cfg(target_os = "linux")]
+mod namespace {
+    use std::ffi::CString;
+    use std::os::unix::process::CommandExt;
+    use std::process::Command;
+
+    /// Mount flags a remount inside a user namespace has to keep.
+    const LOCKED_FLAGS: [(&str, libc::c_ulong); 6] = [
+        ("nosuid", libc::MS_NOSUID),
+        ("nodev", libc::MS_NODEV),
+        ("noexec", libc::MS_NOEXEC),
+        ("noatime", libc::MS_NOATIME),
+        ("nodiratime", libc::MS_NODIRATIME),
+        ("relatime", libc::MS_RELATIME),
+    ];
+
+    pub fn jail(command: &mut Command, writable: &[String], network: bool) -> Result<(), String> {
+        let c_string = |text: &str| CString::new(text).map_err(|err| err.to_string());
+        let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+        let files = [
+            (c_string("/proc/self/setgroups")?, "deny".to_string()),
+            (
+                c_string("/proc/self/uid_map")?,
+                format!("{} {} 1", uid, uid),
+            ),
+            (
+                c_string("/proc/self/gid_map")?,
+                format!("{} {} 1", gid, gid),
+            ),
+        ];
+        let binds = writable
+            .iter()
+            .map(|dir| c_string(dir))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mountinfo = std::fs::read_to_string("/proc/self/mountinfo")
+            .map_err(|err| format!("Cant read the mounts: {}", err))?;
+        let read_only = mount_points(&mountinfo)
+            .into_iter()
+            .filter(|(path, _)| {
+                !writable
+                    .iter()
+                    .any(|dir| path == dir || path.starts_with(&format!("{}/", dir)))
+            })
+            .map(|(path, flags)| Ok((c_string(&path)?, flags)))
+            .collect::<Result<Vec<_>, String>>()?;
+        let private_tmp = !writable
+            .iter()
+            .any(|dir| dir == "/tmp" || dir.starts_with("/tmp/"));
+        let root = c_string("/")?;
+        let tmp = c_string("/tmp")?;
+        let tmpfs = c_string("tmpfs")?;
+
+        let mut flags = libc::CLONE_NEWUSER | libc::CLONE_NEWNS;
+        if !network {
+            flags |= libc::CLONE_NEWNET;
+        }
+        // only async-signal-safe calls between fork and exec, everything is prepared above
+        unsafe {
+            command.pre_exec(move || {
+                let check = |result: libc::c_int| match result {
+                    -1 => Err(std::io::Error::last_os_error()),
+                    _ => Ok(()),
+                };
+                check(libc::unshare(flags))?;
+                for (path, content) in &files {
+                    let fd = libc::open(path.as_ptr(), libc::O_WRONLY);
+                    check(fd)?;
+                    let written = libc::write(fd, content.as_ptr() as *const _, content.len());
+                    libc::close(fd);
+                    check(written as libc::c_int)?;
+                }
+                let null = std::ptr::null();
+                check(libc::mount(
+                    null,
+                    root.as_ptr(),
+                    null,
+                    libc::MS_REC | libc::MS_PRIVATE,
+                    std::ptr::null(),
+                ))?;
+                for dir in &binds {
+                    check(libc::mount(
+                        dir.as_ptr(),
+                        dir.as_ptr(),
+                        null,
+                        libc::MS_BIND | libc::MS_REC,
+                        std::ptr::null(),
+                    ))?;
+                }
+                for (path, locked) in &read_only {
+                    let result = libc::mount(
+                        null,
+                        path.as_ptr(),
+                        null,
+                        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | locked,
+                        std::ptr::null(),
+                    );
+                    // mounts like /proc/sys can't be changed and are read-only anyway
+                    if path.as_bytes() == b"/" {
+                        check(result)?;
+                    }
+                }
+                if private_tmp {
+                    libc::mount(
+                        tmpfs.as_ptr(),
+                        tmp.as_ptr(),
+                        tmpfs.as_ptr(),
+                        libc::MS_NOSUID | libc::MS_NODEV,
+                        std::ptr::null(),
+                    );
+                }
+                // the working folder was entered before it was bind mounted
+                check(libc::chdir(binds[0].as_ptr()))?;
+                Ok(())
+            });
+        }
+        Ok(())
+    }
+
+    /// Mount points of `/proc/self/mountinfo` and their locked flags.
+    pub fn mount_points(mountinfo: &str) -> Vec<(String, libc::c_ulong)> {
+        mountinfo
+            .lines()
+            .filter_map(|line| {
+                let fields = line.split(' ').collect::<Vec<_>>();
+                let path = unescape(fields.get(4)?);
+                let options = fields.get(5)?.split(',').collect::<Vec<_>>();
+                let flags = LOCKED_FLAGS
+                    .iter()
+                    .filter(|(name, _)| options.contains(name))
+                    .fold(0, |flags, (_, flag)| flags | flag);
+                Some((path, flags))
+            })
+            .collect()
+    }
+
+    /// Decodes the octal escapes of spaces and other characters in mount points.
+    fn unescape(path: &str) -> String {
+        let mut bytes = Vec::new();
+        let mut rest = path.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            let octal = tail
+                .get(..3)
+                .and_then(|digits| std::str::from_utf8(digits).ok())
+                .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+            match (byte, octal) {
+                (b'\\', Some(decoded)) => {
+                    bytes.push(decoded);
+                    rest = &tail[3..];
+                }
+                _ => {
+                    bytes.push(byte);
+                    rest = tail;
+                }
+            }
+        }
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    #[test]
+    fn test_limits_are_reported() {
+        let dir = std::env::temp_dir().join("rustsn_test_executor_limits");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap();
+        let mut limits = crate::config::Config::default().execution;
+
+        limits.timeout_secs = 1;
+        let start = std::time::Instant::now();
+        let execution = super::run("sh", &["-c", "echo started; sleep 30"], dir, &limits);
+        assert!(start.elapsed() < std::time::Duration::from_secs(10));
+        assert!(execution.timed_out);
+        assert_ne!(execution.exit_code, 0);
+        assert_eq!(execution.stdout, "started\n");
+        assert!(execution.limit.unwrap().contains("time limit of 1 seconds"));
+
+        limits.file_size_mb = 1;
+        let execution = super::run(
+            "sh",
+            &["-c", "head -c 2000000 /dev/zero > big"],
+            dir,
+            &limits,
+        );
+        assert_ne!(execution.exit_code, 0);
+        assert!(execution.limit.unwrap().contains("file size limit of 1 MB"));
+
+        let execution = super::run("rustsn-missing-tool", &[], dir, &limits);
+        assert_eq!(execution.exit_code, super::LAUNCH_FAILED);
+        assert!(execution.limit.unwrap().starts_with("Cant launch"));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_namespace_jail() {
+        let root = std::env::temp_dir().join("rustsn_test_executor_jail");
+        let _ = std::fs::remove_dir_all(&root);
+        let dir = root.join("project");
+        std::fs::create_dir_all(&dir).unwrap();
+        let dir = dir.to_str().unwrap();
+        let mut limits = crate::config::Config::default().execution;
+        limits.jail = "namespace".to_string();
+        if super::run("true", &[], dir, &limits).exit_code != 0 {
+            println!("User namespaces are not available, skipped");
+            return;
+        }
+
+        let script = "touch inside && touch ../outside";
+        let execution = super::run("sh", &["-c", script], dir, &limits);
+        assert_ne!(execution.exit_code, 0);
+        assert!(execution.stderr.contains("Read-only file system"));
+        assert!(root.join("project/inside").exists());
+        assert!(!root.join("outside").exists());
+
+        let mountinfo = "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+                         25 22 0:5 / /mnt/my\\040disk rw,nosuid,nodev - tmpfs tmpfs rw\n";
+        assert_eq!(
+            super::namespace::mount_points(mountinfo),
+            vec![
+                ("/".to_string(), libc::MS_RELATIME),
+                ("/mnt/my disk".to_string(), libc::MS_NOSUID | libc::MS_NODEV)
+            ]
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
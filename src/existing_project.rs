@@ -0,0 +1,249 @@
+use crate::build_tool::{self, build_tool_in_dir, copy_project, create_application};
+use crate::cache::Cache;
+use crate::config::{Config, ExecutionConfig};
+use crate::language_driver::{driver_for, LanguageDriver};
+use crate::llm_api::LLMApi;
+use crate::llm_prompt::Prompt;
+use crate::llm_response::{Application, LLMResponse};
+use crate::runs::Run;
+use crate::{Lang, VERBOSE};
+
+/// Source files listed to the LLM at most, larger projects are cut.
+const MAX_TREE_FILES: usize = 300;
+
+/// Adds a function and its tests to the module `module` of the existing
+/// project in `project_dir`. The project is copied into the sandbox folder,
+/// built and tested there, and the new and changed files are written back
+/// to the project only once everything passes.
+#[allow(clippy::too_many_arguments)]
+pub fn run_existing_project_state_machine(
+    lang: &Lang,
+    question: &str,
+    prompt: &Prompt,
+    cache: &mut Cache,
+    llm: &LLMApi,
+    config: &Config,
+    project_dir: &str,
+    module: &str,
+) -> Result<(), String> {
+    let driver = driver_for(lang, config);
+    let scratch_dir = config.sandbox_dir.as_str();
+    println!("Copy {} into {}", project_dir, scratch_dir);
+    let (project_files, project_hash) = copy_project(project_dir, scratch_dir)?;
+    let scratch = scratch_in_project(project_dir, scratch_dir);
+
+    // the changes of the LLM on top of the copied project
+    let mut changes = Application {
+        tree: project_files
+            .iter()
+            .take(MAX_TREE_FILES)
+            .map(|path| (path.clone(), String::new()))
+            .collect(),
+        ..Default::default()
+    };
+    let tree = changes.tree_listing();
+    let module_content =
+        std::fs::read_to_string(format!("{}/{}", project_dir, module)).unwrap_or_default();
+    let manifest = driver
+        .manifest()
+        .and_then(|path| {
+            std::fs::read_to_string(format!("{}/{}", project_dir, path))
+                .ok()
+                .map(|content| format!("**{}**\n```\n{}\n```", path, content))
+        })
+        .unwrap_or_else(|| "None".to_string());
+
+    let mut run = Run::start(config, "into", lang, question, llm, project_dir);
+    run.next_attempt();
+    let result = match run.request(
+        llm,
+        "generate_into",
+        &[
+            question.to_string(),
+            module.to_string(),
+            tree.clone(),
+            module_content,
+            manifest,
+        ],
+        cache,
+        prompt,
+        &[],
+    ) {
+        Ok(result) => result,
+        Err(err) => {
+            println!("The project is not changed");
+            run.finish(false);
+            return Err(err);
+        }
+    };
+    let mut install_command =
+        apply_response(driver.as_ref(), &mut changes, &result, scratch.as_deref());
+    create_application(scratch_dir, &changes);
+    let (mut build_res, mut test_res) = build_and_test(
+        driver.as_ref(),
+        scratch_dir,
+        &install_command,
+        &changes,
+        &project_hash,
+        &mut run,
+        &config.execution,
+        cache,
+    );
+
+    let mut number_of_attempts = 0;
+    while !(build_res.0 && test_res.0) {
+        if number_of_attempts > config.max_attempts {
+            println!("To many attempts");
+            println!(
+                "The project is not changed, the last attempt is kept in {}",
+                scratch_dir
+            );
+            run.finish(false);
+            return Ok(());
+        }
+        number_of_attempts += 1;
+        let build_command = if changes.build_command.is_empty() {
+            install_command.clone()
+        } else {
+            changes.build_command.clone()
+        };
+        run.next_attempt();
+        let result = match run.request(
+            llm,
+            "rewrite_into",
+            &[
+                tree.clone(),
+                module.to_string(),
+                build_command,
+                build_res.1.clone(),
+                changes.test_command.clone(),
+                test_res.1.clone(),
+                question.to_string(),
+                changes.files_listing(""),
+            ],
+            cache,
+            prompt,
+            &[],
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                println!("The project is not changed");
+                run.finish(false);
+                return Err(err);
+            }
+        };
+        install_command =
+            apply_response(driver.as_ref(), &mut changes, &result, scratch.as_deref());
+        create_application(scratch_dir, &changes);
+        (build_res, test_res) = build_and_test(
+            driver.as_ref(),
+            scratch_dir,
+            &install_command,
+            &changes,
+            &project_hash,
+            &mut run,
+            &config.execution,
+            cache,
+        );
+    }
+
+    // the project may have been edited while the copy was built and tested
+    if build_tool::project_hash(project_dir, scratch_dir)? != project_hash {
+        println!(
+            "The project is not changed, the passing attempt is kept in {}",
+            scratch_dir
+        );
+        run.finish(false);
+        return Err(format!(
+            "\"{}\" changed since it was copied into \"{}\"",
+            project_dir, scratch_dir
+        ));
+    }
+    for (path, _) in &changes.files {
+        println!("Update: {}/{}", project_dir, path);
+    }
+    create_application(project_dir, &changes);
+    run.finish(true);
+    Ok(())
+}
+
+/// Folder of `scratch_dir` relative to the project when the scratch copy is
+/// made inside the project.
+fn scratch_in_project(project_dir: &str, scratch_dir: &str) -> Option<String> {
+    let project = std::fs::canonicalize(project_dir).ok()?;
+    let scratch = std::fs::canonicalize(scratch_dir).ok()?;
+    let relative = scratch.strip_prefix(&project).ok()?;
+    Some(relative.to_string_lossy().replace('\\', "/"))
+}
+
+/// Takes the files and the commands of the response, returns the install
+/// command. Files inside the `scratch` folder of the project are skipped.
+fn apply_response(
+    driver: &dyn LanguageDriver,
+    changes: &mut Application,
+    response: &str,
+    scratch: Option<&str>,
+) -> String {
+    let files = LLMResponse::parse_files(response);
+    if files.is_empty() {
+        println!("Warning: LLM did not write any file");
+    }
+    for (path, content) in files {
+        if scratch.is_some_and(|scratch| std::path::Path::new(&path).starts_with(scratch)) {
+            println!("Warning: \"{}\" is in the scratch folder, skipped", path);
+            continue;
+        }
+        changes.set_file(&path, content);
+    }
+    let [install, build, test] = driver.parse_commands(response);
+    changes.build_command = build;
+    changes.test_command = test;
+    if *VERBOSE.lock().unwrap() {
+        println!("{:#?}", changes);
+    }
+    install
+}
+
+/// Installs, builds and tests the scratch copy. A failed install is reported
+/// as a failed build.
+#[allow(clippy::too_many_arguments)]
+fn build_and_test(
+    driver: &dyn LanguageDriver,
+    scratch_dir: &str,
+    install_command: &str,
+    changes: &Application,
+    project_hash: &str,
+    run: &mut Run,
+    limits: &ExecutionConfig,
+    cache: &mut Cache,
+) -> ((bool, String), (bool, String)) {
+    run.files(&changes.files);
+    let src = format!("{}\n{}", project_hash, changes.files_listing(""));
+    let build_command = format!("{}\n{}", install_command, changes.build_command);
+    let build_res = run.step("build", build_command.trim(), || {
+        let install_res =
+            build_tool_in_dir(driver, scratch_dir, install_command, &src, limits, cache);
+        if !install_res.0 {
+            return install_res;
+        }
+        build_tool_in_dir(
+            driver,
+            scratch_dir,
+            &changes.build_command,
+            &src,
+            limits,
+            cache,
+        )
+    });
+    let test_res = run.step("test", &changes.test_command, || {
+        build_tool_in_dir(
+            driver,
+            scratch_dir,
+            &changes.test_command,
+            &src,
+            limits,
+            cache,
+        )
+    });
+    (build_res, test_res)
+}
@@ -1,33 +1,173 @@
-pub fn explore_files(
-    path: &str,
-    include_ext: &Vec<String>,
-    exclude_path: &Vec<String>,
-) -> Vec<String> {
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use std::io::Read;
+use std::path::Path;
+
+/// Project specific ignore file, same syntax as `.gitignore`.
+pub const IGNORE_FILE: &str = ".rustsnignore";
+
+/// Bytes read from the start of a file to tell text from binary files.
+const BINARY_CHECK_BYTES: usize = 8000;
+
+/// Which source files of a project are explored.
+pub struct ExploreOptions {
+    /// Extensions of the source files, without the dot.
+    pub extensions: Vec<String>,
+    /// Names of build output and dependency folders, e.g. "target" or "node_modules".
+    pub exclude_dirs: Vec<String>,
+    /// Globs on the path relative to the project, only matching files are explored when not empty.
+    pub include: Vec<String>,
+    /// Globs on the path relative to the project of files and folders to skip.
+    pub exclude: Vec<String>,
+    /// Larger files are skipped.
+    pub max_file_size: u64,
+}
+
+/// Source files of the project in `path`. Files ignored by `.gitignore`,
+/// `.ignore` or `.rustsnignore`, hidden files, binary files and files larger
+/// than the size cap are skipped. Symbolic links are followed, loops and
+/// unreadable entries are reported as warnings and skipped.
+pub fn explore_files(path: &str, options: &ExploreOptions) -> Result<Vec<String>, String> {
+    if !Path::new(path).is_dir() {
+        return Err(format!("\"{}\" is not a folder", path));
+    }
+    let include = glob_set(&options.include)?;
+    let exclude = glob_set(&options.exclude)?;
+    let root = Path::new(path).to_path_buf();
+    let exclude_dirs = options.exclude_dirs.clone();
+    let filter_root = root.clone();
+
+    let walker = WalkBuilder::new(path)
+        .follow_links(true)
+        .require_git(false)
+        .add_custom_ignore_filename(IGNORE_FILE)
+        .filter_entry(move |entry| {
+            let relative = entry
+                .path()
+                .strip_prefix(&filter_root)
+                .unwrap_or(entry.path());
+            if entry.file_type().map(|t| t.is_dir()) == Some(true)
+                && exclude_dirs
+                    .iter()
+                    .any(|dir| entry.file_name().to_string_lossy() == *dir)
+            {
+                return false;
+            }
+            !exclude.is_match(relative)
+        })
+        .build();
+
     let mut files = Vec::new();
-    let paths = std::fs::read_dir(path).unwrap();
-    for path in paths {
-        let path = path.unwrap().path();
-        if path.is_dir() {
-            files.append(&mut explore_files(
-                path.to_str().unwrap(),
-                include_ext,
-                exclude_path,
-            ));
-        } else {
-            let path = path.to_str().unwrap();
-            if include_ext.iter().any(|ext| {
-                let file_ext = format!(".{}", ext);
-                path.ends_with(file_ext.as_str())
-            }) {
-                if exclude_path.iter().any(|exclude| {
-                    // TODO: generate full path not only folder name
-                    path.contains(exclude)
-                }) {
-                    continue;
-                }
-                files.push(path.to_string());
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                println!("Warning: {}, skipped", err);
+                continue;
             }
+        };
+        if entry.file_type().map(|t| t.is_file()) != Some(true) {
+            continue;
+        }
+        let file = entry.path();
+        let extension = file
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !options.extensions.contains(&extension) {
+            continue;
+        }
+        let relative = file.strip_prefix(&root).unwrap_or(file);
+        if !options.include.is_empty() && !include.is_match(relative) {
+            continue;
+        }
+        match is_text_file(file, options.max_file_size) {
+            Ok(true) => files.push(file.to_string_lossy().to_string()),
+            Ok(false) => {}
+            Err(err) => println!(
+                "Warning: Cant read \"{}\": {}, skipped",
+                file.display(),
+                err
+            ),
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn glob_set(globs: &[String]) -> Result<GlobSet, String> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob).map_err(|err| format!("Invalid glob \"{}\": {}", glob, err))?);
+    }
+    builder.build().map_err(|err| err.to_string())
+}
+
+/// False for files above `max_size` bytes and files with NUL bytes at the start.
+fn is_text_file(file: &Path, max_size: u64) -> std::io::Result<bool> {
+    if std::fs::metadata(file)?.len() > max_size {
+        return Ok(false);
+    }
+    let mut head = Vec::with_capacity(BINARY_CHECK_BYTES);
+    std::fs::File::open(file)?
+        .take(BINARY_CHECK_BYTES as u64)
+        .read_to_end(&mut head)?;
+    Ok(!head.contains(&0))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_explore_files() {
+        let root = std::env::temp_dir().join("rustsn_test_explore_files");
+        let _ = std::fs::remove_dir_all(&root);
+        for dir in ["src/generated", "target/debug", "examples"] {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in [
+            "src/main.rs",
+            "src/target_utils.rs",
+            "src/generated/api.rs",
+            "target/debug/build.rs",
+            "examples/demo.rs",
+            "notes.txt",
+        ] {
+            std::fs::write(root.join(file), "fn main() {}").unwrap();
         }
+        std::fs::write(root.join("src/big.rs"), "x".repeat(2000)).unwrap();
+        std::fs::write(root.join("src/blob.rs"), b"fn\0\x01\x02").unwrap();
+        std::fs::write(root.join(".gitignore"), "generated/\n").unwrap();
+        std::fs::write(root.join(super::IGNORE_FILE), "examples/\n").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&root, root.join("src/loop")).unwrap();
+
+        let root_str = root.to_str().unwrap();
+        let mut options = super::ExploreOptions {
+            extensions: vec!["rs".to_string()],
+            exclude_dirs: vec!["target".to_string()],
+            include: Vec::new(),
+            exclude: Vec::new(),
+            max_file_size: 1000,
+        };
+        let relative = |files: Vec<String>| {
+            files
+                .iter()
+                .map(|file| file[root_str.len() + 1..].replace('\\', "/"))
+                .collect::<Vec<_>>()
+        };
+        let files = super::explore_files(root_str, &options).unwrap();
+        assert_eq!(relative(files), vec!["src/main.rs", "src/target_utils.rs"]);
+
+        options.exclude = vec!["**/main.rs".to_string()];
+        let files = super::explore_files(root_str, &options).unwrap();
+        assert_eq!(relative(files), vec!["src/target_utils.rs"]);
+
+        options.exclude = Vec::new();
+        options.include = vec!["src/main.*".to_string()];
+        let files = super::explore_files(root_str, &options).unwrap();
+        assert_eq!(relative(files), vec!["src/main.rs"]);
+
+        assert!(super::explore_files(&format!("{}/missing", root_str), &options).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
     }
-    files
 }
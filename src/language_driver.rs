@@ -0,0 +1,572 @@
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+use crate::config::Config;
+use crate::llm_response::{LLMResponse, Project};
+use crate::utils::remove_comments;
+use crate::Lang;
+
+/// Everything the generate -> build -> rewrite loop needs to know about a
+/// language. Languages are described by `prompt/<lang>.toml` files, see
+/// `LanguageDescriptor`.
+pub trait LanguageDriver {
+    /// Files of the sandbox project: path relative to the sandbox and content.
+    fn sandbox_layout(&self, project: &Project) -> Vec<(String, String)>;
+
+    /// Extracts the project from the LLM response.
+    fn parse_response(&self, response: &str) -> Project;
+
+    /// Parameters of the `rewrite` prompt in the order of its placeholders.
+    fn rewrite_params(
+        &self,
+        project: &Project,
+        build_res: &(bool, String),
+        test_res: &(bool, String),
+        question: &str,
+    ) -> Vec<String>;
+
+    /// Install, build and test commands of a response whose other sections
+    /// are files of an existing project, so only exact section names count.
+    fn parse_commands(&self, response: &str) -> [String; 3];
+
+    /// Path of the file with the dependencies of the project, e.g. "Cargo.toml".
+    fn manifest(&self) -> Option<String>;
+
+    /// Sandbox files which content makes the cache key of a build result.
+    fn cache_key_files(&self) -> Vec<String> {
+        self.sandbox_layout(&Project::default())
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect()
+    }
+
+    /// Headers of the LLM response sections the project is parsed from. The
+    /// streamed response is not read any further once all of them arrived.
+    fn expected_sections(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Executable to launch for the program named in a build or test command.
+    fn executable(&self, program: &str) -> String {
+        program.to_string()
+    }
+
+    /// Build or test command with the arguments making the tool print
+    /// diagnostics `diagnostics::Diagnostics` can parse.
+    fn diagnostic_command(&self, command: &[String]) -> Vec<String> {
+        command.to_vec()
+    }
+
+    /// Commands the LLM may run, e.g. "cargo test"; everything when empty.
+    fn allowed_commands(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Folders, relative to the project, the test tool writes JUnit XML reports into.
+    fn test_reports(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+pub fn driver_for(lang: &Lang, config: &Config) -> Box<dyn LanguageDriver> {
+    Box::new(LanguageDescriptor::for_lang(lang, config))
+}
+
+/// Declarative description of a language loaded from `prompt/<lang>.toml`.
+#[derive(Debug, Deserialize)]
+pub struct LanguageDescriptor {
+    /// Files of the sandbox project of the `generate function` command.
+    #[serde(default)]
+    pub files: Vec<FileDescriptor>,
+    #[serde(default)]
+    pub commands: CommandsDescriptor,
+    /// Placeholders of the `rewrite` prompt in order.
+    #[serde(default)]
+    pub rewrite_params: Vec<RewriteParam>,
+    /// Extension of the script launching build tools on Windows, e.g. "cmd".
+    pub windows_extension: Option<String>,
+    /// Regular expressions matching the first line of a function, impl, class
+    /// or method. The `ask` command splits source files on these lines.
+    #[serde(default)]
+    pub chunk_boundaries: Vec<String>,
+    /// Wrapper of the tests given by the user appended to the solution file
+    /// when the project has no test file; `{tests}` is replaced by the tests.
+    pub inline_tests: Option<String>,
+    /// Arguments added to the commands starting with the key, e.g.
+    /// `"cargo build" = "--message-format=json"`.
+    #[serde(default)]
+    pub diagnostic_args: BTreeMap<String, String>,
+    /// Folders of the JUnit XML reports of the test command.
+    #[serde(default)]
+    pub test_reports: Vec<String>,
+    /// Commands the LLM may run with any options and arguments, e.g.
+    /// "cargo test"; every command is allowed when empty.
+    #[serde(default)]
+    pub allowed_commands: Vec<String>,
+    pub ask: Option<AskDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FileDescriptor {
+    /// Path relative to the sandbox folder.
+    pub path: String,
+    /// Header of the LLM response section with the file content, the path by default.
+    pub section: Option<String>,
+    pub content: ProjectPart,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectPart {
+    Dependencies,
+    Config,
+    Solution,
+    Test,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CommandsDescriptor {
+    pub install: Option<CommandDescriptor>,
+    pub build: Option<CommandDescriptor>,
+    pub test: Option<CommandDescriptor>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CommandDescriptor {
+    /// Header of the LLM response section with the command.
+    pub section: Option<String>,
+    /// Command used when the LLM response has no such section.
+    pub default: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RewriteParam {
+    Dependencies,
+    Config,
+    Solution,
+    Test,
+    InstallCommand,
+    BuildCommand,
+    BuildOutput,
+    TestCommand,
+    TestOutput,
+    Question,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AskDescriptor {
+    pub extensions: Vec<String>,
+    #[serde(default)]
+    pub exclude_dirs: Vec<String>,
+    /// Prompt asking the LLM to explain a source file before its embedding is computed.
+    #[allow(dead_code)]
+    #[serde(default)]
+    pub explain_prompt: String,
+    pub answer_prompt: String,
+}
+
+impl AskDescriptor {
+    /// Adds the source files of `other` for projects mixing several languages.
+    pub fn merge(&mut self, other: &AskDescriptor) {
+        for extension in &other.extensions {
+            if !self.extensions.contains(extension) {
+                self.extensions.push(extension.clone());
+            }
+        }
+        for dir in &other.exclude_dirs {
+            if !self.exclude_dirs.contains(dir) {
+                self.exclude_dirs.push(dir.clone());
+            }
+        }
+    }
+}
+
+impl LanguageDescriptor {
+    pub fn load(file_name: &str) -> Result<LanguageDescriptor, String> {
+        let content = std::fs::read_to_string(file_name)
+            .map_err(|err| format!("Can't read \"{}\": {}", file_name, err))?;
+        toml::from_str(&content).map_err(|err| format!("Can't parse \"{}\": {}", file_name, err))
+    }
+
+    /// Descriptor of `lang` from the prompt folder with the commands of the
+    /// `[languages.<lang>]` config section taking precedence over the LLM ones.
+    pub fn for_lang(lang: &Lang, config: &Config) -> LanguageDescriptor {
+        let file_name = format!("{}/{}.toml", config.prompt_dir, lang);
+        let mut descriptor = LanguageDescriptor::load(&file_name).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        if let Some(commands) = config.language_commands(&lang.to_string()) {
+            descriptor
+                .allowed_commands
+                .extend(commands.allowed.iter().cloned());
+            for (command, configured) in [
+                (&mut descriptor.commands.install, &commands.install),
+                (&mut descriptor.commands.build, &commands.build),
+                (&mut descriptor.commands.test, &commands.test),
+            ] {
+                if let Some(configured) = configured {
+                    descriptor.allowed_commands.push(configured.clone());
+                    *command = Some(CommandDescriptor {
+                        section: None,
+                        default: Some(configured.clone()),
+                    });
+                }
+            }
+        }
+        descriptor
+    }
+
+    fn file_sections(&self) -> Vec<&str> {
+        self.files
+            .iter()
+            .map(|file| file.section.as_deref().unwrap_or(&file.path))
+            .collect()
+    }
+
+    fn command_sections(&self) -> [Option<&str>; 3] {
+        [
+            command_section(&self.commands.install),
+            command_section(&self.commands.build),
+            command_section(&self.commands.test),
+        ]
+    }
+
+    fn part<'a>(&self, project: &'a Project, part: ProjectPart, config_index: usize) -> &'a str {
+        match part {
+            ProjectPart::Dependencies => &project.dependencies,
+            ProjectPart::Config => project
+                .additional_config
+                .get(config_index)
+                .map(|config| config.as_str())
+                .unwrap_or_default(),
+            ProjectPart::Solution => &project.solution_code,
+            ProjectPart::Test => &project.test_code,
+        }
+    }
+}
+
+fn command_section(command: &Option<CommandDescriptor>) -> Option<&str> {
+    command
+        .as_ref()
+        .and_then(|command| command.section.as_deref())
+}
+
+fn command_or_default(command: &Option<CommandDescriptor>, content: Option<String>) -> String {
+    let command_str = content
+        .map(|content| remove_comments(&content))
+        .unwrap_or_default();
+    if !command_str.is_empty() {
+        return command_str;
+    }
+    command
+        .as_ref()
+        .and_then(|command| command.default.clone())
+        .unwrap_or_default()
+}
+
+impl LanguageDriver for LanguageDescriptor {
+    fn sandbox_layout(&self, project: &Project) -> Vec<(String, String)> {
+        let has_test_file = self
+            .files
+            .iter()
+            .any(|file| file.content == ProjectPart::Test);
+        let mut config_index = 0;
+        self.files
+            .iter()
+            .map(|file| {
+                let mut content = self.part(project, file.content, config_index).to_string();
+                if file.content == ProjectPart::Config {
+                    config_index += 1;
+                }
+                if let Some(wrapper) = &self.inline_tests {
+                    if file.content == ProjectPart::Solution
+                        && !has_test_file
+                        && !project.test_code.is_empty()
+                    {
+                        content.push_str("\n\n");
+                        content.push_str(&wrapper.replace("{tests}", &project.test_code));
+                    }
+                }
+                (file.path.clone(), content)
+            })
+            .collect()
+    }
+
+    fn parse_response(&self, response: &str) -> Project {
+        let command_sections = self.command_sections();
+        let mut names = self.file_sections();
+        names.extend(command_sections.iter().flatten());
+        let mut contents = LLMResponse::extract_sections(response, &names).into_iter();
+
+        let mut project = Project::default();
+        for file in &self.files {
+            let content = contents.next().unwrap_or_default();
+            match file.content {
+                ProjectPart::Dependencies => project.dependencies = content,
+                ProjectPart::Config => project.additional_config.push(content),
+                ProjectPart::Solution => project.solution_code = content,
+                ProjectPart::Test => project.test_code = content,
+            }
+        }
+        let mut command_content = |section: Option<&str>| section.and_then(|_| contents.next());
+        let install = command_content(command_sections[0]);
+        let build = command_content(command_sections[1]);
+        let test = command_content(command_sections[2]);
+        project.install_command = command_or_default(&self.commands.install, install);
+        project.build_command = command_or_default(&self.commands.build, build);
+        project.test_command = command_or_default(&self.commands.test, test);
+        project
+    }
+
+    fn parse_commands(&self, response: &str) -> [String; 3] {
+        let sections = LLMResponse::parse_sections(response);
+        let content = |command: &Option<CommandDescriptor>| {
+            let section = command_section(command)?;
+            sections
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(section))
+                .map(|(_, content)| content.clone())
+        };
+        [
+            command_or_default(&self.commands.install, content(&self.commands.install)),
+            command_or_default(&self.commands.build, content(&self.commands.build)),
+            command_or_default(&self.commands.test, content(&self.commands.test)),
+        ]
+    }
+
+    fn manifest(&self) -> Option<String> {
+        self.files
+            .iter()
+            .find(|file| file.content == ProjectPart::Dependencies)
+            .map(|file| file.path.clone())
+    }
+
+    fn rewrite_params(
+        &self,
+        project: &Project,
+        build_res: &(bool, String),
+        test_res: &(bool, String),
+        question: &str,
+    ) -> Vec<String> {
+        let mut config_index = 0;
+        self.rewrite_params
+            .iter()
+            .map(|param| match param {
+                RewriteParam::Dependencies => project.dependencies.clone(),
+                RewriteParam::Config => {
+                    config_index += 1;
+                    self.part(project, ProjectPart::Config, config_index - 1)
+                        .to_string()
+                }
+                RewriteParam::Solution => project.solution_code.clone(),
+                RewriteParam::Test => project.test_code.clone(),
+                RewriteParam::InstallCommand => project.install_command.clone(),
+                RewriteParam::BuildCommand => project.build_command.clone(),
+                RewriteParam::BuildOutput => build_res.1.clone(),
+                RewriteParam::TestCommand => project.test_command.clone(),
+                RewriteParam::TestOutput => test_res.1.clone(),
+                RewriteParam::Question => question.to_string(),
+            })
+            .collect()
+    }
+
+    fn expected_sections(&self) -> Vec<String> {
+        let mut names = self.file_sections();
+        names.extend(self.command_sections().iter().flatten());
+        names.into_iter().map(|name| name.to_string()).collect()
+    }
+
+    fn executable(&self, program: &str) -> String {
+        match &self.windows_extension {
+            Some(extension) if cfg!(target_os = "windows") => format!("{}.{}", program, extension),
+            _ => program.to_string(),
+        }
+    }
+
+    fn diagnostic_command(&self, command: &[String]) -> Vec<String> {
+        for (prefix, args) in &self.diagnostic_args {
+            let prefix = prefix.split_whitespace().collect::<Vec<_>>();
+            let args = args.split_whitespace().map(|arg| arg.to_string());
+            let matches = command.len() >= prefix.len()
+                && command
+                    .iter()
+                    .zip(&prefix)
+                    .all(|(word, expected)| word == expected);
+            if !matches || args.clone().all(|arg| command.contains(&arg)) {
+                continue;
+            }
+            // arguments after "--" belong to the test binary
+            let end = command
+                .iter()
+                .position(|word| word == "--")
+                .unwrap_or(command.len());
+            let mut with_args = command[..end].to_vec();
+            with_args.extend(args);
+            with_args.extend_from_slice(&command[end..]);
+            return with_args;
+        }
+        command.to_vec()
+    }
+
+    fn allowed_commands(&self) -> Vec<String> {
+        self.allowed_commands.clone()
+    }
+
+    fn test_reports(&self) -> Vec<String> {
+        self.test_reports.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_prompt::Prompt;
+
+    #[test]
+    fn test_rewrite_params_match_prompt() {
+        for lang in [
+            Lang::Rust,
+            Lang::Java,
+            Lang::Scala,
+            Lang::Swift,
+            Lang::Kotlin,
+            Lang::Python,
+            Lang::JavaScript,
+            Lang::TypeScript,
+            Lang::Php,
+        ] {
+            let driver = driver_for(&lang, &Config::default());
+            let prompt = Prompt::new(&format!("prompt/{}.txt", lang));
+            let project = Project {
+                additional_config: vec![String::new()],
+                ..Default::default()
+            };
+            let params = driver
+                .rewrite_params(
+                    &project,
+                    &(false, String::new()),
+                    &(false, String::new()),
+                    "",
+                )
+                .iter()
+                .enumerate()
+                .map(|(i, _)| format!("<param {}>", i))
+                .collect::<Vec<_>>();
+            let rewrite = prompt.create("rewrite", &params);
+            for param in &params {
+                assert!(rewrite.contains(param), "{} {} is unused", lang, param);
+            }
+        }
+    }
+
+    #[test]
+    fn test_inline_tests_are_appended_to_solution() {
+        let driver = driver_for(&Lang::Rust, &Config::default());
+        let mut project = Project {
+            solution_code: "pub fn solution() {}".to_string(),
+            ..Default::default()
+        };
+        let lib = |layout: Vec<(String, String)>| {
+            layout
+                .into_iter()
+                .find(|(path, _)| path == "src/lib.rs")
+                .unwrap()
+                .1
+        };
+        assert_eq!(lib(driver.sandbox_layout(&project)), "pub fn solution() {}");
+
+        project.test_code = "#[test]\nfn user_test() {}".to_string();
+        let content = lib(driver.sandbox_layout(&project));
+        assert!(content.starts_with("pub fn solution() {}\n\n#[cfg(test)]\nmod user_tests {"));
+        assert!(content.contains("fn user_test() {}"));
+    }
+
+    #[test]
+    fn test_diagnostic_command() {
+        let driver = driver_for(&Lang::Rust, &Config::default());
+        let command = |text: &str| {
+            let words = text.split_whitespace().map(|word| word.to_string());
+            driver
+                .diagnostic_command(&words.collect::<Vec<_>>())
+                .join(" ")
+        };
+        assert_eq!(
+            command("cargo build --tests"),
+            "cargo build --tests --message-format=json"
+        );
+        assert_eq!(
+            command("cargo test -- --nocapture"),
+            "cargo test --message-format=json -- --nocapture"
+        );
+        assert_eq!(command("cargo fmt"), "cargo fmt");
+        assert_eq!(command("cargo testing"), "cargo testing");
+    }
+
+    #[test]
+    fn test_default_commands_are_allowed() {
+        for entry in std::fs::read_dir("prompt").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map(|ext| ext == "toml") != Some(true) {
+                continue;
+            }
+            let descriptor = LanguageDescriptor::load(path.to_str().unwrap()).unwrap();
+            let commands = &descriptor.commands;
+            for command in [&commands.install, &commands.build, &commands.test] {
+                let Some(default) = command.as_ref().and_then(|c| c.default.as_ref()) else {
+                    continue;
+                };
+                for words in crate::command_line::parse(default).unwrap() {
+                    assert!(
+                        crate::command_line::is_allowed(&words, &descriptor.allowed_commands),
+                        "{:?}: {}",
+                        path,
+                        default
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_parse_existing_project_response() {
+        let driver = driver_for(&Lang::Java, &Config::default());
+        let response = "**src/test/java/app/UtilsTest.java**\n```java\nclass UtilsTest {}\n```\n\n**Test**\n```bash\nmvn -q test\n```\n";
+        assert_eq!(
+            driver.parse_commands(response),
+            [
+                "".to_string(),
+                "mvn compile".to_string(),
+                "mvn -q test".to_string()
+            ]
+        );
+        assert_eq!(
+            LLMResponse::parse_files(response),
+            vec![(
+                "src/test/java/app/UtilsTest.java".to_string(),
+                "class UtilsTest {}\n".to_string()
+            )]
+        );
+        assert_eq!(driver.manifest().as_deref(), Some("pom.xml"));
+    }
+
+    #[test]
+    fn test_chunk_boundaries_are_valid() {
+        for entry in std::fs::read_dir("prompt").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().map(|ext| ext == "toml") != Some(true) {
+                continue;
+            }
+            let descriptor = LanguageDescriptor::load(path.to_str().unwrap()).unwrap();
+            for pattern in &descriptor.chunk_boundaries {
+                assert!(
+                    regex::Regex::new(pattern).is_ok(),
+                    "{:?}: {}",
+                    path,
+                    pattern
+                );
+            }
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use crate::cache::Cache;
+use crate::cache::{Cache, Namespace};
+use crate::cassette::{Cassette, Interaction, CHAT_TEMPLATE, EMBEDDING_TEMPLATE, PROMPT_TEMPLATE};
 use crate::llm_prompt::Prompt;
-use crate::{OLLAMA_API, OLLAMA_EMB, VERBOSE};
+use crate::llm_response::LLMResponse;
+use crate::VERBOSE;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
+use std::io::{BufRead, BufReader, Write};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::time::Duration;
 
 // const STOP_WORDS: &[&str] = &[
@@ -12,35 +16,131 @@ use std::time::Duration;
 //     "**Additional Notes",
 // ];
 const STOP_WORDS: &[&str] = &[];
-const MAX_TOKENS: i32 = 1000;
 pub struct LLMApi {
     model_type: ModelType,
+    temperature: f32,
+    max_tokens: i32,
+    /// Sampling seed, different seeds give different responses to the same prompt.
+    seed: Option<u64>,
+    /// Cassette the traffic is recorded into or replayed from.
+    cassette: Option<Arc<Mutex<Cassette>>>,
+    /// Time limit of a request, reading the whole response included.
+    timeout: Duration,
+    /// Print the streamed tokens as they arrive.
+    echo: bool,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum ModelType {
-    Ollama { model: String, emb: String },
-    OpenAI { api_key: String },
+    Ollama {
+        url: String,
+        model: String,
+        emb: String,
+    },
+    /// Any server implementing the OpenAI chat completions and embeddings API.
+    OpenAI {
+        api_key: Option<String>,
+        base_url: String,
+        model: String,
+        emb: String,
+    },
+}
+
+/// Prompt sent for `prompt_template`: the template filled with `params`, or
+/// `prompt_template` itself as a ready prompt when there are no params.
+pub fn prompt_text(prompt_template: &str, params: &[String], prompt: &Prompt) -> String {
+    if !params.is_empty() {
+        prompt.create(prompt_template, params)
+    } else {
+        prompt_template.to_string()
+    }
 }
 
 impl LLMApi {
-    pub fn new(model_type: ModelType) -> LLMApi {
-        LLMApi { model_type }
+    pub fn new(model_type: ModelType, temperature: f32, max_tokens: i32) -> LLMApi {
+        let timeout = match model_type {
+            ModelType::Ollama { .. } => Duration::from_secs(60 * 10),
+            ModelType::OpenAI { .. } => Duration::from_secs(60 * 5),
+        };
+        LLMApi {
+            model_type,
+            temperature,
+            max_tokens,
+            seed: None,
+            cassette: None,
+            timeout,
+            echo: true,
+        }
+    }
+
+    /// Records the traffic into `cassette`, or serves the responses from it
+    /// without any request to the backend when it is replayed.
+    pub fn with_cassette(mut self, cassette: Cassette) -> LLMApi {
+        self.cassette = Some(Arc::new(Mutex::new(cassette)));
+        self
+    }
+
+    /// Requests taking longer than `timeout` fail.
+    pub fn with_timeout(mut self, timeout: Duration) -> LLMApi {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Same model sampled with `seed`, responses are cached per seed.
+    pub fn with_seed(&self, seed: u64) -> LLMApi {
+        LLMApi {
+            model_type: self.model_type.clone(),
+            temperature: self.temperature,
+            max_tokens: self.max_tokens,
+            seed: Some(seed),
+            cassette: self.cassette.clone(),
+            timeout: self.timeout,
+            echo: self.echo,
+        }
+    }
+
+    /// Without `echo` the streamed tokens are not printed, e.g. when several
+    /// requests stream at the same time.
+    pub fn with_echo(mut self, echo: bool) -> LLMApi {
+        self.echo = echo;
+        self
     }
 
     pub fn request(
         &self,
         prompt_template: &str,
-        params: &Vec<String>,
+        params: &[String],
         cache: &mut Cache,
         prompt: &Prompt,
-    ) -> String {
-        let prompt = if params.len() > 0 {
-            prompt.create(prompt_template, params)
+    ) -> Result<String, String> {
+        self.request_sections(prompt_template, params, cache, prompt, &[])
+    }
+
+    /// Same as `request`, but stops reading the streamed response as soon as
+    /// every section named in `expected_sections` has been received.
+    pub fn request_sections(
+        &self,
+        prompt_template: &str,
+        params: &[String],
+        cache: &mut Cache,
+        prompt: &Prompt,
+        expected_sections: &[String],
+    ) -> Result<String, String> {
+        let prompt = prompt_text(prompt_template, params, prompt);
+        // requests with a ready prompt are recorded without a template name
+        let template = if params.is_empty() {
+            PROMPT_TEMPLATE
         } else {
-            prompt_template.to_string()
+            prompt_template
         };
-        match &self.model_type {
+        if let Some(mut cassette) = self.replaying() {
+            println!("LLM Request replayed from the cassette");
+            return cassette.completion(template, &prompt);
+        }
+        let is_complete = |response: &str| {
+            !expected_sections.is_empty() && LLMResponse::has_sections(response, expected_sections)
+        };
+        let response = match &self.model_type {
             ModelType::Ollama { model, .. } => {
                 let stop = STOP_WORDS;
                 let request = OllamaRequest {
@@ -50,38 +150,38 @@ impl LLMApi {
                     // model: "gemma2:2b".to_string(), // fast but very stupid model - excellent for fast testing
                     //  model: "gemma2".to_string(), // medium model
                     prompt: prompt.to_string(),
-                    stream: false,
+                    stream: true,
                     options: OllamaOptions {
-                        num_predict: MAX_TOKENS,
+                        num_predict: self.max_tokens,
+                        temperature: self.temperature,
                         stop: stop.iter().map(|s| s.to_string()).collect(),
+                        seed: self.seed,
                     },
                 };
 
-                let request_str = serde_json::to_string(&request).unwrap();
+                let key = self.cache_key("generate", &request);
                 if *VERBOSE.lock().unwrap() {
                     println!("Request: {}", request.prompt);
                 }
 
-                let response_opt = cache.get(&request_str);
+                let response_opt = cache.get(Namespace::Llm, &key);
                 let response = match response_opt {
                     None => {
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 10))
-                            .build()
-                            .unwrap();
                         println!("Request to LLM in progress");
-
-                        let response = client.post(OLLAMA_API).json(&request).send().unwrap();
-                        if !response.status().is_success() {
-                            let response_text = response.text().unwrap();
-                            println!("Response: {:?}", response_text);
-                            panic!("Failed to get response from LLM");
-                        }
-                        let response_text = response.text().unwrap();
-                        let response =
-                            serde_json::from_str::<OllamaResponse>(&response_text).unwrap();
-                        cache.set(request_str.clone(), response.response.clone());
-                        response.response
+                        let response = self
+                            .client()?
+                            .post(self.endpoint("generate"))
+                            .json(&request)
+                            .send();
+                        let response = checked(response)?;
+                        let response = read_stream(
+                            BufReader::new(response),
+                            self.echo,
+                            parse_ollama_line,
+                            is_complete,
+                        )?;
+                        cache.set(Namespace::Llm, model, &key, &response);
+                        response
                     }
                     Some(result) => {
                         println!("LLM Request already cached");
@@ -94,53 +194,58 @@ impl LLMApi {
                 }
                 response
             }
-            ModelType::OpenAI { api_key } => {
-                let messages = vec![ChatMessage {
-                    role: "user".to_string(),
-                    content: prompt.to_string(),
-                }];
-
-                let request = OpenAIChatRequest {
-                    model: "gpt-4o-2024-08-06".to_string(),
-                    messages,
-                    max_tokens: MAX_TOKENS,
-                    temperature: 0.7,
-                    stop: Some(STOP_WORDS.iter().map(|s| s.to_string()).collect()),
+            ModelType::OpenAI { .. } => {
+                let messages = vec![ChatMessage::new("user", &prompt)];
+                self.openai_chat(messages, cache, is_complete)?
+            }
+        };
+        self.record(template, &prompt, &response, Vec::new());
+        Ok(response)
+    }
+
+    /// Multi-turn request: the reply of the assistant to the conversation in `messages`.
+    pub fn chat(&self, messages: &[ChatMessage], cache: &mut Cache) -> Result<String, String> {
+        let conversation = serde_json::to_string(messages).unwrap();
+        if let Some(mut cassette) = self.replaying() {
+            println!("LLM Request replayed from the cassette");
+            return cassette.completion(CHAT_TEMPLATE, &conversation);
+        }
+        let response = match &self.model_type {
+            ModelType::Ollama { model, .. } => {
+                let request = OllamaChatRequest {
+                    model: model.to_string(),
+                    messages: messages.to_vec(),
+                    stream: true,
+                    options: OllamaOptions {
+                        num_predict: self.max_tokens,
+                        temperature: self.temperature,
+                        stop: STOP_WORDS.iter().map(|s| s.to_string()).collect(),
+                        seed: self.seed,
+                    },
                 };
 
-                let request_str = serde_json::to_string(&request).unwrap();
+                let key = self.cache_key("chat", &request);
                 if *VERBOSE.lock().unwrap() {
-                    println!("Request: {}", prompt);
+                    println!("Request: {:#?}", request.messages);
                 }
 
-                let response_opt = cache.get(&request_str);
-                let response = match response_opt {
+                let response = match cache.get(Namespace::Llm, &key) {
                     None => {
                         println!("Request to LLM in progress");
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 5))
-                            .build()
-                            .unwrap();
-
-                        let response = client
-                            .post("https://api.openai.com/v1/chat/completions")
-                            .bearer_auth(api_key)
+                        let response = self
+                            .client()?
+                            .post(self.endpoint("chat"))
                             .json(&request)
-                            .send()
-                            .unwrap()
-                            .json::<OpenAIChatResponse>()
-                            .unwrap();
-
-                        // Extract the assistant's reply from the first choice
-                        let openai_response = response
-                            .choices
-                            .into_iter()
-                            .next()
-                            .map(|choice| choice.message.content)
-                            .unwrap_or_default();
-
-                        cache.set(request_str.clone(), openai_response.clone());
-                        openai_response
+                            .send();
+                        let response = checked(response)?;
+                        let response = read_stream(
+                            BufReader::new(response),
+                            self.echo,
+                            parse_ollama_chat_line,
+                            |_| false,
+                        )?;
+                        cache.set(Namespace::Llm, model, &key, &response);
+                        response
                     }
                     Some(result) => {
                         println!("LLM Request already cached");
@@ -149,117 +254,232 @@ impl LLMApi {
                 };
 
                 if *VERBOSE.lock().unwrap() {
-                    println!("OpenAI Chat Response: {}", response);
+                    println!("Response: {}", response);
                 }
                 response
             }
+            ModelType::OpenAI { .. } => self.openai_chat(messages.to_vec(), cache, |_| false)?,
+        };
+        self.record(CHAT_TEMPLATE, &conversation, &response, Vec::new());
+        Ok(response)
+    }
+
+    fn openai_chat(
+        &self,
+        messages: Vec<ChatMessage>,
+        cache: &mut Cache,
+        is_complete: impl Fn(&str) -> bool,
+    ) -> Result<String, String> {
+        let ModelType::OpenAI {
+            api_key,
+            base_url,
+            model,
+            ..
+        } = &self.model_type
+        else {
+            unreachable!("OpenAI chat requested for {:?}", self.model_type);
+        };
+        let request = OpenAIChatRequest {
+            model: model.to_string(),
+            messages,
+            max_tokens: self.max_tokens,
+            temperature: self.temperature,
+            stop: Some(STOP_WORDS.iter().map(|s| s.to_string()).collect()),
+            stream: true,
+            seed: self.seed,
+        };
+
+        let key = self.cache_key("chat/completions", &request);
+        if *VERBOSE.lock().unwrap() {
+            println!("Request: {:#?}", request.messages);
         }
+
+        let response_opt = cache.get(Namespace::Llm, &key);
+        let response = match response_opt {
+            None => {
+                println!("Request to LLM in progress");
+                let response = openai_post(&self.client()?, base_url, "chat/completions", api_key)
+                    .json(&request)
+                    .send();
+                let response = checked(response)?;
+
+                let openai_response = read_stream(
+                    BufReader::new(response),
+                    self.echo,
+                    parse_openai_line,
+                    is_complete,
+                )?;
+                cache.set(Namespace::Llm, model, &key, &openai_response);
+                openai_response
+            }
+            Some(result) => {
+                println!("LLM Request already cached");
+                result.to_string()
+            }
+        };
+
+        if *VERBOSE.lock().unwrap() {
+            println!("OpenAI Chat Response: {}", response);
+        }
+        Ok(response)
     }
-    pub fn emb(&self, content: &str, cache: &mut Cache, full_content: &str) -> Vec<f32> {
-        match &self.model_type {
-            ModelType::Ollama { emb, .. } => {
-                let request = OllamaEmbRequest {
+
+    /// Embedding of `text`, cached.
+    pub fn emb(&self, text: &str, cache: &mut Cache) -> Result<Vec<f32>, String> {
+        if self.replaying().is_some() {
+            return self.embed(text);
+        }
+        let key = match &self.model_type {
+            ModelType::Ollama { emb, .. } => self.cache_key(
+                "embeddings",
+                &OllamaEmbRequest {
                     model: emb.to_string(),
-                    prompt: full_content.to_string(),
-                };
+                    prompt: text.to_string(),
+                },
+            ),
+            ModelType::OpenAI { emb, .. } => self.cache_key(
+                "embeddings",
+                &OpenAIEmbRequest {
+                    model: emb.to_string(),
+                    input: text.to_string(),
+                },
+            ),
+        };
+        if let Some(embedding) = cache.get_embedding(&key) {
+            println!("Embedding Request already cached");
+            self.record(EMBEDDING_TEMPLATE, text, "", embedding.clone());
+            return Ok(embedding);
+        }
+        let embedding = self.embed(text)?;
+        cache.set_embedding(self.emb_model(), &key, &embedding);
+        Ok(embedding)
+    }
 
-                let request_str = serde_json::to_string(&content).unwrap();
-                let response_opt = cache.get(&request_str);
-                let response = match response_opt {
-                    None => {
-                        println!("Request to Ollama Embeddings API in progress");
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 10))
-                            .build()
-                            .unwrap();
-                        // let response = client
-                        //     .post(OLLAMA_EMB)
-                        //     .json(&request)
-                        //     .send()
-                        //     .unwrap()
-                        //     .json::<OllamaEmbResponse>()
-                        //     .unwrap();
-                        //
-                        let response_str = client
-                            .post(OLLAMA_EMB)
-                            .json(&request)
-                            .send()
-                            .unwrap()
-                            .text()
-                            .unwrap();
-                        // println!("Response: {}", response_str);
-                        let response: OllamaEmbResponse =
-                            serde_json::from_str(&response_str).unwrap();
-                        cache.set(
-                            request_str.clone(),
-                            serde_json::to_string(&response.embedding).unwrap(),
-                        );
-                        response.embedding
-                    }
-                    Some(result) => {
-                        println!("Embedding Request already cached");
-                        serde_json::from_str(&result).unwrap()
-                    }
-                };
-                response
+    /// URL of the API `path`, e.g. "generate" or "chat/completions".
+    fn endpoint(&self, path: &str) -> String {
+        match &self.model_type {
+            ModelType::Ollama { url, .. } => format!("{}/api/{}", url.trim_end_matches('/'), path),
+            ModelType::OpenAI { base_url, .. } => {
+                format!("{}/{}", base_url.trim_end_matches('/'), path)
             }
-            ModelType::OpenAI { api_key } => {
-                let request = OpenAIEmbRequest {
-                    model: "text-embedding-ada-002".to_string(),
-                    input: full_content.to_string(),
-                };
-
-                let request_str = serde_json::to_string(&request).unwrap();
+        }
+    }
 
-                let response_opt = cache.get(&content);
+    /// Cache key of a request: the backend, the endpoint and the request with
+    /// the model, the parameters and the content. The API key is left out.
+    fn cache_key(&self, path: &str, request: &impl Serialize) -> String {
+        let backend = match &self.model_type {
+            ModelType::Ollama { .. } => "ollama",
+            ModelType::OpenAI { .. } => "openai",
+        };
+        serde_json::json!({
+            "backend": backend,
+            "endpoint": self.endpoint(path),
+            "request": request,
+        })
+        .to_string()
+    }
 
-                let response = match response_opt {
-                    None => {
-                        let client = Client::builder()
-                            .timeout(Duration::from_secs(60 * 5))
-                            .build()
-                            .unwrap();
+    /// Name of the model answering the prompts.
+    pub fn model(&self) -> &str {
+        match &self.model_type {
+            ModelType::Ollama { model, .. } => model,
+            ModelType::OpenAI { model, .. } => model,
+        }
+    }
 
-                        println!("Request to OpenAI Embeddings API in progress");
+    /// Name of the embedding model, vectors of different models are not comparable.
+    pub fn emb_model(&self) -> &str {
+        match &self.model_type {
+            ModelType::Ollama { emb, .. } => emb,
+            ModelType::OpenAI { emb, .. } => emb,
+        }
+    }
 
-                        let api_response = match client
-                            .post("https://api.openai.com/v1/embeddings")
-                            .bearer_auth(api_key)
-                            .json(&request)
-                            .send()
-                        {
-                            Ok(resp) => resp,
-                            Err(e) => {
-                                eprintln!("Network error: {}", e);
-                                return vec![];
-                            }
-                        };
-
-                        let api_response = match api_response.json::<OpenAIEmbResponse>() {
-                            Ok(json) => json,
-                            Err(e) => {
-                                eprintln!("Failed to parse JSON response: {}", e);
-                                return vec![];
-                            }
-                        };
-
-                        cache.set(
-                            request_str.clone(),
-                            serde_json::to_string(&api_response.data[0].embedding).unwrap(),
-                        );
-                        api_response.data[0].embedding.clone()
-                    }
-                    Some(result) => {
-                        println!("Embedding Request already cached");
-                        serde_json::from_str(&result).unwrap()
-                    }
+    /// Embedding of `text` requested from the backend without looking into the cache.
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+        if let Some(mut cassette) = self.replaying() {
+            return cassette.embedding(text);
+        }
+        let embedding = match &self.model_type {
+            ModelType::Ollama { emb, .. } => {
+                let request = OllamaEmbRequest {
+                    model: emb.to_string(),
+                    prompt: text.to_string(),
+                };
+                println!("Request to Ollama Embeddings API in progress");
+                let response = self
+                    .client()?
+                    .post(self.endpoint("embeddings"))
+                    .json(&request)
+                    .send();
+                checked(response)?
+                    .json::<OllamaEmbResponse>()
+                    .map_err(|err| format!("Cant parse the embedding response: {}", err))?
+                    .embedding
+            }
+            ModelType::OpenAI {
+                api_key,
+                base_url,
+                emb,
+                ..
+            } => {
+                let request = OpenAIEmbRequest {
+                    model: emb.to_string(),
+                    input: text.to_string(),
                 };
+                println!("Request to OpenAI Embeddings API in progress");
+                let response = openai_post(&self.client()?, base_url, "embeddings", api_key)
+                    .json(&request)
+                    .send();
+                let api_response = checked(response)?
+                    .json::<OpenAIEmbResponse>()
+                    .map_err(|err| format!("Cant parse the embedding response: {}", err))?;
 
                 if *VERBOSE.lock().unwrap() {
-                    println!("OpenAI Embedding Response: {:?}", response);
+                    println!("OpenAI Embedding Response: {:?}", api_response.data);
                 }
-                response.to_vec()
+                api_response
+                    .data
+                    .into_iter()
+                    .next()
+                    .map(|data| data.embedding)
+                    .unwrap_or_default()
             }
+        };
+        if embedding.is_empty() {
+            return Err("LLM returned an empty embedding".to_string());
+        }
+        self.record(EMBEDDING_TEMPLATE, text, "", embedding.clone());
+        Ok(embedding)
+    }
+
+    fn client(&self) -> Result<Client, String> {
+        Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .map_err(|err| format!("Cant create the HTTP client: {}", err))
+    }
+
+    /// The cassette when the responses are replayed from it.
+    fn replaying(&self) -> Option<MutexGuard<'_, Cassette>> {
+        let cassette = self.cassette.as_ref()?.lock().unwrap();
+        cassette.is_replay().then_some(cassette)
+    }
+
+    /// Adds the response to the cassette when the traffic is recorded.
+    fn record(&self, template: &str, prompt: &str, response: &str, embedding: Vec<f32>) {
+        let Some(cassette) = &self.cassette else {
+            return;
+        };
+        let mut cassette = cassette.lock().unwrap();
+        if !cassette.is_replay() {
+            cassette.add(Interaction {
+                template: template.to_string(),
+                prompt: prompt.to_string(),
+                response: response.to_string(),
+                embedding,
+            });
         }
     }
 }
@@ -275,23 +495,33 @@ struct OllamaRequest {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct OllamaOptions {
     num_predict: i32,
+    temperature: f32,
     stop: Vec<String>,
+    // not serialized when unset, so the cache keys of unseeded requests stay the same
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
 }
 
+/// One line of the NDJSON stream of `/api/generate`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct OllamaResponse {
-    model: String,
-    created_at: String,
+struct OllamaStreamChunk {
     response: String,
     done: bool,
-    done_reason: String,
-    context: Vec<i64>,
-    total_duration: i64,
-    load_duration: i64,
-    prompt_eval_count: i32,
-    prompt_eval_duration: i64,
-    eval_count: i32,
-    eval_duration: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaChatRequest {
+    model: String,
+    messages: Vec<ChatMessage>,
+    stream: bool,
+    options: OllamaOptions,
+}
+
+/// One line of the NDJSON stream of `/api/chat`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OllamaChatChunk {
+    message: Option<ChatMessage>,
+    done: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -312,36 +542,43 @@ struct OpenAIChatRequest {
     max_tokens: i32,
     temperature: f32,
     stop: Option<Vec<String>>,
+    stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct ChatMessage {
-    role: String, // e.g., "user", "assistant", "system"
-    content: String,
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub role: String, // e.g., "user", "assistant", "system"
+    pub content: String,
+}
+
+impl ChatMessage {
+    pub fn new(role: &str, content: &str) -> ChatMessage {
+        ChatMessage {
+            role: role.to_string(),
+            content: content.to_string(),
+        }
+    }
 }
 
+/// One `data:` event of the server-sent events stream of `/v1/chat/completions`.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIChatResponse {
-    id: String,
-    object: String,
-    created: i64,
-    choices: Vec<OpenAIChatChoice>,
-    usage: OpenAIUsage,
+struct OpenAIStreamChunk {
+    choices: Vec<OpenAIStreamChoice>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIChatChoice {
-    index: i32,
-    message: ChatMessage, // Changed to include the message object
+struct OpenAIStreamChoice {
+    delta: OpenAIDelta,
     finish_reason: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct OpenAIUsage {
-    prompt_tokens: i32,
-    completion_tokens: i32,
-    total_tokens: i32,
+struct OpenAIDelta {
+    content: Option<String>,
 }
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct OpenAIEmbRequest {
     model: String,
@@ -356,3 +593,341 @@ struct OpenAIEmbResponse {
 struct OpenAIEmbData {
     embedding: Vec<f32>,
 }
+
+/// Response of a sent request, an error when it could not be sent, timed out
+/// or the server answered with an error status.
+fn checked(
+    response: reqwest::Result<reqwest::blocking::Response>,
+) -> Result<reqwest::blocking::Response, String> {
+    let response = response.map_err(|err| format!("LLM request failed: {}", err))?;
+    let status = response.status();
+    if !status.is_success() {
+        let text = response.text().unwrap_or_default();
+        return Err(format!(
+            "LLM request failed with {}: {}",
+            status,
+            text.trim()
+        ));
+    }
+    Ok(response)
+}
+
+/// POST request to `<base_url>/<path>` of an OpenAI-compatible server. Local
+/// servers usually do not need an API key.
+fn openai_post(
+    client: &Client,
+    base_url: &str,
+    path: &str,
+    api_key: &Option<String>,
+) -> reqwest::blocking::RequestBuilder {
+    let request = client.post(format!("{}/{}", base_url.trim_end_matches('/'), path));
+    match api_key {
+        Some(api_key) => request.bearer_auth(api_key),
+        None => request,
+    }
+}
+
+/// Piece of a streamed response: generated text and whether the stream is over.
+struct StreamToken {
+    text: String,
+    done: bool,
+}
+
+fn parse_ollama_line(line: &str) -> Result<Option<StreamToken>, String> {
+    stream_error(line)?;
+    let Ok(chunk) = serde_json::from_str::<OllamaStreamChunk>(line) else {
+        return Ok(None);
+    };
+    Ok(Some(StreamToken {
+        text: chunk.response,
+        done: chunk.done,
+    }))
+}
+
+fn parse_ollama_chat_line(line: &str) -> Result<Option<StreamToken>, String> {
+    stream_error(line)?;
+    let Ok(chunk) = serde_json::from_str::<OllamaChatChunk>(line) else {
+        return Ok(None);
+    };
+    Ok(Some(StreamToken {
+        text: chunk
+            .message
+            .map(|message| message.content)
+            .unwrap_or_default(),
+        done: chunk.done,
+    }))
+}
+
+fn parse_openai_line(line: &str) -> Result<Option<StreamToken>, String> {
+    let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+        return Ok(None);
+    };
+    if data == "[DONE]" {
+        return Ok(Some(StreamToken {
+            text: String::new(),
+            done: true,
+        }));
+    }
+    stream_error(data)?;
+    let Ok(chunk) = serde_json::from_str::<OpenAIStreamChunk>(data) else {
+        return Ok(None);
+    };
+    let Some(choice) = chunk.choices.into_iter().next() else {
+        return Ok(None);
+    };
+    Ok(Some(StreamToken {
+        text: choice.delta.content.unwrap_or_default(),
+        done: choice.finish_reason.is_some(),
+    }))
+}
+
+/// Error sent in the stream instead of a token: `{"error":"..."}` by Ollama,
+/// `{"error":{"message":"..."}}` by OpenAI-compatible servers.
+fn stream_error(json: &str) -> Result<(), String> {
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(json) else {
+        return Ok(());
+    };
+    let Some(error) = value.get("error") else {
+        return Ok(());
+    };
+    let message = error
+        .get("message")
+        .unwrap_or(error)
+        .as_str()
+        .map(|message| message.to_string())
+        .unwrap_or_else(|| error.to_string());
+    Err(format!("LLM request failed: {}", message))
+}
+
+/// Assembles the full response, echoing the streamed tokens to the terminal with `echo`.
+/// Reading stops when the stream is over or `is_complete` accepts the text
+/// received so far. A stream which ends without its last token, e.g. on a
+/// dropped connection, is an error, so a truncated response is never cached.
+fn read_stream(
+    reader: impl BufRead,
+    echo: bool,
+    parse_line: fn(&str) -> Result<Option<StreamToken>, String>,
+    is_complete: impl Fn(&str) -> bool,
+) -> Result<String, String> {
+    let mut response = String::new();
+    for line in reader.lines() {
+        let line = line.map_err(|err| format!("Cant read the LLM response: {}", err))?;
+        let Some(token) = parse_line(&line)? else {
+            continue;
+        };
+        if echo {
+            print!("{}", token.text);
+            std::io::stdout().flush().unwrap();
+        }
+        response.push_str(&token.text);
+        if token.done {
+            if echo {
+                println!();
+            }
+            return Ok(response);
+        }
+        if token.text.contains('\n') && is_complete(&response) {
+            if echo {
+                println!();
+            }
+            println!("All expected sections received, stop streaming");
+            return Ok(response);
+        }
+    }
+    if echo {
+        println!();
+    }
+    Err(format!(
+        "LLM response ended before it was complete, {} characters received",
+        response.len()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_read_ollama_stream() {
+        let stream = r#"{"model":"m","response":"Hello","done":false}
+{"model":"m","response":", world","done":false}
+{"model":"m","response":"","done":true,"done_reason":"stop"}
+"#;
+        let response =
+            super::read_stream(stream.as_bytes(), true, super::parse_ollama_line, |_| false)
+                .unwrap();
+        assert_eq!(response, "Hello, world");
+    }
+
+    #[test]
+    fn test_read_ollama_chat_stream() {
+        let stream = r#"{"model":"m","message":{"role":"assistant","content":"Hello"},"done":false}
+{"model":"m","message":{"role":"assistant","content":", world"},"done":false}
+{"model":"m","message":{"role":"assistant","content":""},"done":true}
+"#;
+        let response = super::read_stream(
+            stream.as_bytes(),
+            true,
+            super::parse_ollama_chat_line,
+            |_| false,
+        )
+        .unwrap();
+        assert_eq!(response, "Hello, world");
+    }
+
+    #[test]
+    fn test_read_openai_stream() {
+        let stream = r#"data: {"choices":[{"index":0,"delta":{"role":"assistant"},"finish_reason":null}]}
+
+data: {"choices":[{"index":0,"delta":{"content":"Hello"},"finish_reason":null}]}
+
+data: {"choices":[{"index":0,"delta":{"content":", world"},"finish_reason":null}]}
+
+data: [DONE]
+"#;
+        let response =
+            super::read_stream(stream.as_bytes(), true, super::parse_openai_line, |_| false)
+                .unwrap();
+        assert_eq!(response, "Hello, world");
+    }
+
+    #[test]
+    fn test_read_stream_stops_on_expected_sections() {
+        let tokens = [
+            "**Build**\n",
+            "```bash\ncargo build\n```\n",
+            "**Test**\n",
+            "```bash\ncargo test\n```\n",
+            "**Explanation**\n",
+            "The function...",
+        ];
+        let stream = tokens
+            .iter()
+            .map(|token| format!("{{\"response\":{:?},\"done\":false}}\n", token))
+            .collect::<String>();
+        let expected = vec!["Build".to_string(), "Test".to_string()];
+        let response =
+            super::read_stream(stream.as_bytes(), true, super::parse_ollama_line, |text| {
+                crate::llm_response::LLMResponse::has_sections(text, &expected)
+            })
+            .unwrap();
+        assert_eq!(
+            response,
+            "**Build**\n```bash\ncargo build\n```\n**Test**\n```bash\ncargo test\n```\n"
+        );
+    }
+
+    #[test]
+    fn test_read_stream_waits_for_test_command() {
+        let expected =
+            crate::language_driver::driver_for(&crate::Lang::Kotlin, &Default::default())
+                .expected_sections();
+        let tokens = [
+            "**build.gradle**\n```groovy\nplugins {}\n```\n",
+            "**src/main/kotlin/Solution.kt**\n```kotlin\nfun solution() = 1\n```\n",
+            "**src/test/kotlin/SolutionTest.kt**\n```kotlin\nclass SolutionTest\n```\n",
+            "**Compile**\n```bash\ngradle build\n```\n",
+            "**Test**\n```bash\ngradle test --info\n```\n",
+            "**Explanation**\n",
+        ];
+        let stream = tokens
+            .iter()
+            .map(|token| format!("{{\"response\":{:?},\"done\":false}}\n", token))
+            .collect::<String>();
+        let response =
+            super::read_stream(stream.as_bytes(), true, super::parse_ollama_line, |text| {
+                crate::llm_response::LLMResponse::has_sections(text, &expected)
+            })
+            .unwrap();
+        assert!(response.ends_with("**Test**\n```bash\ngradle test --info\n```\n"));
+    }
+
+    #[test]
+    fn test_read_truncated_stream() {
+        let stream = r#"{"model":"m","response":"Hello","done":false}
+{"model":"m","response":", wor","done":false}
+"#;
+        assert!(
+            super::read_stream(stream.as_bytes(), true, super::parse_ollama_line, |_| false)
+                .is_err()
+        );
+        let stream =
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Hello\"},\"finish_reason\":null}]}\n";
+        assert!(
+            super::read_stream(stream.as_bytes(), true, super::parse_openai_line, |_| false)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_read_stream_error() {
+        let stream = r#"{"model":"m","response":"Hello","done":false}
+{"error":"model runner has unexpectedly stopped"}
+"#;
+        assert_eq!(
+            super::read_stream(stream.as_bytes(), true, super::parse_ollama_line, |_| false),
+            Err("LLM request failed: model runner has unexpectedly stopped".to_string())
+        );
+        let stream = r#"{"error":"model not found"}"#;
+        assert!(super::read_stream(
+            stream.as_bytes(),
+            true,
+            super::parse_ollama_chat_line,
+            |_| false
+        )
+        .is_err());
+        let stream = r#"data: {"error":{"message":"Rate limit exceeded","type":"requests"}}"#;
+        assert_eq!(
+            super::read_stream(stream.as_bytes(), true, super::parse_openai_line, |_| false),
+            Err("LLM request failed: Rate limit exceeded".to_string())
+        );
+    }
+
+    #[test]
+    fn test_cache_key_identity() {
+        use super::{LLMApi, ModelType, OllamaEmbRequest};
+
+        let ollama = |url: &str, emb: &str| {
+            LLMApi::new(
+                ModelType::Ollama {
+                    url: url.to_string(),
+                    model: "qwen2.5-coder:7b".to_string(),
+                    emb: emb.to_string(),
+                },
+                0.7,
+                1000,
+            )
+        };
+        let key = |llm: &LLMApi| {
+            let request = OllamaEmbRequest {
+                model: llm.emb_model().to_string(),
+                prompt: "fn main() {}".to_string(),
+            };
+            llm.cache_key("embeddings", &request)
+        };
+        let local = ollama("http://127.0.0.1:11434/", "bge-large");
+        assert!(key(&local).contains("\"endpoint\":\"http://127.0.0.1:11434/api/embeddings\""));
+        assert_eq!(
+            key(&local),
+            key(&ollama("http://127.0.0.1:11434", "bge-large"))
+        );
+        assert_ne!(
+            key(&local),
+            key(&ollama("http://127.0.0.1:11434", "nomic-embed-text"))
+        );
+        assert_ne!(key(&local), key(&ollama("http://gpu:11434", "bge-large")));
+
+        let openai = |api_key: &str| {
+            LLMApi::new(
+                ModelType::OpenAI {
+                    api_key: Some(api_key.to_string()),
+                    base_url: "http://127.0.0.1:11434".to_string(),
+                    model: "qwen2.5-coder:7b".to_string(),
+                    emb: "bge-large".to_string(),
+                },
+                0.7,
+                1000,
+            )
+        };
+        assert_ne!(key(&local), key(&openai("a")));
+        assert_eq!(key(&openai("a")), key(&openai("b")));
+    }
+}
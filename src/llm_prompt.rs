@@ -24,7 +24,7 @@ impl Prompt {
                 prompt_content = String::new();
             } else {
                 prompt_content.push_str(line);
-                prompt_content.push_str("\n");
+                prompt_content.push('\n');
             }
         }
         prompt_content = replace_last_multiple_return_to_one(&prompt_content);
@@ -33,14 +33,14 @@ impl Prompt {
         prompts
     }
 
-    pub fn create(&self, key: &str, params: &Vec<String>) -> String {
+    pub fn create(&self, key: &str, params: &[String]) -> String {
         let mut prompt = self.prompts.get(key).unwrap().clone();
         prompt = construct_prompt(&prompt, params);
         prompt
     }
 }
 
-fn construct_prompt(template: &str, replace: &Vec<String>) -> String {
+fn construct_prompt(template: &str, replace: &[String]) -> String {
     let mut prompt = template.to_string();
     for (i, r) in replace.iter().enumerate() {
         let placeholder = format!("{{{{{{{}}}}}}}", i); // "{{{0}}}"
@@ -100,7 +100,7 @@ Result of compilation:
         std::fs::write("test.p", content).unwrap();
         let prompt = Prompt::new("test.p");
         println!("{:#?}", prompt);
-        assert_eq!(prompt.create("generate_code_prompt_template", &vec!["123".to_string()]), "\n123\n\nWrite on Rust language code of this function (without example of usage like main function):\n```rust\nfn solution(\n");
+        assert_eq!(prompt.create("generate_code_prompt_template", &["123".to_string()]), "\n123\n\nWrite on Rust language code of this function (without example of usage like main function):\n```rust\nfn solution(\n");
         std::fs::remove_file("test.p").unwrap();
     }
     #[test]
@@ -1,30 +1,95 @@
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-use crate::{utils::remove_comments, Lang};
+use crate::utils::remove_comments;
 
-#[derive(Debug)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Project {
     pub dependencies: String,
     pub additional_config: Vec<String>,
     pub solution_code: String,
     pub test_code: String,
-    pub install_dependency_command: Option<String>,
+    pub install_command: String,
     pub build_command: String,
     pub test_command: String,
-    pub lang: Lang,
 }
 
-impl Default for Project {
-    fn default() -> Self {
-        Self {
-            dependencies: String::new(),
-            additional_config: vec![],
-            solution_code: String::new(),
-            test_code: String::new(),
-            install_dependency_command: None,
-            build_command: String::new(),
-            test_command: String::new(),
-            lang: Lang::Unknown,
+/// Multi-file project of the `generate application` and `generate function
+/// --into` modes: file tree, contents of the files written so far and the
+/// commands to check them.
+#[derive(Debug, Default)]
+pub struct Application {
+    pub tree: Vec<(String, String)>,
+    pub files: Vec<(String, String)>,
+    pub build_command: String,
+    pub test_command: String,
+}
+
+impl Application {
+    pub fn file(&self, path: &str) -> Option<&String> {
+        self.files
+            .iter()
+            .find(|(file_path, _)| file_path == path)
+            .map(|(_, content)| content)
+    }
+
+    pub fn set_file(&mut self, path: &str, content: String) {
+        match self
+            .files
+            .iter_mut()
+            .find(|(file_path, _)| file_path == path)
+        {
+            Some(file) => file.1 = content,
+            None => self.files.push((path.to_string(), content)),
+        }
+    }
+
+    /// File tree in the same "path - description" form the LLM planned it.
+    pub fn tree_listing(&self) -> String {
+        self.tree
+            .iter()
+            .map(|(path, description)| {
+                if description.is_empty() {
+                    path.clone()
+                } else {
+                    format!("{} - {}", path, description)
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Contents of the written files (except `skip`) formatted as prompt sections.
+    pub fn files_listing(&self, skip: &str) -> String {
+        let listing = self
+            .files
+            .iter()
+            .filter(|(path, _)| path != skip)
+            .map(|(path, content)| format!("**{}**\n```\n{}\n```", path, content))
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        if listing.is_empty() {
+            "None".to_string()
+        } else {
+            listing
+        }
+    }
+
+    /// Planned files mentioned in the build or test output. When the output
+    /// does not point to any file every file of the project is rewritten.
+    pub fn files_to_rewrite(&self, build_output: &str, test_output: &str) -> Vec<String> {
+        let mentioned = self
+            .tree
+            .iter()
+            .map(|(path, _)| path.clone())
+            .filter(|path| {
+                build_output.contains(path.as_str()) || test_output.contains(path.as_str())
+            })
+            .collect::<Vec<_>>();
+        if mentioned.is_empty() {
+            self.tree.iter().map(|(path, _)| path.clone()).collect()
+        } else {
+            mentioned
         }
     }
 }
@@ -49,850 +114,277 @@ impl LLMResponse {
         positions
     }
 
-    pub fn parse_llm_response(response: &str, language: Lang) -> Project {
-        match language {
-            Lang::Rust => {
-                let mut cargo_toml = String::new();
-                let mut lib_rs = String::new();
-                let mut build = String::new();
-                let mut test = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("Cargo.toml") {
-                            cargo_toml = content;
-                        } else if section_name.contains("src/lib.rs") {
-                            lib_rs = content;
-                        } else if section_name.contains("Build") {
-                            build = content;
-                        } else if section_name.contains("Test") {
-                            test = content;
-                        }
-                    }
-                }
-
-                if cargo_toml == "" {
-                    let mut lines = response.lines().peekable();
-
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
-
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "Cargo.toml" => {
-                                            cargo_toml = code_content.trim_end().to_string()
-                                        }
-                                        "src/lib.rs" => {
-                                            lib_rs = code_content.trim_end().to_string()
-                                        }
-                                        "Build" => build = code_content.trim_end().to_string(),
-                                        "Test" => test = code_content.trim_end().to_string(),
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                Project {
-                    dependencies: cargo_toml,
-                    solution_code: lib_rs,
-                    build_command: remove_comments(&build),
-                    test_command: remove_comments(&test),
-                    lang: Lang::Rust,
-                    ..Default::default()
-                }
-            }
-            Lang::JavaScript => {
-                let mut pkg_json = String::new();
-                let mut solution = String::new();
-                let mut install_dependency_command = String::new();
-                let mut test = String::new();
-                let mut test_js = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("package.json") {
-                            pkg_json = content;
-                        } else if section_name.contains("src/solution.js") {
-                            solution = content;
-                        } else if section_name.contains("src/solution.test.js") {
-                            test_js = content;
-                        } else if section_name.contains("Install") {
-                            install_dependency_command = content;
-                        } else if section_name.contains("Test") {
-                            test = content;
-                        }
-                    }
-                }
-
-                if pkg_json == "" {
-                    let mut lines = response.lines().peekable();
-
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
-
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "package.json" => {
-                                            pkg_json = code_content.trim_end().to_string()
-                                        }
-                                        "src/solution.js" => {
-                                            solution = code_content.trim_end().to_string()
-                                        }
-                                        "src/solution.test.js" => {
-                                            test = code_content.trim_end().to_string()
-                                        }
-                                        "Install" => {
-                                            install_dependency_command =
-                                                code_content.trim_end().to_string()
-                                        }
-                                        "Test" => test = code_content.trim_end().to_string(),
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-
-                Project {
-                    dependencies: pkg_json,
-                    solution_code: solution,
-                    test_code: test_js,
-                    test_command: test,
-                    install_dependency_command: Some(install_dependency_command),
-                    lang: Lang::JavaScript,
-                    ..Default::default()
-                }
+    /// Name and content of the first code block of every section of the response.
+    pub fn parse_sections(response: &str) -> Vec<(String, String)> {
+        let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
+        let positions = LLMResponse::parse_positions(response);
+
+        let mut sections = Vec::new();
+        for window in positions.windows(2) {
+            let (section_name, start) = &window[0];
+            let section_content = &response[*start..window[1].1];
+            if let Some(cap) = re_code_block.captures(section_content) {
+                let name = section_name.trim().trim_matches('`').trim().to_string();
+                sections.push((name, cap.get(1).unwrap().as_str().to_string()));
             }
-            Lang::Java => {
-                let mut pom_xml = String::new();
-                let mut solution_java = String::new();
-                let mut test_java = String::new();
-                let mut build_command = String::new();
-                let mut test_command = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("pom.xml") {
-                            pom_xml = content;
-                        } else if section_name
-                            .contains("src/main/java/com/example/solution/Solution.java")
-                        {
-                            solution_java = content;
-                        } else if section_name
-                            .contains("src/test/java/com/example/solution/SolutionTest.java")
-                        {
-                            test_java = content;
-                        } else if section_name.contains("Compile") {
-                            build_command = content;
-                        } else if section_name.contains("Test") {
-                            test_command = content;
-                        }
-                    }
-                }
+        }
 
-                if pom_xml == "" {
-                    let mut lines = response.lines().peekable();
+        if sections.is_empty() {
+            let mut lines = response.lines();
+            while let Some(line) = lines.next() {
+                if line.starts_with("## ") || line.starts_with("### ") {
+                    let section_title = line.trim_start_matches('#').trim().trim_matches('`');
 
+                    // Skip lines until the start of the code block
                     while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
-
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
+                        if line.starts_with("```") {
+                            let mut code_content = String::new();
+                            for line in lines.by_ref() {
                                 if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "pom.xml" => pom_xml = code_content.trim_end().to_string(),
-                                        "src/main/java/com/example/solution/Solution.java" => {
-                                            solution_java = code_content.trim_end().to_string()
-                                        }
-                                        "src/test/java/com/example/solution/SolutionTest.java" => {
-                                            test_java = code_content.trim_end().to_string()
-                                        }
-                                        "Compile" => {
-                                            build_command = code_content.trim_end().to_string()
-                                        }
-                                        "Test" => {
-                                            test_command = code_content.trim_end().to_string()
-                                        }
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
                                     break;
                                 }
+                                code_content.push_str(line);
+                                code_content.push('\n');
                             }
+                            sections.push((
+                                section_title.to_string(),
+                                code_content.trim_end().to_string(),
+                            ));
+                            break;
                         }
                     }
                 }
-
-                Project {
-                    dependencies: pom_xml,
-                    solution_code: solution_java,
-                    test_code: test_java,
-                    build_command,
-                    test_command,
-                    ..Default::default()
-                }
             }
-            Lang::Kotlin => {
-                let mut gradle = String::new();
-                let mut solution_kotlin = String::new();
-                let mut test_kotlin = String::new();
-                let mut build_command = String::new();
-                let mut test_command = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("build.gradle") {
-                            gradle = content;
-                        } else if section_name.contains("src/main/kotlin/Solution.kt") {
-                            solution_kotlin = content;
-                        } else if section_name.contains("src/test/kotlin/SolutionTest.kt") {
-                            test_kotlin = content;
-                        } else if section_name.contains("Compile") {
-                            build_command = content;
-                        } else if section_name.contains("Test") {
-                            test_command = content;
-                        }
-                    }
-                }
-
-                if gradle == "" {
-                    let mut lines = response.lines().peekable();
-
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
+        }
+        sections
+    }
 
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "build.gradle" => {
-                                            gradle = code_content.trim_end().to_string()
-                                        }
-                                        "src/main/kotlin/Solution.kt" => {
-                                            solution_kotlin = code_content.trim_end().to_string()
-                                        }
-                                        "src/test/kotlin/SolutionTest.kt" => {
-                                            test_kotlin = code_content.trim_end().to_string()
-                                        }
-                                        "Compile" => {
-                                            build_command = code_content.trim_end().to_string()
-                                        }
-                                        "Test" => {
-                                            test_command = code_content.trim_end().to_string()
-                                        }
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                Project {
-                    dependencies: gradle,
-                    solution_code: solution_kotlin,
-                    test_code: test_kotlin,
-                    build_command,
-                    test_command,
-                    lang: Lang::Kotlin,
-
-                    ..Default::default()
-                }
+    pub fn parse_application_plan(response: &str) -> Application {
+        let mut application = Application::default();
+        for (section_name, content) in LLMResponse::parse_sections(response) {
+            if let Some(path) = sanitize_path(&section_name) {
+                application.set_file(&path, content);
+            } else if section_name.contains("Tree") {
+                application.tree = parse_tree(&content);
+            } else if section_name.contains("Build") {
+                application.build_command = remove_comments(&content);
+            } else if section_name.contains("Test") {
+                application.test_command = remove_comments(&content);
             }
-            Lang::Php => {
-                let mut composer = String::new();
-                let mut solution_php = String::new();
-                let mut test_php = String::new();
-                let mut install_dependency_command = String::new();
-                let mut test_command = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-                        if section_name.contains("composer.json") {
-                            composer = content;
-                        } else if section_name.contains("src/Solution.php") {
-                            solution_php = content;
-                        } else if section_name.contains("tests/SolutionTest.php") {
-                            test_php = content;
-                        } else if section_name.contains("Install") {
-                            install_dependency_command = content;
-                        } else if section_name.contains("Test") {
-                            test_command = content;
-                        }
-                    }
-                }
-
-                if composer == "" {
-                    let mut lines = response.lines().peekable();
-
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
-
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "composer.json" => {
-                                            composer = code_content.trim_end().to_string()
-                                        }
-                                        "src/Solution.php" => {
-                                            solution_php = code_content.trim_end().to_string()
-                                        }
-                                        "tests/SolutionTest.php" => {
-                                            test_php = code_content.trim_end().to_string()
-                                        }
-                                        "Install" => {
-                                            install_dependency_command =
-                                                code_content.trim_end().to_string()
-                                        }
-                                        "Test" => {
-                                            test_command = code_content.trim_end().to_string()
-                                        }
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                Project {
-                    install_dependency_command: Some(install_dependency_command),
-                    dependencies: composer,
-                    solution_code: solution_php,
-                    test_code: test_php,
-                    test_command,
-                    lang: Lang::Php,
-                    ..Default::default()
-                }
-            }
-            Lang::Python => {
-                let mut requirements = String::new();
-                let mut solution_py = String::new();
-                let mut test_py = String::new();
-                let mut install_dependency_command = String::new();
-                let mut test = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("requirements.txt") {
-                            requirements = content;
-                        } else if section_name.contains("solution.py") {
-                            solution_py = content;
-                        } else if section_name.contains("test.py") {
-                            test_py = content;
-                        } else if section_name.contains("Dependencies") {
-                            install_dependency_command = content;
-                        } else if section_name.contains("Test") {
-                            test = content;
-                        }
-                    }
-                }
-
-                if requirements == "" {
-                    let mut lines = response.lines().peekable();
-
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
-
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "requirements.txt" => {
-                                            requirements = code_content.trim_end().to_string()
-                                        }
-                                        "solution.py" => {
-                                            solution_py = code_content.trim_end().to_string()
-                                        }
-                                        "test.py" => test_py = code_content.trim_end().to_string(),
-                                        "Dependencies" => {
-                                            install_dependency_command =
-                                                code_content.trim_end().to_string()
-                                        }
-                                        "Test" => test = code_content.trim_end().to_string(),
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                Project {
-                    dependencies: requirements,
-                    solution_code: solution_py,
-                    test_code: test_py,
-                    install_dependency_command: Some(install_dependency_command),
-                    test_command: test,
-                    lang: Lang::Python,
-                    ..Default::default()
-                }
+        }
+        // files written in the plan belong to the project even if the tree forgot them
+        for (path, _) in &application.files {
+            if !application
+                .tree
+                .iter()
+                .any(|(tree_path, _)| tree_path == path)
+            {
+                application.tree.push((path.clone(), String::new()));
             }
-            Lang::Scala => {
-                let mut sbt = String::new();
-                let mut solution_sc = String::new();
-                let mut test_sc = String::new();
-                let mut build = String::new();
-                let mut test = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("build.sbt") {
-                            sbt = content;
-                        } else if section_name.contains("src/main/scala/Solution.scala") {
-                            solution_sc = content;
-                        } else if section_name.contains("src/test/scala/SolutionTest.scala") {
-                            test_sc = content;
-                        } else if section_name.contains("Compile") {
-                            build = content;
-                        } else if section_name.contains("Test") {
-                            test = content;
-                        }
-                    }
-                }
+        }
+        application
+    }
 
-                if sbt == "" {
-                    let mut lines = response.lines().peekable();
+    /// Files of the response: sections whose header is a relative path inside the project.
+    pub fn parse_files(response: &str) -> Vec<(String, String)> {
+        LLMResponse::parse_sections(response)
+            .into_iter()
+            .filter_map(|(name, content)| sanitize_path(&name).map(|path| (path, content)))
+            .collect()
+    }
 
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
+    /// Content of the file `path` from a response that is expected to contain only this file.
+    pub fn parse_file(response: &str, path: &str) -> Option<String> {
+        let sections = LLMResponse::parse_sections(response);
+        if let Some((_, content)) = sections.iter().find(|(name, _)| name.contains(path)) {
+            return Some(content.clone());
+        }
+        if sections.len() == 1 {
+            return Some(sections[0].1.clone());
+        }
+        let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
+        re_code_block
+            .captures(response)
+            .map(|cap| cap.get(1).unwrap().as_str().to_string())
+    }
 
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "build.sbt" => sbt = code_content.trim_end().to_string(),
-                                        "src/main/scala/Solution.scala" => {
-                                            solution_sc = code_content.trim_end().to_string()
-                                        }
-                                        "src/test/scala/SolutionTest.scala" => {
-                                            test_sc = code_content.trim_end().to_string()
-                                        }
-                                        "Compile" => build = code_content.trim_end().to_string(),
-                                        "Test" => test = code_content.trim_end().to_string(),
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                Project {
-                    dependencies: sbt,
-                    solution_code: solution_sc,
-                    test_code: test_sc,
-                    build_command: build,
-                    test_command: test,
-                    lang: Lang::Scala,
-                    ..Default::default()
-                }
+    /// Whether every section named in `names` is present with a complete code
+    /// block. Headers are matched like in `extract_sections`, so the header
+    /// "SolutionTest.kt" doesn't stand in for a missing "Test" section.
+    pub fn has_sections(response: &str, names: &[String]) -> bool {
+        let names = names.iter().map(|name| name.as_str()).collect::<Vec<_>>();
+        let mut found = vec![false; names.len()];
+        for (section_name, _) in LLMResponse::parse_sections(response) {
+            if let Some(i) = section_index(&section_name, &names) {
+                found[i] = true;
             }
-            Lang::Swift => {
-                let mut package = String::new();
-                let mut solution_sw = String::new();
-                let mut test_sw = String::new();
-                let mut build = String::new();
-                let mut test = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("Package.swift") {
-                            package = content;
-                        } else if section_name.contains("Sources/Solution/main.swift") {
-                            solution_sw = content;
-                        } else if section_name.contains("Tests/SolutionTests/SolutionTests.swift") {
-                            test_sw = content;
-                        } else if section_name.contains("Compile") {
-                            build = content;
-                        } else if section_name.contains("Test") {
-                            test = content;
-                        }
-                    }
-                }
-
-                if package == "" {
-                    let mut lines = response.lines().peekable();
-
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
+        }
+        found.into_iter().all(|found| found)
+    }
 
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "Package.swift" => {
-                                            package = code_content.trim_end().to_string()
-                                        }
-                                        "Sources/Solution/main.swift" => {
-                                            solution_sw = code_content.trim_end().to_string()
-                                        }
-                                        "Tests/SolutionTests/SolutionTests.swift" => {
-                                            test_sw = code_content.trim_end().to_string()
-                                        }
-                                        "Compile" => build = code_content.trim_end().to_string(),
-                                        "Test" => test = code_content.trim_end().to_string(),
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                Project {
-                    dependencies: package,
-                    solution_code: solution_sw,
-                    test_code: test_sw,
-                    build_command: build,
-                    test_command: test,
-                    lang: Lang::Swift,
-                    ..Default::default()
-                }
+    /// Content of the sections named in `names`. A section is assigned to the
+    /// longest name its header contains, so "SolutionTest.kt" wins over "Test".
+    pub fn extract_sections(response: &str, names: &[&str]) -> Vec<String> {
+        let mut contents = vec![String::new(); names.len()];
+        for (section_name, content) in LLMResponse::parse_sections(response) {
+            if let Some(i) = section_index(&section_name, names) {
+                contents[i] = content;
             }
-            Lang::TypeScript => {
-                let mut package = String::new();
-                let mut typescript_config = String::new();
-                let mut solution = String::new();
-                let mut test_code = String::new();
-                let mut build = String::new();
-                let mut test = String::new();
-
-                let positions = LLMResponse::parse_positions(response);
-
-                for i in 0..positions.len() - 1 {
-                    let (section_name, start) = &positions[i];
-                    let end = positions[i + 1].1;
-
-                    let section_content = &response[*start..end];
-
-                    // Регулярное выражение для извлечения блоков кода (с учетом многострочных текстов)
-                    let re_code_block = Regex::new(r"(?s)```.*?\n(.*?)```").unwrap();
-
-                    if let Some(cap) = re_code_block.captures(section_content) {
-                        let content = cap.get(1).unwrap().as_str().to_string();
-
-                        if section_name.contains("package.json") {
-                            package = content;
-                        } else if section_name.contains("src/solution.ts") {
-                            solution = content;
-                        } else if section_name.contains("tsconfig.json") {
-                            typescript_config = content;
-                        } else if section_name.contains("src/solution.test.ts") {
-                            test_code = content;
-                        } else if section_name.contains("Install") {
-                            build = content;
-                        } else if section_name.contains("Test") {
-                            test = content;
-                        }
-                    }
-                }
+        }
+        contents
+    }
+}
 
-                if package == "" {
-                    let mut lines = response.lines().peekable();
+/// Index of the longest name in `names` the section header contains.
+fn section_index(section_name: &str, names: &[&str]) -> Option<usize> {
+    names
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| section_name.contains(*name))
+        .max_by_key(|(_, name)| name.len())
+        .map(|(i, _)| i)
+}
 
-                    while let Some(line) = lines.next() {
-                        if line.starts_with("## ") || line.starts_with("### ") {
-                            let section_title = line[3..].trim();
+/// Folders of a project the LLM must never write into: the git repository,
+/// where a hook would run on the next commit, and the data of rustsn.
+const RESERVED_DIRS: [&str; 2] = [".git", crate::embedding_index::INDEX_DIR];
+
+/// Relative path of a project file named by the LLM. Absolute paths, paths
+/// leaving the project folder and paths inside `RESERVED_DIRS` are rejected.
+fn sanitize_path(name: &str) -> Option<String> {
+    let path = name
+        .trim()
+        .trim_matches('`')
+        .trim()
+        .trim_start_matches("./");
+    if path.is_empty()
+        || path.contains(' ')
+        || path.contains(':')
+        || path.starts_with('/')
+        || path.starts_with('\\')
+        || path.ends_with('/')
+        || !(path.contains('.') || path.contains('/'))
+        || path
+            .split(['/', '\\'])
+            .any(|part| part == ".." || RESERVED_DIRS.contains(&part))
+    {
+        return None;
+    }
+    Some(path.to_string())
+}
 
-                            // Skip lines until the start of the code block
-                            while let Some(line) = lines.next() {
-                                if line.starts_with("```") {
-                                    // Capture the code block content
-                                    let mut code_content = String::new();
-                                    while let Some(line) = lines.next() {
-                                        if line.starts_with("```") {
-                                            // End of code block
-                                            break;
-                                        } else {
-                                            code_content.push_str(line);
-                                            code_content.push('\n');
-                                        }
-                                    }
-
-                                    // Assign the captured content to the appropriate field
-                                    match section_title {
-                                        "package.json" => {
-                                            package = code_content.trim_end().to_string()
-                                        }
-                                        "src/solution.ts" => {
-                                            solution = code_content.trim_end().to_string()
-                                        }
-                                        "src/solution.test.ts" => {
-                                            test_code = code_content.trim_end().to_string()
-                                        }
-                                        "tsconfig.json" => {
-                                            typescript_config = code_content.trim_end().to_string()
-                                        }
-                                        "Install" => build = code_content.trim_end().to_string(),
-                                        "Test" => test = code_content.trim_end().to_string(),
-                                        _ => (),
-                                    }
-
-                                    // Break out of the inner loop to process the next section
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-                Project {
-                    dependencies: package,
-                    solution_code: solution,
-                    test_code,
-                    additional_config: vec![typescript_config],
-                    build_command: build,
-                    test_command: test,
-                    lang: Lang::TypeScript,
-                    ..Default::default()
-                }
-            }
-            _ => {
-                unimplemented!()
+fn parse_tree(content: &str) -> Vec<(String, String)> {
+    let mut tree = Vec::new();
+    for line in content.lines() {
+        let line = line.trim_start_matches(|c: char| c.is_whitespace() || "-*│├└─".contains(c));
+        let (path, description) = match line.split_once(" - ") {
+            Some((path, description)) => (path, description.trim()),
+            None => (line, ""),
+        };
+        if let Some(path) = sanitize_path(path) {
+            if !tree.iter().any(|(tree_path, _)| *tree_path == path) {
+                tree.push((path, description.to_string()));
             }
         }
     }
+    tree
 }
 
 mod tests {
+    #[test]
+    fn test_sanitize_path() {
+        use super::sanitize_path;
+
+        assert_eq!(
+            sanitize_path(" `./src/lib.rs` "),
+            Some("src/lib.rs".to_string())
+        );
+        assert_eq!(sanitize_path(".gitignore"), Some(".gitignore".to_string()));
+        for path in [
+            "../outside.rs",
+            "src/../../outside.rs",
+            "/etc/passwd",
+            "C:\\Windows\\win.ini",
+            "my file.rs",
+            "Build",
+            ".git/hooks/pre-commit",
+            "sub/.git/config",
+            ".git\\hooks\\pre-commit",
+            ".rustsn/index.bin",
+        ] {
+            assert_eq!(sanitize_path(path), None, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_parse_application_plan() {
+        let response = r#"**Tree**
+```text
+Cargo.toml - project manifest
+src/lib.rs - declares modules
+- src/parser.rs - parses input
+../outside.rs - must be ignored
+```
+
+**Cargo.toml**
+```toml
+[package]
+name = "app"
+```
+
+**Build**
+```bash
+cargo build --tests
+```
+
+**Test**
+```bash
+cargo test
+```
+"#;
+        let application = crate::llm_response::LLMResponse::parse_application_plan(response);
+        assert_eq!(
+            application.tree,
+            vec![
+                ("Cargo.toml".to_string(), "project manifest".to_string()),
+                ("src/lib.rs".to_string(), "declares modules".to_string()),
+                ("src/parser.rs".to_string(), "parses input".to_string()),
+            ]
+        );
+        assert_eq!(
+            application.file("Cargo.toml").unwrap(),
+            "[package]\nname = \"app\"\n"
+        );
+        assert_eq!(application.build_command, "cargo build --tests");
+        assert_eq!(application.test_command, "cargo test");
+        assert_eq!(
+            application.files_to_rewrite("error in src/lib.rs", ""),
+            vec!["src/lib.rs".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_parse_file() {
+        let response = "**src/parser.rs**\n```rust\npub fn parse() {}\n```\n";
+        assert_eq!(
+            crate::llm_response::LLMResponse::parse_file(response, "src/parser.rs").unwrap(),
+            "pub fn parse() {}\n"
+        );
+        let response = "```rust\npub fn parse() {}\n```\n";
+        assert_eq!(
+            crate::llm_response::LLMResponse::parse_file(response, "src/parser.rs").unwrap(),
+            "pub fn parse() {}\n"
+        );
+    }
+
     #[test]
     fn test_parse_llm_response_java() {
         for i in 1..=5 {
             let file = format!("./test_data/java_create_{}.txt", i);
             let response = std::fs::read_to_string(file).unwrap();
             let project =
-                crate::llm_response::LLMResponse::parse_llm_response(&response, crate::Lang::Java);
+                crate::language_driver::driver_for(&crate::Lang::Java, &Default::default())
+                    .parse_response(&response);
 
             println!("{:#?}", project);
             assert!(!project.dependencies.is_empty());
@@ -909,7 +401,8 @@ mod tests {
             let file = format!("./test_data/rust_create_{}.txt", i);
             let response = std::fs::read_to_string(file).unwrap();
             let mut project =
-                crate::llm_response::LLMResponse::parse_llm_response(&response, crate::Lang::Rust);
+                crate::language_driver::driver_for(&crate::Lang::Rust, &Default::default())
+                    .parse_response(&response);
             project.build_command = crate::utils::remove_comments(&project.build_command);
             project.test_command = crate::utils::remove_comments(&project.test_command);
 
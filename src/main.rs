@@ -1,26 +1,35 @@
 use clap::{Arg, ArgAction, Command};
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
 use std::fmt::Display;
 use std::str::FromStr;
 use std::sync::Mutex;
 
+mod application;
 mod build_tool;
 mod cache;
+mod cassette;
+mod chat;
+mod chunker;
+mod command_line;
+mod config;
+mod diagnostics;
+mod embedding_index;
+mod executor;
+mod existing_project;
 mod file_explorer;
+mod language_driver;
 mod llm_api;
 mod llm_prompt;
 mod llm_response;
+mod mock_server;
+mod retrieval;
+mod runs;
 mod state_machine;
 mod utils;
 mod vector_utils;
 
 static VERBOSE: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
 
-const MAX_NUMBER_OF_ATTEMPTS: i32 = 5;
-const OLLAMA_API: &str = "http://127.0.0.1:11434/api/generate";
-const OLLAMA_EMB: &str = "http://127.0.0.1:11434/api/embeddings";
-
 fn main() {
     std::env::set_var("OLLAMA_NUM_PARALLEL", "2");
     let matches = Command::new("rustsn - Rust Snippets Generator")
@@ -48,36 +57,155 @@ Usage:
             Arg::new("lang")
                 .long("lang")
                 .value_name("LANG")
-                .help("Sets the programming language")
-                .default_value("rust")
-                .global(true)
-                .value_parser(*&[
-                    "rust",
-                    "java",
-                    "javascript",
-                    "typescript",
-                    "scala",
-                    "kotlin",
-                    "swift",
-                    "php",
-                    "python",
-                    "cs",
-                ]),
+                .env("RUSTSN_LANG")
+                .help("Sets the programming language: rust, java, javascript, typescript, scala, kotlin, swift, php, python, cs or any language described by prompt/<LANG>.toml")
+                .global(true),
+        )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .value_name("BACKEND")
+                .env("RUSTSN_BACKEND")
+                .help("LLM backend: ollama or openai (any OpenAI-compatible server). By default openai is used when the token file exists")
+                .value_parser(["ollama", "openai"])
+                .global(true),
         )
         .arg(
             Arg::new("ollmod")
                 .long("ollmod")
                 .value_name("OLLAMA-MODEL")
+                .env("RUSTSN_OLLAMA_MODEL")
                 .help("Set desired ollama model")
-                .default_value("qwen2.5-coder:7b")
                 .global(true),
         )
         .arg(
             Arg::new("ollemb")
                 .long("ollemb")
                 .value_name("OLLAMA-EMBEDIDING")
+                .env("RUSTSN_OLLAMA_EMB")
                 .help("Set desired ollama embedding")
-                .default_value("bge-large")
+                .global(true),
+        )
+        .arg(
+            Arg::new("ollama-url")
+                .long("ollama-url")
+                .value_name("URL")
+                .env("RUSTSN_OLLAMA_URL")
+                .help("Base URL of the Ollama API")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-url")
+                .long("openai-url")
+                .value_name("URL")
+                .env("RUSTSN_OPENAI_URL")
+                .help("Base URL of the OpenAI-compatible API, e.g. http://localhost:8000/v1 for vLLM")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-key")
+                .long("openai-key")
+                .value_name("KEY")
+                .env("OPENAI_API_KEY")
+                .hide_env_values(true)
+                .help("API key of the OpenAI-compatible API, the content of the token file by default")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-model")
+                .long("openai-model")
+                .value_name("MODEL")
+                .env("RUSTSN_OPENAI_MODEL")
+                .help("Chat model of the OpenAI-compatible API")
+                .global(true),
+        )
+        .arg(
+            Arg::new("openai-emb")
+                .long("openai-emb")
+                .value_name("MODEL")
+                .env("RUSTSN_OPENAI_EMB")
+                .help("Embedding model of the OpenAI-compatible API")
+                .global(true),
+        )
+        .arg(
+            Arg::new("temperature")
+                .long("temperature")
+                .value_name("TEMPERATURE")
+                .env("RUSTSN_TEMPERATURE")
+                .help("Sampling temperature of the LLM")
+                .value_parser(clap::value_parser!(f64))
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-tokens")
+                .long("max-tokens")
+                .value_name("N")
+                .env("RUSTSN_MAX_TOKENS")
+                .help("Maximum number of tokens the LLM may generate per request")
+                .value_parser(clap::value_parser!(i32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("max-attempts")
+                .long("max-attempts")
+                .value_name("N")
+                .env("RUSTSN_MAX_ATTEMPTS")
+                .help("Maximum number of rewrite attempts")
+                .value_parser(clap::value_parser!(i32))
+                .global(true),
+        )
+        .arg(
+            Arg::new("sandbox-dir")
+                .long("sandbox-dir")
+                .value_name("DIR")
+                .env("RUSTSN_SANDBOX_DIR")
+                .help("Folder where generated functions are built and tested")
+                .global(true),
+        )
+        .arg(
+            Arg::new("cache-path")
+                .long("cache-path")
+                .value_name("FILE")
+                .env("RUSTSN_CACHE_PATH")
+                .help("File of the LLM and build results cache")
+                .global(true),
+        )
+        .arg(
+            Arg::new("no-cache")
+                .long("no-cache")
+                .help("Neither use nor store cached LLM completions and embeddings")
+                .global(true)
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("refresh")
+                .long("refresh")
+                .help("Request new LLM completions and embeddings and replace the cached ones")
+                .global(true)
+                .conflicts_with("no-cache")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("record")
+                .long("record")
+                .value_name("FILE")
+                .help("Record the LLM requests and responses into a cassette file")
+                .global(true),
+        )
+        .arg(
+            Arg::new("replay")
+                .long("replay")
+                .value_name("FILE")
+                .help("Serve the LLM responses from a recorded cassette file instead of the backend")
+                .global(true)
+                .conflicts_with("record"),
+        )
+        .arg(
+            Arg::new("prompt-dir")
+                .long("prompt-dir")
+                .value_name("DIR")
+                .env("RUSTSN_PROMPT_DIR")
+                .help("Folder with prompts and language descriptors")
                 .global(true),
         )
         .subcommand(
@@ -90,6 +218,57 @@ Usage:
                         .value_parser(*&["function", "application"])
                         .default_value("function")
                         .index(1),
+                )
+                .arg(
+                    Arg::new("output")
+                        .long("output")
+                        .value_name("DIR")
+                        .help("Folder for the generated application")
+                        .required_if_eq("type", "application"),
+                )
+                .arg(
+                    Arg::new("tests")
+                        .long("tests")
+                        .value_name("FILE")
+                        .help("File with tests the generated function must pass, they are never rewritten"),
+                )
+                .arg(
+                    Arg::new("into")
+                        .long("into")
+                        .value_name("DIR")
+                        .help("Existing project the function is added to")
+                        .requires("module")
+                        .conflicts_with_all(["output", "tests"]),
+                )
+                .arg(
+                    Arg::new("module")
+                        .long("module")
+                        .value_name("FILE")
+                        .help("File of the --into project the function is added to, relative to the project")
+                        .requires("into"),
+                )
+                .arg(
+                    Arg::new("candidates")
+                        .long("candidates")
+                        .value_name("N")
+                        .help("Generate N independent solutions concurrently, each in its own <sandbox-dir>-<K> folder, and keep the first one which passes")
+                        .value_parser(clap::value_parser!(u64).range(1..))
+                        .default_value("1")
+                        .conflicts_with_all(["output", "into"]),
+                )
+                .arg(
+                    Arg::new("resume")
+                        .long("resume")
+                        .value_name("RUN-ID")
+                        .help("Continue a failed or interrupted function run from its last checkpoint with --max-attempts more rewrites")
+                        .conflicts_with_all(["output", "tests", "into", "candidates"]),
+                )
+                .arg(
+                    Arg::new("guidance")
+                        .long("guidance")
+                        .value_name("TEXT")
+                        .help("Extra instructions added to the question of the resumed run")
+                        .requires("resume"),
                 ),
         )
         .subcommand(
@@ -101,6 +280,116 @@ Usage:
                         .help("Path to the source code folder")
                         .required(true)
                         .index(1),
+                )
+                .arg(
+                    Arg::new("top-k")
+                        .long("top-k")
+                        .value_name("N")
+                        .env("RUSTSN_TOP_K")
+                        .help("Number of code chunks passed to the LLM with the question")
+                        .value_parser(clap::value_parser!(i64).range(1..)),
+                )
+                .arg(
+                    Arg::new("rerank")
+                        .long("rerank")
+                        .help("Let the LLM pick the relevant chunks among the retrieved ones")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(languages_arg())
+                .arg(glob_arg("include", "Index only files matching the glob, e.g. 'src/**'"))
+                .arg(glob_arg("exclude", "Do not index files and folders matching the glob")),
+        )
+        .subcommand(
+            Command::new("index")
+                .about("Build or update the embedding index of a folder used by the ask command")
+                .arg(
+                    Arg::new("path")
+                        .help("Path to the source code folder")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(languages_arg())
+                .arg(glob_arg("include", "Index only files matching the glob, e.g. 'src/**'"))
+                .arg(glob_arg("exclude", "Do not index files and folders matching the glob")),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Inspect the configuration")
+                .subcommand(Command::new("show").about("Print the effective configuration")),
+        )
+        .subcommand(
+            Command::new("runs")
+                .about("Browse the transcripts of the generate runs")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("List the runs"))
+                .subcommand(
+                    Command::new("show")
+                        .about("Show a run, the latest one by default")
+                        .arg(Arg::new("id").help("Run ID as listed by 'runs list'").index(1))
+                        .arg(
+                            Arg::new("attempt")
+                                .long("attempt")
+                                .value_name("N")
+                                .help("Print the prompts, responses and outputs of the attempt")
+                                .value_parser(clap::value_parser!(usize)),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("cache")
+                .about("Inspect and clean up the cache of LLM completions, embeddings and build results")
+                .subcommand_required(true)
+                .subcommand(Command::new("stats").about("Number of entries, size and models by namespace"))
+                .subcommand(
+                    Command::new("list")
+                        .about("List the entries")
+                        .args(cache_filter_args()),
+                )
+                .subcommand(
+                    Command::new("prune")
+                        .about("Remove the entries older than the TTL of their namespace, or than --older-than")
+                        .args(cache_filter_args())
+                        .arg(
+                            Arg::new("older-than")
+                                .long("older-than")
+                                .value_name("HOURS")
+                                .help("Remove the entries older than HOURS instead")
+                                .value_parser(clap::value_parser!(u64)),
+                        ),
+                )
+                .subcommand(
+                    Command::new("clear")
+                        .about("Remove all entries, or the ones of a namespace or model")
+                        .args(cache_filter_args()),
+                )
+                .subcommand(
+                    Command::new("export")
+                        .about("Write the entries into a new cache file, e.g. to share embeddings")
+                        .arg(Arg::new("file").help("File to create").required(true).index(1))
+                        .args(cache_filter_args()),
+                )
+                .subcommand(
+                    Command::new("import")
+                        .about("Add the entries of an exported cache file")
+                        .arg(Arg::new("file").help("Exported cache file").required(true).index(1)),
+                ),
+        )
+        .subcommand(
+            Command::new("mock-server")
+                .about("Serve scripted Ollama and OpenAI responses for testing without models")
+                .arg(
+                    Arg::new("port")
+                        .long("port")
+                        .value_name("PORT")
+                        .help("Port on 127.0.0.1 the server listens on")
+                        .default_value("11435")
+                        .value_parser(clap::value_parser!(u16)),
+                )
+                .arg(
+                    Arg::new("rules")
+                        .long("rules")
+                        .value_name("FILE")
+                        .help("TOML file with the scripted responses, errors and delays"),
                 ),
         )
         .get_matches();
@@ -108,17 +397,115 @@ Usage:
     let verbose = matches.get_one::<bool>("verbose").unwrap();
     *VERBOSE.lock().unwrap() = *verbose;
 
-    let lang: Lang = matches
-        .get_one::<String>("lang")
-        .unwrap()
-        .parse()
-        .unwrap_or_else(|err| {
-            eprintln!("{}", err);
-            std::process::exit(1);
-        });
+    let mut config = config::Config::load(cli_overrides(&matches)).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    if matches.subcommand_name() == Some("config") {
+        println!("# Config files: {}", config::Config::files().join(", "));
+        println!("{}", config.show());
+        return;
+    }
+    if let Some(runs_matches) = matches.subcommand_matches("runs") {
+        match runs_matches.subcommand() {
+            Some(("show", show_matches)) => {
+                let id = show_matches.get_one::<String>("id");
+                let attempt = show_matches.get_one::<usize>("attempt");
+                match runs::show(&config.runs_dir, id.map(|id| id.as_str()), attempt.copied()) {
+                    Ok(report) => println!("{}", report),
+                    Err(err) => {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            _ => println!("{}", runs::list_table(&config.runs_dir)),
+        }
+        return;
+    }
+    if let Some(cache_matches) = matches.subcommand_matches("cache") {
+        let mut cache = cache::Cache::new(&config.cache_path, &config.cache);
+        let result = match cache_matches.subcommand().unwrap() {
+            ("stats", _) => cache.stats(),
+            ("list", list_matches) => cache.list_table(&cache_filter(list_matches)),
+            ("prune", prune_matches) => {
+                let mut filter = cache_filter(prune_matches);
+                filter.older_than = prune_matches
+                    .get_one::<u64>("older-than")
+                    .map(|hours| hours * 3600);
+                cache
+                    .remove(&filter, filter.older_than.is_none())
+                    .map(|count| format!("Removed {} entries", count))
+            }
+            ("clear", clear_matches) => cache
+                .remove(&cache_filter(clear_matches), false)
+                .map(|count| format!("Removed {} entries", count)),
+            ("export", export_matches) => {
+                let file: &String = export_matches.get_one("file").unwrap();
+                cache
+                    .export(file, &cache_filter(export_matches))
+                    .map(|count| format!("Exported {} entries to {}", count, file))
+            }
+            (_, import_matches) => {
+                let file: &String = import_matches.get_one("file").unwrap();
+                cache
+                    .import(file)
+                    .map(|count| format!("Imported {} entries from {}", count, file))
+            }
+        };
+        match result {
+            Ok(report) => println!("{}", report),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+    if let Some(mock_matches) = matches.subcommand_matches("mock-server") {
+        let script = match mock_matches.get_one::<String>("rules") {
+            Some(path) => mock_server::Script::load(path),
+            None => Ok(Default::default()),
+        };
+        let addr = format!("127.0.0.1:{}", mock_matches.get_one::<u16>("port").unwrap());
+        match script.and_then(|script| mock_server::MockServer::start(&addr, script)) {
+            Ok(server) => {
+                println!("Mock server listening on {}", server.url());
+                println!("Use it with: --ollama-url {}", server.url());
+                println!(
+                    "         or: --backend openai --openai-url {}/v1",
+                    server.url()
+                );
+                server.run();
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-    // Optionally, handle the selected language
-    match lang {
+    let resume = matches
+        .subcommand_matches("generate")
+        .and_then(|generate| generate.get_one::<String>("resume"));
+    if let Some(run_id) = resume {
+        // the resumed run keeps its language
+        match runs::manifest(&config.runs_dir, run_id) {
+            Ok(manifest) => config.lang = manifest.lang,
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let lang: Lang = config.lang.parse().unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+
+    match &lang {
         Lang::Rust => println!("Selected language: Rust"),
         Lang::Java => println!("Selected language: Java"),
         Lang::Scala => println!("Selected language: Scala"),
@@ -129,102 +516,153 @@ Usage:
         Lang::Swift => println!("Selected language: Swift"),
         Lang::TypeScript => println!("Selected language: TypeScript"),
         Lang::CSharp => println!("Selected language: C#"),
-        _ => {
-            println!("Unimplemented language: {:?}", lang);
-            std::process::exit(1);
-        }
+        Lang::Custom(name) => println!("Selected language: {}", name),
     }
 
-    let mut cache = cache::Cache::new();
-
-    let prompt_file_path = format!("prompt/{}.txt", lang);
-    if !std::path::Path::new(&prompt_file_path).exists() {
-        println!(
-            "Warning: Cant find \"{}\". Downloading it from https://github.com/evgenyigumnov/rustsn/raw/HEAD/{}",
-            prompt_file_path, prompt_file_path
-        );
-
-        let url = format!(
-            "https://github.com/evgenyigumnov/rustsn/raw/HEAD/{}",
-            prompt_file_path
-        );
-        match reqwest::blocking::get(&url) {
-            Ok(response) => {
-                if response.status().is_success() {
-                    let content = response.text().unwrap();
-                    // Create directories if they don't exist
-                    if let Some(parent) = std::path::Path::new(&prompt_file_path).parent() {
-                        std::fs::create_dir_all(parent).unwrap();
-                    }
-                    // Write the content to the prompt file
-                    std::fs::write(&prompt_file_path, content).unwrap();
-                } else {
-                    eprintln!(
-                        "Failed to download the prompt file: HTTP {}",
-                        response.status()
-                    );
-                    std::process::exit(1);
-                }
+    let mut cache = cache::Cache::new(&config.cache_path, &config.cache);
+    if matches.get_flag("no-cache") {
+        cache.set_mode(cache::Mode::Off);
+    } else if matches.get_flag("refresh") {
+        cache.set_mode(cache::Mode::Refresh);
+    }
+
+    download_if_missing(&config.prompt_dir, &format!("{}.txt", lang));
+    download_if_missing(&config.prompt_dir, &format!("{}.toml", lang));
+    let descriptor = language_driver::LanguageDescriptor::for_lang(&lang, &config);
+
+    let prompt = llm_prompt::Prompt::new(&format!("{}/{}.txt", config.prompt_dir, lang));
+    let llm = if config.backend() == "openai" {
+        println!("Use OpenAI API: {}", config.openai.url);
+        println!("Use OpenAI model: {}", config.openai.model);
+        println!();
+        llm_api::LLMApi::new(
+            llm_api::ModelType::OpenAI {
+                api_key: config.openai_api_key(),
+                base_url: config.openai.url.clone(),
+                model: config.openai.model.clone(),
+                emb: config.openai.emb.clone(),
+            },
+            config.temperature as f32,
+            config.max_tokens,
+        )
+        .with_timeout(std::time::Duration::from_secs(config.openai.timeout_secs))
+    } else {
+        if !std::path::Path::new(&config.openai.token_file).exists() {
+            println!(
+                "Warning: Cant find \"{}\" file for OpenAI API integration.",
+                config.openai.token_file
+            );
+        }
+        println!("Use Ollama API: {}", config.ollama.url);
+        println!("Use Ollama model: {}", config.ollama.model);
+        println!();
+        llm_api::LLMApi::new(
+            llm_api::ModelType::Ollama {
+                url: config.ollama.url.clone(),
+                model: config.ollama.model.clone(),
+                emb: config.ollama.emb.clone(),
+            },
+            config.temperature as f32,
+            config.max_tokens,
+        )
+        .with_timeout(std::time::Duration::from_secs(config.ollama.timeout_secs))
+    };
+    let llm = if let Some(path) = matches.get_one::<String>("record") {
+        println!("Record LLM traffic into: {}", path);
+        llm.with_cassette(cassette::Cassette::record(path))
+    } else if let Some(path) = matches.get_one::<String>("replay") {
+        match cassette::Cassette::replay(path) {
+            Ok(cassette) => {
+                println!("Replay LLM responses from: {}", path);
+                llm.with_cassette(cassette)
             }
             Err(err) => {
-                eprintln!("Error downloading the prompt file: {}", err);
+                println!("{}", err);
                 std::process::exit(1);
             }
         }
-    }
-
-    let prompt = llm_prompt::Prompt::new(format!("prompt/{}.txt", lang).as_str());
-    // if file token.txt exists
-    let llm = if std::path::Path::new("token.txt").exists() {
-        println!("Use OpenAI API");
-        println!("");
-        let token = std::fs::read_to_string("token.txt").unwrap();
-        llm_api::LLMApi::new(llm_api::ModelType::OpenAI {
-            api_key: token.trim().to_string(),
-        })
     } else {
-        let ollama_model: String = matches
-            .get_one::<String>("ollmod")
-            .unwrap()
-            .parse()
-            .unwrap_or_else(|err| {
-                eprintln!("{}", err);
-                std::process::exit(1);
-            });
-        println!("Warning: Cant find \"token.txt\" file for OpenAI API integration.");
-        println!("Use Ollama API: {}", OLLAMA_API);
-        println!("Use Ollama model: {}", ollama_model);
-        println!("");
-
-        let emb: String = matches
-            .get_one::<String>("ollemb")
-            .unwrap()
-            .parse()
-            .unwrap_or_else(|err| {
-                eprintln!("{}", err);
-                std::process::exit(1);
-            });
-        llm_api::LLMApi::new(llm_api::ModelType::Ollama {
-            model: ollama_model,
-            emb,
-        })
+        llm
     };
 
     println!(
         "Use '\\' char in the end of line for multiline mode or just copy-paste multiline text."
     );
-    println!("");
+    println!();
 
     println!("For launch work with AI, type ENTER twice after the last line of the prompt.");
-    println!("");
+    println!();
 
     let command = matches.subcommand_name();
     match command {
         Some("generate") => {
-            println!("Explain what the function should do:");
-            let question: String = ask();
-
-            state_machine::run_state_machine(&lang, &question, &prompt, &mut cache, &llm);
+            if descriptor.files.is_empty() {
+                println!("Unsupported language: {:?}", lang);
+                std::process::exit(1);
+            }
+            let generate_matches = matches.subcommand_matches("generate").unwrap();
+            let generation_type: &String = generate_matches.get_one("type").unwrap();
+            if let Some(run_id) = resume {
+                let guidance = generate_matches.get_one::<String>("guidance");
+                state_machine::resume_state_machine(
+                    run_id,
+                    guidance.map(|guidance| guidance.as_str()),
+                    &prompt,
+                    &mut cache,
+                    &llm,
+                    &config,
+                )
+                .unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    std::process::exit(1);
+                });
+            } else if generation_type == "application" {
+                let output_dir: &String = generate_matches.get_one("output").unwrap();
+                println!("Explain what the application should do:");
+                let question: String = ask();
+
+                application::run_application_state_machine(
+                    &lang, &question, &prompt, &mut cache, &llm, &config, output_dir,
+                );
+            } else {
+                println!("Explain what the function should do:");
+                let question: String = ask();
+                if let Some(project_dir) = generate_matches.get_one::<String>("into") {
+                    let module: &String = generate_matches.get_one("module").unwrap();
+                    existing_project::run_existing_project_state_machine(
+                        &lang,
+                        &question,
+                        &prompt,
+                        &mut cache,
+                        &llm,
+                        &config,
+                        project_dir,
+                        module,
+                    )
+                    .unwrap_or_else(|err| {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    });
+                    println!("++++++++ Finished ++++++++++++");
+                    return;
+                }
+                let tests = state_machine::user_tests(&question, generate_matches.get_one("tests"))
+                    .unwrap_or_else(|err| {
+                        eprintln!("{}", err);
+                        std::process::exit(1);
+                    });
+
+                state_machine::run_state_machine(
+                    &lang,
+                    &question,
+                    tests.as_deref(),
+                    *generate_matches.get_one::<u64>("candidates").unwrap() as usize,
+                    &prompt,
+                    &mut cache,
+                    &llm,
+                    &config,
+                );
+            }
             println!("++++++++ Finished ++++++++++++");
         }
         Some("ask") => {
@@ -234,120 +672,241 @@ Usage:
                 .get_one("path")
                 .unwrap();
             println!("Path: {:?}", path);
-            match lang {
-                Lang::Rust => {
-                    handle_ask_command(
-                        path,
-                        &lang,
-                        &llm,
-                        &mut cache,
-                        &prompt,
-                        vec![String::from("rs"), String::from("toml")],
-                        vec![String::from("target")],
-                        "Explain how this code works and what it do:",
-                        "Use functions from code above to give answer for this question:",
-                    );
-                }
-                Lang::CSharp => {
-                    handle_ask_command(
-                        path,
-                        &lang,
-                        &llm,
-                        &mut cache,
-                        &prompt,
-                        vec![String::from("cs")],
-                        vec![String::from("bin"), String::from("obj")],
-                        "Explain how this code works and what it does:",
-                        "Use the code above to answer the following question:",
-                    );
-                }
-                Lang::JavaScript => {
-                    handle_ask_command(
-                        path,
-                        &lang,
-                        &llm,
-                        &mut cache,
-                        &prompt,
-                        vec![String::from("js")],
-                        vec![String::from("node_modules")],
-                        "Explain how this JavaScript code works and what it does:",
-                        "Use the code above to answer the following question:",
-                    );
-                }
-                _ => {
-                    println!("Unsupported language: {:?}", lang);
-                    std::process::exit(1);
-                }
-            }
+            let (ask, chunker) = ask_sources(&lang, &descriptor, &config);
+            let index = update_index(path, &llm, &ask, &chunker, &config.ask);
+            chat::run_chat(path, index, &llm, &mut cache, &prompt, &ask, &config.ask);
 
             println!("++++++++ Finished ++++++++++++");
         }
+        Some("index") => {
+            let path: &String = matches
+                .subcommand_matches("index")
+                .unwrap()
+                .get_one("path")
+                .unwrap();
+            let (ask, chunker) = ask_sources(&lang, &descriptor, &config);
+            update_index(path, &llm, &ask, &chunker, &config.ask);
+        }
         _ => {
-            println!("Unknown command, please use 'generate' or 'ask'");
+            println!("Unknown command, please use 'generate', 'ask' or 'index'");
             std::process::exit(1);
         }
     }
 }
 
-fn handle_ask_command(
-    path: &String,
-    _lang: &Lang,
-    llm: &llm_api::LLMApi,
-    cache: &mut cache::Cache,
-    prompt: &llm_prompt::Prompt,
-    extensions: Vec<String>,
-    exclude_dirs: Vec<String>,
-    _explain_prompt: &str,
-    answer_prompt: &str,
-) {
-    let files = file_explorer::explore_files(&path, &extensions, &exclude_dirs);
-    let mut vectors: HashMap<String, Vec<f32>> = HashMap::new();
-    for file in &files {
-        println!("File: {:?}", file);
-        let content_file = std::fs::read_to_string(file).unwrap();
-        let content = format!("# {}\r\n{}", file, content_file);
-
-        // let prompt_template = format!("{}\r\n{}", content, _explain_prompt);
-        // let llm_code_explanation = llm.request(&prompt_template, &Vec::new(), cache, prompt);
-        // let full_content = format!("{}\r\n{}", content, llm_code_explanation);
-        // let emb = llm.emb(&content, cache, &full_content);
-        let emb = llm.emb(&content, cache, &content);
-        vectors.insert(file.clone(), emb);
+/// Downloads a file of the `prompt` folder from the repository into `prompt_dir`
+/// if it is missing locally.
+fn download_if_missing(prompt_dir: &str, file_name: &str) {
+    let file_path = format!("{}/{}", prompt_dir, file_name);
+    if std::path::Path::new(&file_path).exists() {
+        return;
     }
+    let url = format!(
+        "https://github.com/evgenyigumnov/rustsn/raw/HEAD/prompt/{}",
+        file_name
+    );
+    println!(
+        "Warning: Cant find \"{}\". Downloading it from {}",
+        file_path, url
+    );
 
-    println!("Enter the question about your project sources:");
-    let question: String = ask();
-    let target_emb = llm.emb(&question, cache, &question);
-    let result = vector_utils::find_closest(&target_emb, &vectors);
-    let limited_result = result.iter().take(3).collect::<Vec<_>>();
-    println!("Find closest files:");
-    for (k, _v) in &limited_result {
-        println!("File: {}", k);
+    match reqwest::blocking::get(&url) {
+        Ok(response) => {
+            if response.status().is_success() {
+                let content = response.text().unwrap();
+                // Create directories if they don't exist
+                if let Some(parent) = std::path::Path::new(&file_path).parent() {
+                    std::fs::create_dir_all(parent).unwrap();
+                }
+                std::fs::write(&file_path, content).unwrap();
+            } else {
+                eprintln!(
+                    "Failed to download \"{}\": HTTP {}",
+                    file_path,
+                    response.status()
+                );
+                std::process::exit(1);
+            }
+        }
+        Err(err) => {
+            eprintln!("Error downloading \"{}\": {}", file_path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// `--namespace` and `--model` options of the cache commands.
+fn cache_filter_args() -> [Arg; 2] {
+    [
+        Arg::new("namespace")
+            .long("namespace")
+            .value_name("NAMESPACE")
+            .help("Only the entries of the namespace")
+            .value_parser(["llm", "embedding", "build"]),
+        Arg::new("model")
+            .long("model")
+            .value_name("MODEL")
+            .help("Only the entries of the model, e.g. qwen2.5-coder:7b"),
+    ]
+}
+
+fn cache_filter(matches: &clap::ArgMatches) -> cache::Filter {
+    cache::Filter {
+        namespace: matches
+            .get_one::<String>("namespace")
+            .map(|namespace| namespace.parse().unwrap()),
+        model: matches.get_one::<String>("model").cloned(),
+        older_than: None,
+    }
+}
+
+/// Config values given by CLI flags or their environment variables.
+fn cli_overrides(matches: &clap::ArgMatches) -> toml::Table {
+    let mut overrides = toml::Table::new();
+    for (arg, key) in [
+        ("lang", "lang"),
+        ("backend", "backend"),
+        ("ollama-url", "ollama.url"),
+        ("ollmod", "ollama.model"),
+        ("ollemb", "ollama.emb"),
+        ("openai-url", "openai.url"),
+        ("openai-key", "openai.api_key"),
+        ("openai-model", "openai.model"),
+        ("openai-emb", "openai.emb"),
+        ("sandbox-dir", "sandbox_dir"),
+        ("cache-path", "cache_path"),
+        ("prompt-dir", "prompt_dir"),
+    ] {
+        if let Some(value) = matches.get_one::<String>(arg) {
+            config::set_override(&mut overrides, key, toml::Value::from(value.as_str()));
+        }
+    }
+    if let Some(value) = matches.get_one::<f64>("temperature") {
+        config::set_override(&mut overrides, "temperature", toml::Value::from(*value));
     }
-    let files_content_vec = limited_result
-        .iter()
-        .map(|(k, _)| {
-            let content = std::fs::read_to_string(k).unwrap();
-            format!("# {} \r\n{}", k, content)
-        })
-        .collect::<Vec<_>>();
-    let files_content = files_content_vec.join("\r\n");
-
-    let prompt_template = format!("{}\r\n{}\r\n{}", files_content, answer_prompt, question);
-    if *VERBOSE.lock().unwrap() {
-        println!("Request: {}", prompt_template);
+    for (arg, key) in [
+        ("max-tokens", "max_tokens"),
+        ("max-attempts", "max_attempts"),
+    ] {
+        if let Some(value) = matches.get_one::<i32>(arg) {
+            config::set_override(&mut overrides, key, toml::Value::from(*value as i64));
+        }
+    }
+    if let Some(ask) = matches.subcommand_matches("ask") {
+        if let Some(top_k) = ask.get_one::<i64>("top-k") {
+            config::set_override(&mut overrides, "ask.top_k", toml::Value::from(*top_k));
+        }
+        if ask.get_flag("rerank") {
+            config::set_override(&mut overrides, "ask.rerank", toml::Value::from(true));
+        }
+    }
+    for command in ["ask", "index"] {
+        let Some(command) = matches.subcommand_matches(command) else {
+            continue;
+        };
+        for (arg, key) in [
+            ("languages", "ask.languages"),
+            ("include", "ask.include"),
+            ("exclude", "ask.exclude"),
+        ] {
+            if let Some(values) = command.get_many::<String>(arg) {
+                let values = values.map(|value| toml::Value::from(value.as_str()));
+                config::set_override(&mut overrides, key, toml::Value::Array(values.collect()));
+            }
+        }
     }
-    let answer = llm.request(&prompt_template, &Vec::new(), cache, prompt);
+    overrides
+}
 
-    println!("++++++++ Answer ++++++++++++");
+fn languages_arg() -> Arg {
+    Arg::new("languages")
+        .long("languages")
+        .value_name("LANGS")
+        .env("RUSTSN_ASK_LANGUAGES")
+        .value_delimiter(',')
+        .help("Other languages of a polyglot project indexed together with --lang, e.g. python,typescript")
+}
 
-    println!("Answer: {}", answer);
+fn glob_arg(name: &'static str, help: &'static str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .value_name("GLOB")
+        .action(ArgAction::Append)
+        .value_delimiter(',')
+        .help(help)
+}
+
+/// Source files and chunking of the `ask` and `index` commands: the ones of
+/// `lang` plus the ones of the other languages of a polyglot project.
+fn ask_sources(
+    lang: &Lang,
+    descriptor: &language_driver::LanguageDescriptor,
+    config: &config::Config,
+) -> (language_driver::AskDescriptor, chunker::Chunker) {
+    let Some(mut ask) = descriptor.ask.clone() else {
+        println!("Unsupported language: {:?}", lang);
+        std::process::exit(1);
+    };
+    let mut chunker = chunker::Chunker::new(
+        &descriptor.chunk_boundaries,
+        config.ask.max_chunk_lines,
+        config.ask.chunk_overlap_lines,
+    );
+    for name in &config.ask.languages {
+        let other: Lang = name.parse().unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        });
+        if other.to_string() == lang.to_string() {
+            continue;
+        }
+        download_if_missing(&config.prompt_dir, &format!("{}.toml", other));
+        let other_descriptor = language_driver::LanguageDescriptor::for_lang(&other, config);
+        let Some(other_ask) = &other_descriptor.ask else {
+            println!("Unsupported language: {:?}", other);
+            std::process::exit(1);
+        };
+        println!("Also indexed: {}", other);
+        ask.merge(other_ask);
+        chunker = chunker.with_language(&other_ask.extensions, &other_descriptor.chunk_boundaries);
+    }
+    (ask, chunker)
+}
+
+/// Embeds new and changed source files of `path` into its persistent index.
+fn update_index(
+    path: &str,
+    llm: &llm_api::LLMApi,
+    ask_descriptor: &language_driver::AskDescriptor,
+    chunker: &chunker::Chunker,
+    ask_config: &config::AskConfig,
+) -> embedding_index::EmbeddingIndex {
+    let options = file_explorer::ExploreOptions {
+        extensions: ask_descriptor.extensions.clone(),
+        exclude_dirs: ask_descriptor.exclude_dirs.clone(),
+        include: ask_config.include.clone(),
+        exclude: ask_config.exclude.clone(),
+        max_file_size: ask_config.max_file_kb * 1024,
+    };
+    let files = file_explorer::explore_files(path, &options).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    let settings = format!("{} {}", llm.emb_model(), chunker.signature());
+    let mut index = embedding_index::EmbeddingIndex::load(path, &settings);
+    let stats = index.update(path, &files, chunker, &mut |text| llm.embed(text));
+    index.save(path);
+    println!(
+        "Index: {} added, {} updated, {} removed, {} unchanged, {} failed",
+        stats.added, stats.updated, stats.removed, stats.unchanged, stats.failed
+    );
+    index
 }
 
 fn ask() -> String {
     let mut question;
     let mut lines = vec![];
-    let mut start_sec = 0 as u128;
+    let mut start_sec = 0_u128;
     loop {
         let mut line = String::new();
         std::io::stdin().read_line(&mut line).unwrap();
@@ -397,13 +956,12 @@ enum Lang {
     TypeScript,
     Scala,
     Python,
-    C,
-    Cpp,
     Kotlin,
     Php,
     Swift,
     CSharp,
-    Unknown,
+    /// Language described only by `prompt/<name>.toml` and `prompt/<name>.txt`.
+    Custom(String),
 }
 
 impl Display for Lang {
@@ -415,15 +973,11 @@ impl Display for Lang {
             Lang::TypeScript => write!(f, "typescript"),
             Lang::Scala => write!(f, "scala"),
             Lang::Python => write!(f, "python"),
-            Lang::C => write!(f, "c"),
-            Lang::Cpp => write!(f, "cpp"),
             Lang::Kotlin => write!(f, "kotlin"),
             Lang::Php => write!(f, "php"),
             Lang::Swift => write!(f, "swift"),
             Lang::CSharp => write!(f, "cs"),
-            _ => {
-                return Ok(());
-            }
+            Lang::Custom(name) => write!(f, "{}", name),
         }
     }
 }
@@ -439,12 +993,17 @@ impl FromStr for Lang {
             "typescript" => Ok(Lang::TypeScript),
             "scala" => Ok(Lang::Scala),
             "python" => Ok(Lang::Python),
-            "c" => Ok(Lang::C),
-            "cpp" => Ok(Lang::Cpp),
             "kotlin" => Ok(Lang::Kotlin),
             "php" => Ok(Lang::Php),
             "cs" => Ok(Lang::CSharp),
             "swift" => Ok(Lang::Swift),
+            name if !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') =>
+            {
+                Ok(Lang::Custom(name.to_string()))
+            }
             _ => Err(format!("Unsupported language: {}", s)),
         }
     }
@@ -0,0 +1,425 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use std::time::Duration;
+use tiny_http::{Header, Response, Server};
+
+/// Scripted answers of the mock server, read from a TOML file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Script {
+    /// Length of the hash embeddings.
+    #[serde(default = "default_dimensions")]
+    pub dimensions: usize,
+    #[serde(default)]
+    pub rule: Vec<Rule>,
+}
+
+fn default_dimensions() -> usize {
+    256
+}
+
+impl Default for Script {
+    fn default() -> Script {
+        Script {
+            dimensions: default_dimensions(),
+            rule: Vec::new(),
+        }
+    }
+}
+
+/// Answer to the requests matching `path` and `contains`. The first matching
+/// rule which is not used up answers, an empty completion when none matches.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Request path, e.g. "/api/generate", every path when not set.
+    pub path: Option<String>,
+    /// Text of the prompt, the messages or the embedding input, every request when not set.
+    pub contains: Option<String>,
+    /// Completion returned by the rule.
+    pub response: Option<String>,
+    /// File with the completion, e.g. "test_data/rust_create_1.txt".
+    pub response_file: Option<String>,
+    /// HTTP status of the error returned instead of the response.
+    pub status: Option<u16>,
+    #[serde(default)]
+    pub delay_ms: u64,
+    /// Number of requests the rule answers, unlimited when not set.
+    pub times: Option<usize>,
+}
+
+impl Script {
+    pub fn load(path: &str) -> Result<Script, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|err| format!("Cant read the rules \"{}\": {}", path, err))?;
+        let mut script: Script = toml::from_str(&content)
+            .map_err(|err| format!("Invalid rules \"{}\": {}", path, err))?;
+        for rule in script.rule.iter_mut() {
+            if let Some(file) = &rule.response_file {
+                let response = std::fs::read_to_string(file)
+                    .map_err(|err| format!("Cant read \"{}\": {}", file, err))?;
+                rule.response = Some(response);
+            }
+        }
+        Ok(script)
+    }
+}
+
+/// HTTP server answering like Ollama and OpenAI-compatible servers from a
+/// `Script`, for testing rustsn without models.
+pub struct MockServer {
+    server: Server,
+    script: Script,
+    /// Number of requests answered by every rule.
+    used: Mutex<Vec<usize>>,
+}
+
+impl MockServer {
+    pub fn start(addr: &str, script: Script) -> Result<MockServer, String> {
+        let server =
+            Server::http(addr).map_err(|err| format!("Cant listen on {}: {}", addr, err))?;
+        Ok(MockServer {
+            server,
+            used: Mutex::new(vec![0; script.rule.len()]),
+            script,
+        })
+    }
+
+    /// Base URL of the server, the OpenAI API is under "<url>/v1".
+    pub fn url(&self) -> String {
+        format!("http://{}", self.server.server_addr())
+    }
+
+    /// Answers the requests, every one in its own thread so delayed answers
+    /// don't hold up the others.
+    pub fn run(&self) {
+        std::thread::scope(|scope| {
+            for request in self.server.incoming_requests() {
+                scope.spawn(|| self.answer(request));
+            }
+        });
+    }
+
+    fn answer(&self, mut request: tiny_http::Request) {
+        let mut body = String::new();
+        let _ = request.as_reader().read_to_string(&mut body);
+        let path = request.url().to_string();
+        let value: Value = serde_json::from_str(&body).unwrap_or(Value::Null);
+        let text = request_text(&path, &value);
+        let rule = self.rule(&path, &text);
+        if rule.delay_ms > 0 {
+            std::thread::sleep(Duration::from_millis(rule.delay_ms));
+        }
+        let (status, content_type, body) = match rule.status {
+            Some(status) => (
+                status,
+                "application/json",
+                json!({ "error": format!("Mock server error {}", status) }).to_string(),
+            ),
+            None => {
+                let response = rule.response.unwrap_or_default();
+                match self.body(&path, &value, &text, &response) {
+                    Some((content_type, body)) => (200, content_type, body),
+                    None => (
+                        404,
+                        "application/json",
+                        json!({ "error": "Not found" }).to_string(),
+                    ),
+                }
+            }
+        };
+        println!("{} {} {}", request.method(), path, status);
+        let header = Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap();
+        let response = Response::from_string(body)
+            .with_status_code(status)
+            .with_header(header);
+        if let Err(err) = request.respond(response) {
+            println!("Warning: Cant answer {}: {}", path, err);
+        }
+    }
+
+    fn rule(&self, path: &str, text: &str) -> Rule {
+        let mut used = self.used.lock().unwrap();
+        for (index, rule) in self.script.rule.iter().enumerate() {
+            if rule.path.as_ref().is_none_or(|rule_path| rule_path == path)
+                && rule
+                    .contains
+                    .as_ref()
+                    .is_none_or(|contains| text.contains(contains.as_str()))
+                && rule.times.is_none_or(|times| used[index] < times)
+            {
+                used[index] += 1;
+                return rule.clone();
+            }
+        }
+        Rule::default()
+    }
+
+    /// Content type and body of the answer with `response`, None for an unknown path.
+    fn body(
+        &self,
+        path: &str,
+        request: &Value,
+        text: &str,
+        response: &str,
+    ) -> Option<(&'static str, String)> {
+        let model = request["model"].as_str().unwrap_or_default();
+        // the streamed responses are sent line by line
+        let chunks = response.split_inclusive('\n');
+        let answer = match path {
+            "/api/generate" if request["stream"].as_bool().unwrap_or(true) => (
+                "application/x-ndjson",
+                chunks
+                    .map(|chunk| json!({ "model": model, "response": chunk, "done": false }))
+                    .chain([json!({ "model": model, "response": "", "done": true })])
+                    .map(|line| format!("{}\n", line))
+                    .collect(),
+            ),
+            "/api/generate" => (
+                "application/json",
+                json!({ "model": model, "response": response, "done": true }).to_string(),
+            ),
+            "/api/chat" if request["stream"].as_bool().unwrap_or(true) => (
+                "application/x-ndjson",
+                chunks
+                    .map(Some)
+                    .chain([None])
+                    .map(|chunk| {
+                        let content = chunk.unwrap_or_default();
+                        let message = json!({ "role": "assistant", "content": content });
+                        json!({ "model": model, "message": message, "done": chunk.is_none() })
+                    })
+                    .map(|line| format!("{}\n", line))
+                    .collect(),
+            ),
+            "/api/chat" => (
+                "application/json",
+                json!({
+                    "model": model,
+                    "message": { "role": "assistant", "content": response },
+                    "done": true,
+                })
+                .to_string(),
+            ),
+            "/api/embeddings" => (
+                "application/json",
+                json!({ "embedding": hash_embedding(text, self.script.dimensions) }).to_string(),
+            ),
+            "/v1/chat/completions" if request["stream"].as_bool().unwrap_or(false) => (
+                "text/event-stream",
+                chunks
+                    .map(|chunk| (json!({ "content": chunk }), Value::Null))
+                    .chain([(json!({}), json!("stop"))])
+                    .map(|(delta, finish_reason)| {
+                        let choice =
+                            json!({ "index": 0, "delta": delta, "finish_reason": finish_reason });
+                        format!(
+                            "data: {}\n\n",
+                            json!({ "model": model, "choices": [choice] })
+                        )
+                    })
+                    .chain(["data: [DONE]\n\n".to_string()])
+                    .collect(),
+            ),
+            "/v1/chat/completions" => (
+                "application/json",
+                json!({
+                    "model": model,
+                    "choices": [{
+                        "index": 0,
+                        "message": { "role": "assistant", "content": response },
+                        "finish_reason": "stop",
+                    }],
+                })
+                .to_string(),
+            ),
+            "/v1/embeddings" => {
+                let data = inputs(request)
+                    .iter()
+                    .enumerate()
+                    .map(|(index, input)| {
+                        json!({ "index": index, "embedding": hash_embedding(input, self.script.dimensions) })
+                    })
+                    .collect::<Vec<_>>();
+                (
+                    "application/json",
+                    json!({ "model": model, "data": data }).to_string(),
+                )
+            }
+            _ => return None,
+        };
+        Some(answer)
+    }
+}
+
+/// Text the rules are matched against: the prompt, the contents of the
+/// messages or the embedding input.
+fn request_text(path: &str, request: &Value) -> String {
+    match path {
+        "/api/chat" | "/v1/chat/completions" => request["messages"]
+            .as_array()
+            .map(|messages| {
+                messages
+                    .iter()
+                    .filter_map(|message| message["content"].as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            })
+            .unwrap_or_default(),
+        "/v1/embeddings" => inputs(request).join("\n"),
+        _ => request["prompt"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Inputs of an OpenAI embeddings request, a string or an array of strings.
+fn inputs(request: &Value) -> Vec<String> {
+    match &request["input"] {
+        Value::Array(inputs) => inputs
+            .iter()
+            .filter_map(|input| input.as_str())
+            .map(|input| input.to_string())
+            .collect(),
+        input => vec![input.as_str().unwrap_or_default().to_string()],
+    }
+}
+
+/// Deterministic embedding of `text`: every word adds ±1 to the dimension
+/// picked by its hash, so texts sharing words are similar.
+pub fn hash_embedding(text: &str, dimensions: usize) -> Vec<f32> {
+    let mut embedding = vec![0.0; dimensions.max(1)];
+    for word in text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+    {
+        let hash = Sha256::digest(word.to_lowercase().as_bytes());
+        let index = u64::from_le_bytes(hash[..8].try_into().unwrap()) % embedding.len() as u64;
+        embedding[index as usize] += if hash[8] & 1 == 0 { 1.0 } else { -1.0 };
+    }
+    let norm = embedding.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|x| *x /= norm);
+    }
+    embedding
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_hash_embedding() {
+        use crate::vector_utils::cosine_similarity;
+
+        let query = super::hash_embedding("sum of two numbers", 256);
+        assert_eq!(query, super::hash_embedding("Sum of two numbers", 256));
+        assert!((query.iter().map(|x| x * x).sum::<f32>() - 1.0).abs() < 1e-5);
+        let close = super::hash_embedding("fn sum(numbers: &[i32]) -> i32", 256);
+        let far = super::hash_embedding("parse the json file", 256);
+        assert!(cosine_similarity(&query, &close) > cosine_similarity(&query, &far));
+        assert_eq!(super::hash_embedding("", 8), vec![0.0; 8]);
+    }
+
+    #[test]
+    fn test_mock_backends() {
+        use crate::llm_api::{LLMApi, ModelType};
+        use std::time::Duration;
+
+        let root = std::env::temp_dir().join("rustsn_test_mock_server");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let rules = root.join("rules.toml");
+        std::fs::write(
+            &rules,
+            r#"
+            dimensions = 32
+
+            [[rule]]
+            contains = "flaky"
+            status = 503
+            times = 1
+
+            [[rule]]
+            contains = "broken"
+            status = 500
+
+            [[rule]]
+            contains = "slow"
+            delay_ms = 2000
+
+            [[rule]]
+            contains = "Multiply"
+            response_file = "test_data/rust_create_1.txt"
+            "#,
+        )
+        .unwrap();
+        let script = super::Script::load(&rules.to_string_lossy()).unwrap();
+        let response = std::fs::read_to_string("test_data/rust_create_1.txt").unwrap();
+        let server = super::MockServer::start("127.0.0.1:0", script).unwrap();
+        let url = server.url();
+        std::thread::spawn(move || server.run());
+
+        let cache_path = root.join("cache.redb").to_string_lossy().to_string();
+        let mut cache =
+            crate::cache::Cache::new(&cache_path, &crate::config::Config::default().cache);
+        cache.set_mode(crate::cache::Mode::Off);
+        let prompt = crate::llm_prompt::Prompt::new("prompt/rust.txt");
+        let ollama = LLMApi::new(
+            ModelType::Ollama {
+                url: url.clone(),
+                model: "qwen2.5-coder:7b".to_string(),
+                emb: "bge-large".to_string(),
+            },
+            0.0,
+            1000,
+        );
+        let openai = LLMApi::new(
+            ModelType::OpenAI {
+                api_key: None,
+                base_url: format!("{}/v1", url),
+                model: "gpt-4o".to_string(),
+                emb: "text-embedding-3-small".to_string(),
+            },
+            0.0,
+            1000,
+        );
+        let messages = [crate::llm_api::ChatMessage::new("user", "Multiply")];
+        for llm in [ollama, openai] {
+            let llm = llm.with_timeout(Duration::from_millis(500));
+            let request = |text: &str, cache: &mut crate::cache::Cache| {
+                llm.request(text, &[], cache, &prompt)
+            };
+            assert_eq!(request("Multiply", &mut cache).unwrap(), response);
+            assert_eq!(llm.chat(&messages, &mut cache).unwrap(), response);
+            assert_eq!(request("Divide", &mut cache).unwrap(), "");
+            assert_eq!(
+                llm.emb("fn sum", &mut cache).unwrap(),
+                super::hash_embedding("fn sum", 32)
+            );
+
+            let err = request("broken", &mut cache).unwrap_err();
+            assert!(err.contains("500"), "{}", err);
+            let err = llm.emb("broken", &mut cache).unwrap_err();
+            assert!(err.contains("500"), "{}", err);
+            let started = std::time::Instant::now();
+            assert!(request("slow", &mut cache).is_err());
+            assert!(llm.emb("slow", &mut cache).is_err());
+            assert!(started.elapsed() < Duration::from_secs(2));
+        }
+
+        let client = reqwest::blocking::Client::new();
+        let flaky = || {
+            client
+                .post(format!("{}/api/generate", url))
+                .json(&serde_json::json!({ "prompt": "flaky", "stream": false }))
+                .send()
+                .unwrap()
+                .status()
+                .as_u16()
+        };
+        assert_eq!(flaky(), 503);
+        assert_eq!(flaky(), 200);
+        let missing = client.post(format!("{}/api/missing", url)).send().unwrap();
+        assert_eq!(missing.status().as_u16(), 404);
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
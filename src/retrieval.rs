@@ -0,0 +1,326 @@
+use regex::Regex;
+use std::collections::HashMap;
+
+use crate::cache::Cache;
+use crate::embedding_index::EmbeddingIndex;
+use crate::llm_api::LLMApi;
+use crate::llm_prompt::Prompt;
+use crate::vector_utils::cosine_similarity;
+
+/// Constant of the reciprocal rank fusion, dampens the weight of the first ranks.
+const RRF_K: f32 = 60.0;
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+
+const RERANK_PROMPT: &str = "Below are numbered code fragments and a question about the code. \
+Reply only with the numbers of the fragments needed to answer the question, most relevant first, separated by commas.";
+
+/// Chunk of a project file returned by the retrieval.
+#[derive(Debug, Clone)]
+pub struct RetrievedChunk {
+    pub path: String,
+    pub start_line: usize,
+    pub end_line: usize,
+    pub text: String,
+    pub score: f32,
+}
+
+/// Ranks the chunks of the index by BM25 over identifiers and comments and by
+/// cosine similarity of the embeddings, fuses both rankings with reciprocal
+/// rank fusion and returns the best `limit` chunks. Chunks containing a symbol
+/// named in the question verbatim (e.g. `parse_positions`) always come first.
+pub fn hybrid_search(
+    index: &EmbeddingIndex,
+    root: &str,
+    question: &str,
+    question_vector: &[f32],
+    limit: usize,
+) -> Vec<RetrievedChunk> {
+    let mut chunks = Vec::new();
+    let mut vectors = Vec::new();
+    for file in &index.files {
+        let Ok(content) = std::fs::read_to_string(std::path::Path::new(root).join(&file.path))
+        else {
+            continue;
+        };
+        for chunk in &file.chunks {
+            chunks.push(RetrievedChunk {
+                path: file.path.clone(),
+                start_line: chunk.start_line,
+                end_line: chunk.end_line,
+                text: content
+                    .get(chunk.start..chunk.end)
+                    .unwrap_or("")
+                    .to_string(),
+                score: 0.0,
+            });
+            vectors.push(&chunk.vector);
+        }
+    }
+
+    let documents = chunks
+        .iter()
+        .map(|chunk| tokenize(&chunk.text))
+        .collect::<Vec<_>>();
+    let lexical = bm25_scores(&documents, &tokenize(question));
+    let semantic = vectors
+        .iter()
+        .map(|vector| cosine_similarity(question_vector, vector))
+        .collect::<Vec<_>>();
+    let fused = reciprocal_rank_fusion(&[ranking(&lexical), ranking(&semantic)], chunks.len());
+
+    let symbols = symbols(question);
+    let mut scored = chunks
+        .into_iter()
+        .zip(fused)
+        .map(|(mut chunk, score)| {
+            chunk.score = score;
+            let symbol_hits = symbols
+                .iter()
+                .filter(|symbol| chunk.text.contains(symbol.as_str()))
+                .count();
+            (symbol_hits, chunk)
+        })
+        .collect::<Vec<_>>();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(b.1.score.total_cmp(&a.1.score)));
+    scored
+        .into_iter()
+        .take(limit)
+        .map(|(_, chunk)| chunk)
+        .collect()
+}
+
+/// Asks the LLM to pick the `top_k` chunks relevant to the question. Falls
+/// back to the order of the candidates if the reply can't be understood.
+pub fn rerank(
+    candidates: Vec<RetrievedChunk>,
+    question: &str,
+    top_k: usize,
+    llm: &LLMApi,
+    cache: &mut Cache,
+    prompt: &Prompt,
+) -> Vec<RetrievedChunk> {
+    let fragments = candidates
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            format!(
+                "[{}] {}:{}-{}\r\n{}",
+                i + 1,
+                chunk.path,
+                chunk.start_line,
+                chunk.end_line,
+                chunk.text
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n");
+    let request = format!(
+        "{}\r\n{}\r\nQuestion: {}",
+        RERANK_PROMPT, fragments, question
+    );
+    // without the answer the candidates keep the order of the search
+    let answer = llm
+        .request(&request, &Vec::new(), cache, prompt)
+        .unwrap_or_else(|err| {
+            println!("Warning: Cant rerank the chunks: {}", err);
+            String::new()
+        });
+
+    let mut order = parse_rerank_answer(&answer, candidates.len());
+    // candidates the LLM did not mention keep their order after the chosen ones
+    for i in 0..candidates.len() {
+        if !order.contains(&i) {
+            order.push(i);
+        }
+    }
+    order
+        .into_iter()
+        .take(top_k)
+        .map(|i| candidates[i].clone())
+        .collect()
+}
+
+/// 0-based candidate indexes from a reply like "3, 1, 7".
+fn parse_rerank_answer(answer: &str, candidates: usize) -> Vec<usize> {
+    let re_number = Regex::new(r"\d+").unwrap();
+    let mut order = Vec::new();
+    for number in re_number.find_iter(answer) {
+        if let Ok(n) = number.as_str().parse::<usize>() {
+            if n >= 1 && n <= candidates && !order.contains(&(n - 1)) {
+                order.push(n - 1);
+            }
+        }
+    }
+    order
+}
+
+/// Lowercase terms of identifiers and comments. Identifiers are kept whole
+/// and also split into their snake_case and camelCase parts, so both
+/// `parse_positions` and `positions` find `fn parse_positions`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let re_word = Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap();
+    let mut tokens = Vec::new();
+    for word in re_word.find_iter(text) {
+        let word = word.as_str();
+        let parts = split_identifier(word);
+        if parts.len() > 1 {
+            tokens.push(word.to_lowercase());
+        }
+        tokens.extend(parts);
+    }
+    tokens
+}
+
+fn split_identifier(word: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    for part in word.split('_').filter(|part| !part.is_empty()) {
+        let mut current = String::new();
+        let chars = part.chars().collect::<Vec<_>>();
+        for (i, c) in chars.iter().enumerate() {
+            let starts_word = c.is_uppercase()
+                && i > 0
+                && (chars[i - 1].is_lowercase()
+                    || chars.get(i + 1).map(|next| next.is_lowercase()) == Some(true));
+            if starts_word && !current.is_empty() {
+                parts.push(current.to_lowercase());
+                current.clear();
+            }
+            current.push(*c);
+        }
+        if !current.is_empty() {
+            parts.push(current.to_lowercase());
+        }
+    }
+    parts
+}
+
+/// Words of the question that look like code symbols: snake_case, camelCase or paths like `a::b`.
+fn symbols(question: &str) -> Vec<String> {
+    let re_symbol =
+        Regex::new(r"[A-Za-z_][A-Za-z0-9_]*(?:(?:::|\.)[A-Za-z_][A-Za-z0-9_]*)*").unwrap();
+    re_symbol
+        .find_iter(question)
+        .map(|symbol| symbol.as_str())
+        .filter(|symbol| {
+            symbol.len() > 2
+                && (symbol.contains('_')
+                    || symbol.contains("::")
+                    || symbol.chars().skip(1).any(|c| c.is_uppercase()))
+        })
+        .map(|symbol| symbol.to_string())
+        .collect()
+}
+
+/// Okapi BM25 score of every document for the query.
+pub fn bm25_scores(documents: &[Vec<String>], query: &[String]) -> Vec<f32> {
+    let count = documents.len() as f32;
+    if documents.is_empty() {
+        return Vec::new();
+    }
+    let average_length = documents.iter().map(|doc| doc.len()).sum::<usize>() as f32 / count;
+    let mut document_frequency: HashMap<&str, usize> = HashMap::new();
+    for doc in documents {
+        let mut seen = doc.iter().map(|term| term.as_str()).collect::<Vec<_>>();
+        seen.sort();
+        seen.dedup();
+        for term in seen {
+            *document_frequency.entry(term).or_default() += 1;
+        }
+    }
+
+    documents
+        .iter()
+        .map(|doc| {
+            let length = doc.len() as f32;
+            let mut term_frequency: HashMap<&str, usize> = HashMap::new();
+            for term in doc {
+                *term_frequency.entry(term.as_str()).or_default() += 1;
+            }
+            query
+                .iter()
+                .map(|term| {
+                    let tf = *term_frequency.get(term.as_str()).unwrap_or(&0) as f32;
+                    if tf == 0.0 {
+                        return 0.0;
+                    }
+                    let df = *document_frequency.get(term.as_str()).unwrap_or(&0) as f32;
+                    let idf = ((count - df + 0.5) / (df + 0.5) + 1.0).ln();
+                    idf * tf * (BM25_K1 + 1.0)
+                        / (tf
+                            + BM25_K1 * (1.0 - BM25_B + BM25_B * length / average_length.max(1.0)))
+                })
+                .sum()
+        })
+        .collect()
+}
+
+/// Document indexes ordered by descending score.
+fn ranking(scores: &[f32]) -> Vec<usize> {
+    let mut order = (0..scores.len()).collect::<Vec<_>>();
+    order.sort_by(|a, b| scores[*b].total_cmp(&scores[*a]));
+    order
+}
+
+/// Fused score of every document: the sum of `1 / (k + rank)` over the rankings.
+pub fn reciprocal_rank_fusion(rankings: &[Vec<usize>], documents: usize) -> Vec<f32> {
+    let mut scores = vec![0.0; documents];
+    for ranking in rankings {
+        for (rank, document) in ranking.iter().enumerate() {
+            scores[*document] += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    scores
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            super::tokenize("fn parse_positions(parseLLMResponse) // HTTPServer"),
+            vec![
+                "fn",
+                "parse_positions",
+                "parse",
+                "positions",
+                "parsellmresponse",
+                "parse",
+                "llm",
+                "response",
+                "httpserver",
+                "http",
+                "server",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bm25_finds_symbol() {
+        let documents = [
+            "fn parse_sections(response: &str) { parse the response }",
+            "fn parse_positions(response: &str) -> Vec<(String, usize)> { }",
+            "fn parse_file(response: &str, path: &str) { parse }",
+        ]
+        .iter()
+        .map(|doc| super::tokenize(doc))
+        .collect::<Vec<_>>();
+        let scores = super::bm25_scores(
+            &documents,
+            &super::tokenize("How does parse_positions work?"),
+        );
+        assert_eq!(super::ranking(&scores)[0], 1);
+        assert_eq!(
+            super::symbols("How does parse_positions work with LLMResponse?"),
+            vec!["parse_positions", "LLMResponse"]
+        );
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        let scores = super::reciprocal_rank_fusion(&[vec![0, 1, 2], vec![2, 1, 0]], 3);
+        assert!(scores[1] < scores[0]);
+        assert_eq!(scores[0], scores[2]);
+        assert_eq!(super::parse_rerank_answer("3, 1, 9, 3", 3), vec![2, 0]);
+    }
+}
@@ -0,0 +1,588 @@
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::cache::Cache;
+use crate::config::Config;
+use crate::llm_api::{self, LLMApi};
+use crate::llm_prompt::Prompt;
+use crate::llm_response::Project;
+use crate::Lang;
+
+const MANIFEST_FILE: &str = "manifest.json";
+const ATTEMPT_FILE: &str = "attempt.json";
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+/// `manifest.json` of a run: what was asked and how it ended.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub id: String,
+    /// "function", "application" or "into".
+    pub mode: String,
+    pub question: String,
+    pub lang: String,
+    pub backend: String,
+    pub model: String,
+    /// Folder the code was generated into.
+    pub output_dir: String,
+    pub started: String,
+    pub finished: Option<String>,
+    pub attempts: usize,
+    /// "success" or "failed", "running" is left by interrupted runs.
+    pub outcome: String,
+}
+
+/// `attempt.json` of an attempt folder.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Attempt {
+    pub number: usize,
+    pub started: String,
+    pub requests: Vec<Request>,
+    pub steps: Vec<Step>,
+}
+
+/// LLM request of an attempt, the prompt and the response are in the files.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Request {
+    /// Name of the prompt template, e.g. "rewrite".
+    pub template: String,
+    pub prompt_file: String,
+    pub response_file: String,
+    pub secs: f64,
+}
+
+/// Build or test step of an attempt, the output is in the file.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Step {
+    pub name: String,
+    pub command: String,
+    pub success: bool,
+    pub secs: f64,
+    pub output_file: String,
+}
+
+/// Transcript of a `generate` run written to `<runs_dir>/<id>/`: the
+/// manifest and an `attempt-NN` folder per attempt with the prompts, the
+/// responses, the generated files and the build and test outputs. Writing is
+/// best effort, a failure only prints a warning.
+pub struct Run {
+    dir: Option<PathBuf>,
+    manifest: Manifest,
+    attempt: Attempt,
+}
+
+impl Run {
+    pub fn start(
+        config: &Config,
+        mode: &str,
+        lang: &Lang,
+        question: &str,
+        llm: &LLMApi,
+        output_dir: &str,
+    ) -> Run {
+        let now = chrono::Local::now();
+        let base_id = now.format("%Y%m%d-%H%M%S").to_string();
+        let mut id = base_id.clone();
+        let mut suffix = 1;
+        while Path::new(&config.runs_dir).join(&id).exists() {
+            suffix += 1;
+            id = format!("{}-{}", base_id, suffix);
+        }
+        let dir = Path::new(&config.runs_dir).join(&id);
+        let dir = match std::fs::create_dir_all(&dir) {
+            Ok(()) => {
+                println!("Run transcript: {}", dir.display());
+                Some(dir)
+            }
+            Err(err) => {
+                println!("Warning: Cant create \"{}\": {}", dir.display(), err);
+                None
+            }
+        };
+        let run = Run {
+            dir,
+            manifest: Manifest {
+                id,
+                mode: mode.to_string(),
+                question: question.to_string(),
+                lang: lang.to_string(),
+                backend: config.backend().to_string(),
+                model: llm.model().to_string(),
+                output_dir: output_dir.to_string(),
+                started: now.to_rfc3339(),
+                finished: None,
+                attempts: 0,
+                outcome: "running".to_string(),
+            },
+            attempt: Attempt::default(),
+        };
+        run.save();
+        run
+    }
+
+    /// Continues the run `id` of `mode` from its checkpoint, the next attempts
+    /// are added to the same transcript.
+    pub fn resume<T: DeserializeOwned>(
+        config: &Config,
+        mode: &str,
+        id: &str,
+    ) -> Result<(Run, T), String> {
+        let mut manifest = manifest(&config.runs_dir, id)?;
+        if manifest.mode != mode {
+            return Err(format!(
+                "The run \"{}\" generated {} code, only {} runs can be resumed",
+                id, manifest.mode, mode
+            ));
+        }
+        if manifest.outcome == "success" {
+            return Err(format!("The run \"{}\" already succeeded", id));
+        }
+        let dir = run_dir(&config.runs_dir, id)?;
+        let checkpoint = read_json::<T>(&dir.join(CHECKPOINT_FILE)).map_err(|err| {
+            format!(
+                "The run \"{}\" has no checkpoint to resume from: {}",
+                id, err
+            )
+        })?;
+        println!(
+            "Resume run {} after {} attempts, transcript: {}",
+            id,
+            manifest.attempts,
+            dir.display()
+        );
+        manifest.outcome = "running".to_string();
+        manifest.finished = None;
+        let run = Run {
+            dir: Some(dir),
+            manifest,
+            attempt: Attempt::default(),
+        };
+        run.save();
+        Ok((run, checkpoint))
+    }
+
+    pub fn id(&self) -> &str {
+        &self.manifest.id
+    }
+
+    /// Folder the code of the run is generated into.
+    pub fn output_dir(&self) -> &str {
+        &self.manifest.output_dir
+    }
+
+    /// Starts the next attempt folder.
+    pub fn next_attempt(&mut self) {
+        self.manifest.attempts += 1;
+        self.attempt = Attempt {
+            number: self.manifest.attempts,
+            started: chrono::Local::now().to_rfc3339(),
+            ..Default::default()
+        };
+        self.save();
+    }
+
+    /// `LLMApi::request_sections` with the prompt and the response, or the
+    /// error of a failed request, written to the attempt folder.
+    pub fn request(
+        &mut self,
+        llm: &LLMApi,
+        template: &str,
+        params: &[String],
+        cache: &mut Cache,
+        prompt: &Prompt,
+        expected_sections: &[String],
+    ) -> Result<String, String> {
+        let start = Instant::now();
+        let response = llm.request_sections(template, params, cache, prompt, expected_sections);
+        let number = self.attempt.requests.len() + 1;
+        let request = Request {
+            template: template.to_string(),
+            prompt_file: format!("prompt-{}.txt", number),
+            response_file: format!("response-{}.md", number),
+            secs: start.elapsed().as_secs_f64(),
+        };
+        self.write(
+            &request.prompt_file,
+            &llm_api::prompt_text(template, params, prompt),
+        );
+        self.write(
+            &request.response_file,
+            response.as_ref().unwrap_or_else(|err| err),
+        );
+        self.attempt.requests.push(request);
+        self.save();
+        response
+    }
+
+    /// Writes the project parsed from the response as `project.json`.
+    pub fn project(&self, project: &Project) {
+        self.write(
+            "project.json",
+            &serde_json::to_string_pretty(project).unwrap(),
+        );
+    }
+
+    /// Writes the generated files into the `files` folder of the attempt.
+    pub fn files(&self, files: &[(String, String)]) {
+        for (path, content) in files {
+            self.write(&format!("files/{}", path), content);
+        }
+    }
+
+    /// Runs a build or test step and records its command, result, output and time.
+    pub fn step(
+        &mut self,
+        name: &str,
+        command: &str,
+        run: impl FnOnce() -> (bool, String),
+    ) -> (bool, String) {
+        let start = Instant::now();
+        let result = run();
+        let step = Step {
+            name: name.to_string(),
+            command: command.to_string(),
+            success: result.0,
+            secs: start.elapsed().as_secs_f64(),
+            output_file: format!("{}.txt", name),
+        };
+        self.write(&step.output_file, &result.1);
+        self.attempt.steps.push(step);
+        self.save();
+        result
+    }
+
+    /// Saves the state the run can be resumed from as `checkpoint.json`.
+    pub fn checkpoint<T: Serialize>(&self, checkpoint: &T) {
+        if let Some(dir) = &self.dir {
+            write_file(
+                &dir.join(CHECKPOINT_FILE),
+                &serde_json::to_string_pretty(checkpoint).unwrap(),
+            );
+        }
+    }
+
+    pub fn finish(mut self, success: bool) {
+        self.manifest.finished = Some(chrono::Local::now().to_rfc3339());
+        self.manifest.outcome = if success { "success" } else { "failed" }.to_string();
+        self.save();
+        if let Some(dir) = &self.dir {
+            println!(
+                "Run {} {}, transcript in {}",
+                self.manifest.id,
+                self.manifest.outcome,
+                dir.display()
+            );
+        }
+    }
+
+    fn attempt_dir(&self) -> Option<PathBuf> {
+        let dir = self.dir.as_ref()?;
+        match self.attempt.number {
+            0 => None,
+            number => Some(dir.join(format!("attempt-{:02}", number))),
+        }
+    }
+
+    fn write(&self, file: &str, content: &str) {
+        if let Some(dir) = self.attempt_dir() {
+            write_file(&dir.join(file), content);
+        }
+    }
+
+    fn save(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+        write_file(
+            &dir.join(MANIFEST_FILE),
+            &serde_json::to_string_pretty(&self.manifest).unwrap(),
+        );
+        if let Some(attempt_dir) = self.attempt_dir() {
+            write_file(
+                &attempt_dir.join(ATTEMPT_FILE),
+                &serde_json::to_string_pretty(&self.attempt).unwrap(),
+            );
+        }
+    }
+}
+
+fn write_file(path: &Path, content: &str) {
+    let result = match path.parent() {
+        Some(parent) => std::fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+    .and_then(|()| std::fs::write(path, content));
+    if let Err(err) = result {
+        println!("Warning: Cant write \"{}\": {}", path.display(), err);
+    }
+}
+
+/// Manifests of the runs in `runs_dir`, oldest first.
+pub fn list(runs_dir: &str) -> Vec<Manifest> {
+    let Ok(entries) = std::fs::read_dir(runs_dir) else {
+        return Vec::new();
+    };
+    let mut manifests = entries
+        .flatten()
+        .filter_map(|entry| read_json::<Manifest>(&entry.path().join(MANIFEST_FILE)).ok())
+        .collect::<Vec<_>>();
+    manifests.sort_by(|a, b| a.id.cmp(&b.id));
+    manifests
+}
+
+/// Table of the runs for `rustsn runs list`.
+pub fn list_table(runs_dir: &str) -> String {
+    let manifests = list(runs_dir);
+    if manifests.is_empty() {
+        return format!("No runs in {}", runs_dir);
+    }
+    let mut lines = vec![format!(
+        "{:<20} {:<8} {:>8}  {:<11} {:<10} {}",
+        "ID", "OUTCOME", "ATTEMPTS", "MODE", "LANG", "QUESTION"
+    )];
+    for manifest in manifests {
+        let question = manifest.question.lines().next().unwrap_or_default();
+        let question = match question.char_indices().nth(60) {
+            Some((end, _)) => format!("{}...", &question[..end]),
+            None => question.to_string(),
+        };
+        lines.push(format!(
+            "{:<20} {:<8} {:>8}  {:<11} {:<10} {}",
+            manifest.id,
+            manifest.outcome,
+            manifest.attempts,
+            manifest.mode,
+            manifest.lang,
+            question
+        ));
+    }
+    lines.join("\n")
+}
+
+/// Folder of the run `id`. The id comes from the command line, so ids which
+/// would point outside of `runs_dir` are rejected.
+fn run_dir(runs_dir: &str, id: &str) -> Result<PathBuf, String> {
+    if id.is_empty() || id.contains(['/', '\\']) || id.contains("..") {
+        return Err(format!("Invalid run id \"{}\"", id));
+    }
+    Ok(Path::new(runs_dir).join(id))
+}
+
+/// Manifest of the run `id`.
+pub fn manifest(runs_dir: &str, id: &str) -> Result<Manifest, String> {
+    read_json::<Manifest>(&run_dir(runs_dir, id)?.join(MANIFEST_FILE))
+        .map_err(|err| format!("Cant read the run \"{}\": {}", id, err))
+}
+
+/// Report of the run `id` (the latest one when `None`) for `rustsn runs show`.
+/// With `attempt` the prompts, responses and outputs of that attempt are included.
+pub fn show(runs_dir: &str, id: Option<&str>, attempt: Option<usize>) -> Result<String, String> {
+    let id = match id {
+        Some(id) => id.to_string(),
+        None => list(runs_dir)
+            .pop()
+            .map(|manifest| manifest.id)
+            .ok_or_else(|| format!("No runs in {}", runs_dir))?,
+    };
+    let dir = run_dir(runs_dir, &id)?;
+    let manifest = manifest(runs_dir, &id)?;
+    let mut report = vec![
+        format!("Run: {}", manifest.id),
+        format!("Question: {}", manifest.question.trim()),
+        format!(
+            "Mode: {}, language: {}, model: {} ({})",
+            manifest.mode, manifest.lang, manifest.model, manifest.backend
+        ),
+        format!(
+            "Started: {}, finished: {}",
+            manifest.started,
+            manifest.finished.as_deref().unwrap_or("-")
+        ),
+        format!(
+            "Outcome: {} after {} attempts",
+            manifest.outcome, manifest.attempts
+        ),
+        format!("Output: {}", manifest.output_dir),
+        format!("Transcript: {}", dir.display()),
+    ];
+    for number in 1..=manifest.attempts {
+        if attempt.is_some_and(|attempt| attempt != number) {
+            continue;
+        }
+        let attempt_dir = dir.join(format!("attempt-{:02}", number));
+        let Ok(record) = read_json::<Attempt>(&attempt_dir.join(ATTEMPT_FILE)) else {
+            continue;
+        };
+        report.push(String::new());
+        report.push(format!("Attempt {}:", number));
+        for request in &record.requests {
+            report.push(format!(
+                "  LLM {:<24} {:>7.1}s  {}",
+                request.template, request.secs, request.response_file
+            ));
+        }
+        for step in &record.steps {
+            let result = if step.success { "ok" } else { "failed" };
+            report.push(format!(
+                "  {:<5} {:<6} {:>7.1}s  {}",
+                step.name, result, step.secs, step.command
+            ));
+        }
+        if attempt.is_none() {
+            continue;
+        }
+        let files = record
+            .requests
+            .iter()
+            .flat_map(|request| [&request.prompt_file, &request.response_file])
+            .chain(record.steps.iter().map(|step| &step.output_file));
+        for file in files {
+            let content = std::fs::read_to_string(attempt_dir.join(file)).unwrap_or_default();
+            report.push(format!(
+                "\n======== {} ========\n{}",
+                file,
+                content.trim_end()
+            ));
+        }
+    }
+    if attempt.is_some_and(|attempt| attempt == 0 || attempt > manifest.attempts) {
+        return Err(format!(
+            "The run \"{}\" has {} attempts",
+            id, manifest.attempts
+        ));
+    }
+    Ok(report.join("\n"))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| err.to_string())?;
+    serde_json::from_str(&content).map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_run_transcript() {
+        use crate::llm_api::{LLMApi, ModelType};
+
+        let root = std::env::temp_dir().join("rustsn_test_runs");
+        let _ = std::fs::remove_dir_all(&root);
+        let config = crate::config::Config {
+            runs_dir: root.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let llm = LLMApi::new(
+            ModelType::Ollama {
+                url: String::new(),
+                model: "qwen2.5-coder:7b".to_string(),
+                emb: String::new(),
+            },
+            0.0,
+            0,
+        );
+        let lang = crate::Lang::Rust;
+
+        let mut run = super::Run::start(&config, "function", &lang, "Sum", &llm, "sandbox");
+        run.next_attempt();
+        run.files(&[("src/lib.rs".to_string(), "fn sum() {}".to_string())]);
+        run.step("build", "cargo build", || (true, String::new()));
+        run.step("test", "cargo test", || {
+            (false, "assertion failed".to_string())
+        });
+        run.next_attempt();
+        run.step("build", "cargo build", || (true, String::new()));
+        // a ready prompt is written as sent, the error of the failed request as the response
+        let mut cache =
+            crate::cache::Cache::new(&root.join("cache.redb").to_string_lossy(), &config.cache);
+        let prompt = crate::llm_prompt::Prompt::new("prompt/rust.txt");
+        assert!(run
+            .request(&llm, "Explain sum", &[], &mut cache, &prompt, &[])
+            .is_err());
+        run.finish(true);
+
+        let runs_dir = config.runs_dir.as_str();
+        let id = super::list(runs_dir)[0].id.clone();
+        super::Run::start(&config, "into", &lang, "Other", &llm, "project");
+        assert_eq!(super::list(runs_dir).len(), 2);
+        assert!(super::list_table(runs_dir).contains("running"));
+
+        let report = super::show(runs_dir, Some(&id), None).unwrap();
+        assert!(report.contains("Outcome: success after 2 attempts"));
+        assert!(report.contains("test  failed"));
+        let report = super::show(runs_dir, Some(&id), Some(1)).unwrap();
+        assert!(report.contains("======== test.txt ========\nassertion failed"));
+        assert!(!report.contains("Attempt 2:"));
+        assert!(root.join(&id).join("attempt-01/files/src/lib.rs").exists());
+        let attempt = root.join(&id).join("attempt-02");
+        assert_eq!(
+            std::fs::read_to_string(attempt.join("prompt-1.txt")).unwrap(),
+            "Explain sum"
+        );
+        assert!(std::fs::read_to_string(attempt.join("response-1.md"))
+            .unwrap()
+            .starts_with("LLM request failed"));
+        assert!(super::show(runs_dir, Some(&id), Some(3)).is_err());
+        assert!(super::show(runs_dir, Some("missing"), None).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_resume_run() {
+        use crate::llm_api::{LLMApi, ModelType};
+
+        let root = std::env::temp_dir().join("rustsn_test_resume_runs");
+        let _ = std::fs::remove_dir_all(&root);
+        let config = crate::config::Config {
+            runs_dir: root.to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let llm = LLMApi::new(
+            ModelType::Ollama {
+                url: String::new(),
+                model: "qwen2.5-coder:7b".to_string(),
+                emb: String::new(),
+            },
+            0.0,
+            0,
+        );
+
+        let mut run = super::Run::start(
+            &config,
+            "function",
+            &crate::Lang::Rust,
+            "Sum",
+            &llm,
+            "sandbox",
+        );
+        let id = run.id().to_string();
+        assert!(super::Run::resume::<Vec<i32>>(&config, "function", &id).is_err());
+        run.next_attempt();
+        run.checkpoint(&vec![1, 2]);
+        run.finish(false);
+
+        assert!(super::Run::resume::<Vec<i32>>(&config, "into", &id).is_err());
+        for invalid in ["", "..", "../runs", "a/b", "a\\b"] {
+            assert!(super::Run::resume::<Vec<i32>>(&config, "function", invalid).is_err());
+            assert!(super::show(&config.runs_dir, Some(invalid), None).is_err());
+        }
+        let (mut run, checkpoint) =
+            super::Run::resume::<Vec<i32>>(&config, "function", &id).unwrap();
+        assert_eq!(checkpoint, vec![1, 2]);
+        assert_eq!(run.output_dir(), "sandbox");
+        assert_eq!(
+            super::manifest(&config.runs_dir, &id).unwrap().outcome,
+            "running"
+        );
+        run.next_attempt();
+        run.finish(true);
+        let manifest = super::manifest(&config.runs_dir, &id).unwrap();
+        assert_eq!(
+            (manifest.attempts, manifest.outcome.as_str()),
+            (2, "success")
+        );
+        assert!(root.join(&id).join("attempt-02").exists());
+        assert!(super::Run::resume::<Vec<i32>>(&config, "function", &id).is_err());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,531 +1,660 @@
-use crate::build_tool::{
-    build_tool, create_project_java, create_project_javascript, create_project_kotlin,
-    create_project_php, create_project_python, create_project_rust, create_project_scala,
-    create_project_swift, create_project_typescript,
-};
+use crate::build_tool::{build_tool, create_project};
 use crate::cache::Cache;
+use crate::config::{Config, ExecutionConfig};
+use crate::diagnostics::Counts;
+use crate::language_driver::{driver_for, LanguageDriver};
 use crate::llm_api::LLMApi;
 use crate::llm_prompt::Prompt;
-use crate::llm_response::LLMResponse;
-use crate::{Lang, MAX_NUMBER_OF_ATTEMPTS, VERBOSE};
+use crate::llm_response::{LLMResponse, Project};
+use crate::runs::Run;
+use crate::{Lang, VERBOSE};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Header of the question section with the tests given by the user.
+const TESTS_SECTION: &str = "Tests";
+
+const GUIDANCE_PROMPT: &str = "Additional guidance:";
+
+const LOCKED_TESTS_PROMPT: &str = "The tests below are given and can't be changed. \
+Use exactly these tests, don't write other tests, and write the solution so that they pass:";
+
+/// State of a function run saved after every attempt, `generate --resume`
+/// continues from it.
+#[derive(Serialize, Deserialize)]
+struct Checkpoint {
+    /// Question with the locked tests and the guidance given on resume.
+    question: String,
+    tests: Option<String>,
+    project: Project,
+    build_res: (bool, String),
+    test_res: (bool, String),
+    /// Errors and failed tests found in the build and test outputs.
+    #[serde(default)]
+    problems: Counts,
+    rewrites: i32,
+}
+
+impl Checkpoint {
+    fn passed(&self) -> bool {
+        self.build_res.0 && self.test_res.0
+    }
+}
 
+/// Tests given by the user: the content of `tests_file` or the code block of
+/// the `**Tests**` section of the question.
+pub fn user_tests(question: &str, tests_file: Option<&String>) -> Result<Option<String>, String> {
+    if let Some(file) = tests_file {
+        let tests = std::fs::read_to_string(file)
+            .map_err(|err| format!("Can't read \"{}\": {}", file, err))?;
+        return Ok(Some(tests));
+    }
+    Ok(LLMResponse::parse_sections(question)
+        .into_iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(TESTS_SECTION))
+        .map(|(_, tests)| tests))
+}
+
+/// Generates a function and its tests, then rewrites them until the project
+/// builds and the tests pass. With `tests` given by the user only the
+/// solution is rewritten, the tests are locked. With several `candidates`
+/// independent solutions are generated and checked concurrently.
+#[allow(clippy::too_many_arguments)]
 pub fn run_state_machine(
     lang: &Lang,
     question: &str,
+    tests: Option<&str>,
+    candidates: usize,
     prompt: &Prompt,
     cache: &mut Cache,
     llm: &LLMApi,
+    config: &Config,
 ) {
-    match lang {
-        Lang::Rust => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Rust);
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-            create_project_rust(lang, &project);
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_code,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Rust);
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-                    create_project_rust(lang, &project);
-                    build_res = build_tool(lang, &project.build_command, cache);
-                    test_res = build_tool(lang, &project.test_command, cache);
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+    let question = match tests {
+        Some(tests) => {
+            println!("Tests are locked, only the solution is generated");
+            format!(
+                "{}\r\n{}\r\n```\r\n{}\r\n```\r\n",
+                question.trim_end(),
+                LOCKED_TESTS_PROMPT,
+                tests.trim_end()
+            )
         }
-        Lang::Java => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Java);
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-            create_project_java(&project);
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Java);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_java(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
-
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
-        }
-        Lang::Scala => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Scala);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_scala(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Scala);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_scala(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
-
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
-        }
-        Lang::Swift => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Swift);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_swift(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
+        None => question.to_string(),
+    };
+    if candidates > 1 {
+        run_candidates(
+            lang, &question, tests, candidates, prompt, cache, llm, config,
+        );
+        return;
+    }
+    let driver = driver_for(lang, config);
+    let sandbox_dir = config.sandbox_dir.as_str();
+    let run = Run::start(config, "function", lang, &question, llm, sandbox_dir);
+    generate(
+        driver.as_ref(),
+        run,
+        &question,
+        tests,
+        sandbox_dir,
+        &AtomicBool::new(false),
+        prompt,
+        cache,
+        llm,
+        config,
+    );
+}
 
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
+/// Generates `candidates` solutions with different seeds, each one in its own
+/// `<sandbox_dir>-N` folder and thread. The first candidate which passes
+/// stops the others and wins, otherwise the one with the best `score`. The
+/// winner is written into the sandbox folder.
+#[allow(clippy::too_many_arguments)]
+fn run_candidates(
+    lang: &Lang,
+    question: &str,
+    tests: Option<&str>,
+    candidates: usize,
+    prompt: &Prompt,
+    cache: &mut Cache,
+    llm: &LLMApi,
+    config: &Config,
+) {
+    println!("Generate {} candidates", candidates);
+    let stop = AtomicBool::new(false);
+    let runs = (1..=candidates)
+        .map(|number| {
+            let dir = format!("{}-{}", config.sandbox_dir, number);
+            let run = Run::start(config, "function", lang, question, llm, &dir);
+            (number, dir, run)
+        })
+        .collect::<Vec<_>>();
+    let results = std::thread::scope(|scope| {
+        let handles = runs
+            .into_iter()
+            .map(|(number, dir, run)| {
+                let mut cache = cache.clone();
+                // the tokens of concurrent candidates would interleave on the terminal
+                let llm = llm.with_seed(number as u64).with_echo(false);
+                let stop = &stop;
+                scope.spawn(move || {
+                    let driver = driver_for(lang, config);
+                    let checkpoint = generate(
+                        driver.as_ref(),
+                        run,
+                        question,
+                        tests,
+                        &dir,
+                        stop,
                         prompt,
+                        &mut cache,
+                        &llm,
+                        config,
                     );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Swift);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_swift(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
-
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
-        }
-        Lang::Kotlin => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Kotlin);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_kotlin(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
+                    let first = checkpoint.passed() && !stop.swap(true, Ordering::SeqCst);
+                    (number, checkpoint, first)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let mut winner: Option<(usize, Checkpoint)> = None;
+    for (number, checkpoint, first) in results {
+        println!(
+            "Candidate {}: {}",
+            number,
+            if checkpoint.passed() {
+                "passed"
             } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Kotlin);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_kotlin(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
-
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
+                "failed"
             }
+        );
+        let better = match &winner {
+            None => true,
+            Some(_) if first => true,
+            Some((_, best)) => !best.passed() && score(&checkpoint) > score(best),
+        };
+        if better {
+            winner = Some((number, checkpoint));
         }
-        Lang::Python => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Python);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_python(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Python);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_python(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
+    }
+    let (number, checkpoint) = winner.unwrap();
+    println!("Candidate {} is kept in {}", number, config.sandbox_dir);
+    create_project(
+        driver_for(lang, config).as_ref(),
+        &config.sandbox_dir,
+        &checkpoint.project,
+    );
+}
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+/// Ranks the candidates which did not pass: a successful build first, then
+/// fewer errors, fewer failed tests, then less code.
+fn score(checkpoint: &Checkpoint) -> (bool, Reverse<usize>, Reverse<usize>, Reverse<usize>) {
+    (
+        checkpoint.build_res.0,
+        Reverse(checkpoint.problems.errors),
+        Reverse(checkpoint.problems.failed_tests),
+        Reverse(checkpoint.project.solution_code.len()),
+    )
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+/// Generates a function into `sandbox_dir` and rewrites it until it passes.
+#[allow(clippy::too_many_arguments)]
+fn generate(
+    driver: &dyn LanguageDriver,
+    mut run: Run,
+    question: &str,
+    tests: Option<&str>,
+    sandbox_dir: &str,
+    stop: &AtomicBool,
+    prompt: &Prompt,
+    cache: &mut Cache,
+    llm: &LLMApi,
+    config: &Config,
+) -> Checkpoint {
+    run.next_attempt();
+    let result = match run.request(
+        llm,
+        "generate",
+        &[question.to_string()],
+        cache,
+        prompt,
+        &driver.expected_sections(),
+    ) {
+        Ok(result) => result,
+        Err(err) => {
+            // saved without a project, the resumed run generates it again
+            println!("{}", err);
+            println!(
+                "Resume with: rustsn generate --resume {} [--guidance TEXT] [--max-attempts N]",
+                run.id()
+            );
+            let checkpoint = Checkpoint {
+                question: question.to_string(),
+                tests: tests.map(|tests| tests.to_string()),
+                project: Project::default(),
+                build_res: (false, err),
+                test_res: (false, String::new()),
+                problems: Counts::default(),
+                rewrites: 0,
+            };
+            run.checkpoint(&checkpoint);
+            run.finish(false);
+            return checkpoint;
         }
-        Lang::JavaScript => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::JavaScript);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            if *VERBOSE.lock().unwrap() {
-                create_project_javascript(&project);
-            }
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::JavaScript);
-
-                    println!("{:#?}", project);
-
-                    create_project_javascript(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
+    };
+    let mut project = driver.parse_response(&result);
+    lock_tests(&mut project, tests);
+    let (build_res, test_res, problems) =
+        check_project(driver, sandbox_dir, &project, &mut run, config, cache);
+    let checkpoint = Checkpoint {
+        question: question.to_string(),
+        tests: tests.map(|tests| tests.to_string()),
+        project,
+        build_res,
+        test_res,
+        problems,
+        rewrites: 0,
+    };
+    run.checkpoint(&checkpoint);
+    rewrite_until_passed(
+        driver,
+        run,
+        checkpoint,
+        config.max_attempts,
+        sandbox_dir,
+        stop,
+        prompt,
+        cache,
+        llm,
+        config,
+    )
+}
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+/// Continues the function run `run_id` from its last checkpoint with up to
+/// `max_attempts` more rewrites. The `guidance` is added to the question.
+pub fn resume_state_machine(
+    run_id: &str,
+    guidance: Option<&str>,
+    prompt: &Prompt,
+    cache: &mut Cache,
+    llm: &LLMApi,
+    config: &Config,
+) -> Result<(), String> {
+    let lang: Lang = config.lang.parse()?;
+    let driver = driver_for(&lang, config);
+    let (run, mut checkpoint) = Run::resume::<Checkpoint>(config, "function", run_id)?;
+    // a candidate run continues in its own `<sandbox_dir>-N` folder
+    let sandbox_dir = run.output_dir().to_string();
+    if let Some(guidance) = guidance {
+        checkpoint.question = format!(
+            "{}\r\n{}\r\n{}\r\n",
+            checkpoint.question.trim_end(),
+            GUIDANCE_PROMPT,
+            guidance.trim_end()
+        );
+    }
+    if checkpoint.project.solution_code.is_empty() {
+        // the first request failed, there is nothing to rewrite yet
+        generate(
+            driver.as_ref(),
+            run,
+            &checkpoint.question,
+            checkpoint.tests.as_deref(),
+            &sandbox_dir,
+            &AtomicBool::new(false),
+            prompt,
+            cache,
+            llm,
+            config,
+        );
+        return Ok(());
+    }
+    let limit = checkpoint.rewrites + config.max_attempts;
+    rewrite_until_passed(
+        driver.as_ref(),
+        run,
+        checkpoint,
+        limit,
+        &sandbox_dir,
+        &AtomicBool::new(false),
+        prompt,
+        cache,
+        llm,
+        config,
+    );
+    Ok(())
+}
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+/// Rewrites the project of the checkpoint until it builds and the tests pass,
+/// the number of rewrites exceeds `limit` or `stop` is set, checkpointing
+/// every attempt.
+#[allow(clippy::too_many_arguments)]
+fn rewrite_until_passed(
+    driver: &dyn LanguageDriver,
+    mut run: Run,
+    mut checkpoint: Checkpoint,
+    limit: i32,
+    sandbox_dir: &str,
+    stop: &AtomicBool,
+    prompt: &Prompt,
+    cache: &mut Cache,
+    llm: &LLMApi,
+    config: &Config,
+) -> Checkpoint {
+    let expected_sections = driver.expected_sections();
+    while !checkpoint.passed() {
+        if stop.load(Ordering::SeqCst) {
+            println!("Another candidate passed, {} is stopped", sandbox_dir);
+            break;
         }
-        Lang::TypeScript => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::TypeScript);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
-            }
-
-            create_project_typescript(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    let ts_config = project.additional_config[0].clone();
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            ts_config,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::TypeScript);
-
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
-
-                    create_project_typescript(&project);
-
-                    build_res = build_tool(lang, &project.build_command, cache);
-
-                    test_res = build_tool(lang, &project.test_command, cache);
-
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+        if checkpoint.rewrites > limit {
+            println!("To many attempts");
+            println!(
+                "Resume with: rustsn generate --resume {} [--guidance TEXT] [--max-attempts N]",
+                run.id()
+            );
+            break;
         }
-        Lang::Php => {
-            let result = llm.request("generate", &vec![question.to_string()], cache, prompt);
-            let mut project = LLMResponse::parse_llm_response(&result, Lang::Php);
-
-            if *VERBOSE.lock().unwrap() {
-                println!("{:#?}", project);
+        run.next_attempt();
+        let result = match run.request(
+            llm,
+            "rewrite",
+            &driver.rewrite_params(
+                &checkpoint.project,
+                &checkpoint.build_res,
+                &checkpoint.test_res,
+                &checkpoint.question,
+            ),
+            cache,
+            prompt,
+            &expected_sections,
+        ) {
+            Ok(result) => result,
+            Err(err) => {
+                // the last checkpoint is kept, the run can be resumed
+                println!("{}", err);
+                println!(
+                    "Resume with: rustsn generate --resume {} [--guidance TEXT] [--max-attempts N]",
+                    run.id()
+                );
+                break;
             }
+        };
+        checkpoint.rewrites += 1;
+        checkpoint.project = driver.parse_response(&result);
+        lock_tests(&mut checkpoint.project, checkpoint.tests.as_deref());
+        (
+            checkpoint.build_res,
+            checkpoint.test_res,
+            checkpoint.problems,
+        ) = check_project(
+            driver,
+            sandbox_dir,
+            &checkpoint.project,
+            &mut run,
+            config,
+            cache,
+        );
+        run.checkpoint(&checkpoint);
+    }
+    run.finish(checkpoint.passed());
+    checkpoint
+}
 
-            create_project_php(&project);
-
-            let mut build_res = build_tool(lang, &project.build_command, cache);
-
-            let mut test_res = build_tool(lang, &project.test_command, cache);
-
-            if build_res.0 && test_res.0 {
-                return;
-            } else {
-                let mut number_of_attempts = 0;
-                loop {
-                    if number_of_attempts > MAX_NUMBER_OF_ATTEMPTS {
-                        println!("To many attempts");
-
-                        break;
-                    }
-                    number_of_attempts += 1;
-                    let result = llm.request(
-                        "rewrite",
-                        &vec![
-                            project.dependencies,
-                            project.solution_code,
-                            project.test_code,
-                            project.build_command,
-                            build_res.1,
-                            project.test_command,
-                            test_res.1,
-                            question.to_string(),
-                        ],
-                        cache,
-                        prompt,
-                    );
-                    project = LLMResponse::parse_llm_response(&result, Lang::Php);
+/// Writes the project into the sandbox, builds and tests it. Returns the build
+/// and test results and the errors and failed tests found in their outputs.
+fn check_project(
+    driver: &dyn LanguageDriver,
+    sandbox_dir: &str,
+    project: &Project,
+    run: &mut Run,
+    config: &Config,
+    cache: &mut Cache,
+) -> ((bool, String), (bool, String), Counts) {
+    if *VERBOSE.lock().unwrap() {
+        println!("{:#?}", project);
+    }
+    run.project(project);
+    run.files(&driver.sandbox_layout(project));
+    create_project(driver, sandbox_dir, project);
+    let build_command = [&project.install_command, &project.build_command]
+        .iter()
+        .filter(|command| !command.is_empty())
+        .map(|command| command.as_str())
+        .collect::<Vec<_>>()
+        .join("\n");
+    let mut build_counts = Counts::default();
+    let build_res = run.step("build", &build_command, || {
+        let (success, message, counts) =
+            install_and_build(driver, sandbox_dir, project, &config.execution, cache);
+        build_counts = counts;
+        (success, message)
+    });
+    let mut test_counts = Counts::default();
+    let test_res = run.step("test", &project.test_command, || {
+        let (success, message, counts) = build_tool(
+            driver,
+            sandbox_dir,
+            &project.test_command,
+            &config.execution,
+            cache,
+        );
+        test_counts = counts;
+        (success, message)
+    });
+    let problems = Counts {
+        errors: build_counts.errors + test_counts.errors,
+        failed_tests: build_counts.failed_tests + test_counts.failed_tests,
+    };
+    (build_res, test_res, problems)
+}
 
-                    if *VERBOSE.lock().unwrap() {
-                        println!("{:#?}", project);
-                    }
+/// Replaces the tests written by the LLM with the ones given by the user.
+fn lock_tests(project: &mut Project, tests: Option<&str>) {
+    if let Some(tests) = tests {
+        project.test_code = tests.to_string();
+    }
+}
 
-                    create_project_php(&project);
+/// Installs the dependencies of the project and builds it. A failed install is
+/// reported as a failed build.
+fn install_and_build(
+    driver: &dyn LanguageDriver,
+    sandbox_dir: &str,
+    project: &Project,
+    limits: &ExecutionConfig,
+    cache: &mut Cache,
+) -> (bool, String, Counts) {
+    let install_res = build_tool(driver, sandbox_dir, &project.install_command, limits, cache);
+    if !install_res.0 {
+        return install_res;
+    }
+    build_tool(driver, sandbox_dir, &project.build_command, limits, cache)
+}
 
-                    build_res = build_tool(lang, &project.build_command, cache);
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_user_tests_from_question() {
+        let question = "Sum of two numbers\r\n**Tests**\r\n```rust\r\n#[test]\r\nfn sum() { assert_eq!(solution(1, 2), 3); }\r\n```\r\n";
+        assert_eq!(
+            super::user_tests(question, None).unwrap().unwrap(),
+            "#[test]\r\nfn sum() { assert_eq!(solution(1, 2), 3); }\r\n"
+        );
+        assert_eq!(super::user_tests("Sum of two numbers", None), Ok(None));
+        assert!(super::user_tests("", Some(&"missing_tests.rs".to_string())).is_err());
+    }
 
-                    test_res = build_tool(lang, &project.test_command, cache);
+    #[test]
+    fn test_score_candidates() {
+        use crate::diagnostics::Counts;
+
+        let candidate =
+            |build: bool, errors: usize, failed_tests: usize, code: &str| super::Checkpoint {
+                question: String::new(),
+                tests: None,
+                project: crate::llm_response::Project {
+                    solution_code: code.to_string(),
+                    ..Default::default()
+                },
+                build_res: (build, String::new()),
+                test_res: (false, String::new()),
+                problems: Counts {
+                    errors,
+                    failed_tests,
+                },
+                rewrites: 0,
+            };
+        let failed_tests = candidate(true, 0, 2, "fn a() {}");
+        let failed_test = candidate(true, 0, 1, "fn a() { 1 }");
+        let shorter = candidate(true, 0, 1, "fn a() {}");
+        let test_error = candidate(true, 1, 0, "fn a() {}");
+        let not_built = candidate(false, 1, 0, "");
+        assert!(super::score(&failed_test) > super::score(&test_error));
+        assert!(super::score(&failed_test) > super::score(&failed_tests));
+        assert!(super::score(&shorter) > super::score(&failed_test));
+        assert!(super::score(&failed_tests) > super::score(&not_built));
+    }
 
-                    if build_res.0 && test_res.0 {
-                        return;
-                    }
-                }
-            }
+    #[test]
+    fn test_rewrite_replayed_function() {
+        use crate::cassette::{Cassette, Interaction};
+        use crate::llm_api::{LLMApi, ModelType};
+
+        let root = std::env::temp_dir().join("rustsn_test_replay");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let path = |name: &str| root.join(name).to_string_lossy().to_string();
+        let config = crate::config::Config {
+            sandbox_dir: path("sandbox"),
+            runs_dir: path("runs"),
+            max_attempts: 2,
+            ..Default::default()
+        };
+
+        // the first response multiplies with a wrong operator, the rewrite fixes it
+        let fixed = std::fs::read_to_string("test_data/rust_create_1.txt").unwrap();
+        let broken = fixed.replace("a * b", "a + b");
+        let mut cassette = Cassette::record(&path("cassette.json"));
+        for (template, response) in [("generate", broken), ("rewrite", fixed)] {
+            cassette.add(Interaction {
+                template: template.to_string(),
+                prompt: String::new(),
+                response,
+                embedding: Vec::new(),
+            });
         }
+        let llm = LLMApi::new(
+            ModelType::Ollama {
+                url: "http://127.0.0.1:1".to_string(),
+                model: "qwen2.5-coder:7b".to_string(),
+                emb: String::new(),
+            },
+            0.0,
+            0,
+        )
+        .with_cassette(Cassette::replay(&path("cassette.json")).unwrap());
+        let mut cache = crate::cache::Cache::new(&path("cache.redb"), &config.cache);
+        let prompt = crate::llm_prompt::Prompt::new("prompt/rust.txt");
+
+        super::run_state_machine(
+            &crate::Lang::Rust,
+            "Multiply two numbers",
+            None,
+            1,
+            &prompt,
+            &mut cache,
+            &llm,
+            &config,
+        );
+
+        let runs = crate::runs::list(&config.runs_dir);
+        assert_eq!(runs[0].outcome, "success");
+        assert_eq!(runs[0].attempts, 2);
+        let report = crate::runs::show(&config.runs_dir, Some(&runs[0].id), Some(1)).unwrap();
+        assert!(report.contains("test  failed"));
+        let code = std::fs::read_to_string(root.join("sandbox/src/lib.rs")).unwrap();
+        assert!(code.contains("a * b"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
 
-        &_ => {
-            panic!("Unknown lang: {}", lang);
-        }
+    #[test]
+    fn test_resume_failed_generate() {
+        use crate::cassette::{Cassette, Interaction};
+        use crate::llm_api::{LLMApi, ModelType};
+
+        let root = std::env::temp_dir().join("rustsn_test_resume_generate");
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let path = |name: &str| root.join(name).to_string_lossy().to_string();
+        let config = crate::config::Config {
+            sandbox_dir: path("sandbox"),
+            runs_dir: path("runs"),
+            max_attempts: 2,
+            ..Default::default()
+        };
+        // a cassette without the generate response fails the first request
+        let llm = |name: &str, template: &str| {
+            let mut cassette = Cassette::record(&path(name));
+            cassette.add(Interaction {
+                template: template.to_string(),
+                prompt: String::new(),
+                response: std::fs::read_to_string("test_data/rust_create_1.txt").unwrap(),
+                embedding: Vec::new(),
+            });
+            LLMApi::new(
+                ModelType::Ollama {
+                    url: "http://127.0.0.1:1".to_string(),
+                    model: "qwen2.5-coder:7b".to_string(),
+                    emb: String::new(),
+                },
+                0.0,
+                0,
+            )
+            .with_cassette(Cassette::replay(&path(name)).unwrap())
+        };
+        let mut cache = crate::cache::Cache::new(&path("cache.redb"), &config.cache);
+        let prompt = crate::llm_prompt::Prompt::new("prompt/rust.txt");
+
+        super::run_state_machine(
+            &crate::Lang::Rust,
+            "Multiply two numbers",
+            None,
+            1,
+            &prompt,
+            &mut cache,
+            &llm("failing.json", "rewrite"),
+            &config,
+        );
+        let id = crate::runs::list(&config.runs_dir)[0].id.clone();
+        assert_eq!(
+            crate::runs::manifest(&config.runs_dir, &id)
+                .unwrap()
+                .outcome,
+            "failed"
+        );
+
+        super::resume_state_machine(
+            &id,
+            None,
+            &prompt,
+            &mut cache,
+            &llm("generate.json", "generate"),
+            &config,
+        )
+        .unwrap();
+        let manifest = crate::runs::manifest(&config.runs_dir, &id).unwrap();
+        assert_eq!(
+            (manifest.attempts, manifest.outcome.as_str()),
+            (2, "success")
+        );
+        assert!(root.join("sandbox/src/lib.rs").exists());
+        std::fs::remove_dir_all(&root).unwrap();
     }
 }
@@ -8,3 +8,12 @@ pub fn remove_comments(text: &str) -> String {
         .trim()
         .to_string()
 }
+
+/// Hex encoded SHA-256 of `content`.
+pub fn sha256_hex(content: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}